@@ -1,4 +1,8 @@
 use criterion::{criterion_group, criterion_main, Criterion};
+use mqtt_bench::broker::{tls_acceptor, Broker};
+use mqtt_bench::cert::{mk_ca_cert, KeyAlgorithm};
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use std::net::TcpStream;
 use std::path::PathBuf;
 
 fn criterion_benchmark(c: &mut Criterion) {
@@ -14,14 +18,49 @@ fn criterion_benchmark(c: &mut Criterion) {
 
     let mut seq = 0;
 
-    c.bench_function("SignX509Certs", |b| {
-        b.iter(|| {
-            let common_name = format!("common-name-{}", seq);
-            seq += 1;
-            mqtt_bench::cert::mk_ca_signed_cert(&ca_cert, &ca_key, &common_name)
-        })
-    });
+    // RSA 4096 key generation takes around a second
+    let mut group = c.benchmark_group("SignX509Certs");
+    group.sample_size(10);
+    for algorithm in KeyAlgorithm::ALL {
+        group.bench_function(algorithm.to_string(), |b| {
+            b.iter(|| {
+                let common_name = format!("common-name-{}", seq);
+                seq += 1;
+                mqtt_bench::cert::mk_ca_signed_cert(&ca_cert, &ca_key, &common_name, algorithm)
+            })
+        });
+    }
+    group.finish();
 }
 
-criterion_group!(benches, criterion_benchmark);
+/// Full TLS handshakes with the embedded broker, whose CA and certificate use the same algorithm.
+fn tls_handshake_benchmark(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("TlsHandshake");
+    for algorithm in KeyAlgorithm::ALL {
+        let (ca_cert, ca_key) = mk_ca_cert(algorithm).unwrap();
+        let acceptor = tls_acceptor(&ca_cert, &ca_key, algorithm, false).unwrap();
+        let addr = runtime
+            .block_on(async {
+                Broker::bind("127.0.0.1:0".parse()?)
+                    .await?
+                    .tls(acceptor)
+                    .spawn()
+            })
+            .unwrap();
+
+        let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let connector = connector.build();
+        group.bench_function(algorithm.to_string(), |b| {
+            b.iter(|| {
+                let tcp_stream = TcpStream::connect(addr).unwrap();
+                connector.connect("localhost", tcp_stream).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark, tls_handshake_benchmark);
 criterion_main!(benches);
@@ -3,13 +3,18 @@
 //! A program that generates ca certs, certs verified by the ca, and public
 //! and private keys.
 
+use mqtt_bench::cert::KeyAlgorithm;
 use openssl::error::ErrorStack;
 use openssl::x509::X509VerifyResult;
 
 fn real_main() -> Result<(), ErrorStack> {
-    let (ca_cert, ca_key_pair) = mqtt_bench::cert::mk_ca_cert()?;
-    let (cert, _key_pair) =
-        mqtt_bench::cert::mk_ca_signed_cert(&ca_cert, &ca_key_pair, "example.com")?;
+    let (ca_cert, ca_key_pair) = mqtt_bench::cert::mk_ca_cert(KeyAlgorithm::default())?;
+    let (cert, _key_pair) = mqtt_bench::cert::mk_ca_signed_cert(
+        &ca_cert,
+        &ca_key_pair,
+        "example.com",
+        KeyAlgorithm::default(),
+    )?;
 
     // Verify that this cert was issued by this ca
     match ca_cert.issued(&cert) {
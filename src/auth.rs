@@ -0,0 +1,299 @@
+//! Passwords presented in CONNECT.
+//!
+//! Brokers of cloud IoT platforms authenticate devices with a token signed by the device rather
+//! than a password: a JSON Web Token, or an HMAC-SHA256 signature over the client ID and a
+//! timestamp keyed by a device secret. Such passwords are signed afresh for every connection
+//! attempt, reconnects included, so that the broker pays for verifying them each time.
+
+use anyhow::{ensure, Context};
+use clap::ValueEnum;
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey, PKeyRef, Private};
+use openssl::sign::Signer;
+use serde::Serialize;
+use serde_json::json;
+use std::time::{Duration, SystemTime};
+
+/// How the password of each client is derived from its configured password.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMethod {
+    /// The configured password, as is
+    #[default]
+    Plain,
+    /// JSON Web Token signed with HMAC-SHA256 keyed by the configured password
+    JwtHs256,
+    /// JSON Web Token signed with RSA-SHA256 by `--jwt-key`
+    JwtRs256,
+    /// HMAC-SHA256 signature over `--hmac-content` keyed by the configured password
+    HmacSha256,
+}
+
+/// Encoding of HMAC signatures.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureEncoding {
+    /// Lowercase hexadecimal
+    #[default]
+    Hex,
+    /// Standard Base64, with padding
+    Base64,
+}
+
+/// Client a password is generated for.
+#[derive(Debug, Clone, Copy)]
+pub struct Login<'a> {
+    pub client_id: &'a str,
+    pub username: &'a str,
+    /// Configured password of the client, the device secret of signed passwords
+    pub secret: &'a str,
+}
+
+/// Generates the password a client presents in CONNECT.
+pub trait PasswordGenerator: Send + Sync {
+    /// Password for the next connection attempt of the client.
+    fn password(&self, login: &Login) -> Result<String, anyhow::Error>;
+
+    /// Whether the password of a client never changes, so that reconnects can reuse it.
+    fn is_static(&self) -> bool {
+        false
+    }
+}
+
+/// The configured password, as is.
+pub struct Plain;
+
+impl PasswordGenerator for Plain {
+    fn password(&self, login: &Login) -> Result<String, anyhow::Error> {
+        Ok(login.secret.to_owned())
+    }
+
+    fn is_static(&self) -> bool {
+        true
+    }
+}
+
+/// Key JSON Web Tokens are signed with.
+pub enum JwtKey {
+    /// HMAC-SHA256 keyed by the configured password of each client
+    Hs256,
+    /// RSA-SHA256 with a key shared by all clients
+    Rs256(PKey<Private>),
+}
+
+/// JSON Web Token whose subject is the client ID, issued now and valid for `ttl`.
+pub struct Jwt {
+    key: JwtKey,
+    audience: Option<String>,
+    ttl: Duration,
+}
+
+impl Jwt {
+    pub fn new(
+        key: JwtKey,
+        audience: Option<String>,
+        ttl: Duration,
+    ) -> Result<Self, anyhow::Error> {
+        if let JwtKey::Rs256(key) = &key {
+            ensure!(key.id() == Id::RSA, "RS256 needs an RSA key");
+        }
+        Ok(Self { key, audience, ttl })
+    }
+}
+
+impl PasswordGenerator for Jwt {
+    fn password(&self, login: &Login) -> Result<String, anyhow::Error> {
+        let algorithm = match self.key {
+            JwtKey::Hs256 => "HS256",
+            JwtKey::Rs256(_) => "RS256",
+        };
+        let issued_at = unix_time()?.as_secs();
+        let header = json!({ "alg": algorithm, "typ": "JWT" });
+        let mut claims = json!({
+            "sub": login.client_id,
+            "iat": issued_at,
+            "exp": issued_at + self.ttl.as_secs(),
+        });
+        if let Some(audience) = &self.audience {
+            claims["aud"] = json!(audience);
+        }
+
+        let signing_input = format!(
+            "{}.{}",
+            base64_url(header.to_string().as_bytes()),
+            base64_url(claims.to_string().as_bytes())
+        );
+        let signature = match &self.key {
+            JwtKey::Hs256 => hmac_sha256(login.secret.as_bytes(), signing_input.as_bytes())?,
+            JwtKey::Rs256(key) => sign_sha256(key, signing_input.as_bytes())?,
+        };
+        Ok(format!("{}.{}", signing_input, base64_url(&signature)))
+    }
+}
+
+/// HMAC-SHA256 signature over a template in which `{client_id}`, `{username}`, `{timestamp}`
+/// and `{timestamp_ms}` are expanded, the timestamps being the current UNIX time in seconds and
+/// milliseconds.
+pub struct Hmac {
+    content: String,
+    encoding: SignatureEncoding,
+}
+
+impl Hmac {
+    pub fn new(content: String, encoding: SignatureEncoding) -> Self {
+        Self { content, encoding }
+    }
+
+    fn content_of(&self, login: &Login, now: Duration) -> String {
+        self.content
+            .replace("{client_id}", login.client_id)
+            .replace("{username}", login.username)
+            .replace("{timestamp_ms}", &now.as_millis().to_string())
+            .replace("{timestamp}", &now.as_secs().to_string())
+    }
+}
+
+impl PasswordGenerator for Hmac {
+    fn password(&self, login: &Login) -> Result<String, anyhow::Error> {
+        let content = self.content_of(login, unix_time()?);
+        let signature = hmac_sha256(login.secret.as_bytes(), content.as_bytes())?;
+        Ok(match self.encoding {
+            SignatureEncoding::Hex => hex(&signature),
+            SignatureEncoding::Base64 => base64::encode_block(&signature),
+        })
+    }
+}
+
+fn unix_time() -> Result<Duration, anyhow::Error> {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .context("System time is before the UNIX epoch")
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+fn sign_sha256(key: &PKeyRef<Private>, data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+/// Base64 with the URL-safe alphabet and without padding, as used by JSON Web Tokens.
+fn base64_url(data: &[u8]) -> String {
+    base64::encode_block(data)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        base64_url, hex, hmac_sha256, Hmac, Jwt, JwtKey, Login, PasswordGenerator,
+        SignatureEncoding,
+    };
+    use crate::cert::KeyAlgorithm;
+    use openssl::base64;
+    use openssl::hash::MessageDigest;
+    use openssl::sign::Verifier;
+    use serde_json::Value;
+    use std::time::Duration;
+
+    const LOGIN: Login = Login {
+        client_id: "device-1",
+        username: "user",
+        secret: "secret",
+    };
+
+    /// Decode Base64 without padding and with the URL-safe alphabet.
+    fn decode_base64_url(data: &str) -> Vec<u8> {
+        let mut data = data.replace('-', "+").replace('_', "/");
+        // Restore the padding `base64_url` strips
+        data.push_str(&"=".repeat((4 - data.len() % 4) % 4));
+        base64::decode_block(&data).unwrap()
+    }
+
+    #[test]
+    fn test_hmac_sha256() -> anyhow::Result<()> {
+        // Test case 1 of RFC 4231
+        assert_eq!(
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            hex(&hmac_sha256(&[0x0b; 20], b"Hi There")?)
+        );
+        assert_eq!("-_8", base64_url(&[0xfb, 0xff]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_jwt_hs256() -> anyhow::Result<()> {
+        let jwt = Jwt::new(
+            JwtKey::Hs256,
+            Some("project".to_owned()),
+            Duration::from_secs(60),
+        )?;
+        let token = jwt.password(&LOGIN)?;
+        let parts = token.split('.').collect::<Vec<_>>();
+        assert_eq!(3, parts.len());
+
+        let header: Value = serde_json::from_slice(&decode_base64_url(parts[0]))?;
+        assert_eq!("HS256", header["alg"]);
+        let claims: Value = serde_json::from_slice(&decode_base64_url(parts[1]))?;
+        assert_eq!("device-1", claims["sub"]);
+        assert_eq!("project", claims["aud"]);
+        assert_eq!(
+            60,
+            claims["exp"].as_u64().unwrap() - claims["iat"].as_u64().unwrap()
+        );
+
+        let signing_input = format!("{}.{}", parts[0], parts[1]);
+        assert_eq!(
+            hmac_sha256(b"secret", signing_input.as_bytes())?,
+            decode_base64_url(parts[2])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_jwt_rs256() -> anyhow::Result<()> {
+        let key = KeyAlgorithm::Rsa2048.generate()?;
+        let jwt = Jwt::new(JwtKey::Rs256(key.clone()), None, Duration::from_secs(60))?;
+        let token = jwt.password(&LOGIN)?;
+        let (signing_input, signature) = token.rsplit_once('.').unwrap();
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+        assert!(verifier.verify_oneshot(&decode_base64_url(signature), signing_input.as_bytes())?);
+
+        let key = KeyAlgorithm::EcdsaP256.generate()?;
+        assert!(Jwt::new(JwtKey::Rs256(key), None, Duration::from_secs(60)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hmac() -> anyhow::Result<()> {
+        let hmac = Hmac::new(
+            "clientId{client_id}username{username}timestamp{timestamp_ms}".to_owned(),
+            SignatureEncoding::Hex,
+        );
+        assert_eq!(
+            "clientIddevice-1usernameusertimestamp1700000000123",
+            hmac.content_of(&LOGIN, Duration::from_millis(1_700_000_000_123))
+        );
+        assert_eq!(64, hmac.password(&LOGIN)?.len());
+
+        let hmac = Hmac::new("{client_id}".to_owned(), SignatureEncoding::Base64);
+        assert_eq!(
+            base64::encode_block(&hmac_sha256(b"secret", b"device-1")?),
+            hmac.password(&LOGIN)?
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,552 @@
+//! Minimal in-process MQTT broker.
+//!
+//! Speaks MQTT 3.1, 3.1.1 and 5 over TCP, TLS or WebSocket, and supports CONNECT, PUBLISH at QoS 0
+//! to 2, SUBSCRIBE with wildcards and shared subscriptions, UNSUBSCRIBE, PINGREQ and DISCONNECT. It
+//! accepts any credentials and keeps no state beyond the lifetime of a connection: there are no
+//! retained messages, persistent sessions or wills. This is enough to test the tool offline and
+//! to measure its own ceiling without a real broker in the way.
+
+mod codec;
+mod websocket;
+
+use crate::cert::{mk_ca_signed_cert, KeyAlgorithm};
+use crate::topic::{is_valid_filter, matches};
+use anyhow::Context;
+use bytes::BytesMut;
+use codec::{Packet, MQTT_3_1, MQTT_3_1_1, MQTT_5};
+use log::{debug, info, warn};
+use openssl::nid::Nid;
+use openssl::pkey::{PKeyRef, Private};
+use openssl::ssl::{Ssl, SslAcceptor, SslAcceptorBuilder, SslMethod, SslVerifyMode};
+use openssl::x509::X509Ref;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_openssl::SslStream;
+
+/// CONNACK return code refusing the protocol version, for MQTT 3.x.
+const UNACCEPTABLE_PROTOCOL_VERSION: u8 = 0x01;
+
+/// CONNACK reason code refusing the protocol version, for MQTT 5.
+const UNSUPPORTED_PROTOCOL_VERSION: u8 = 0x84;
+
+/// SUBACK return code rejecting a topic filter.
+const SUBSCRIBE_FAILURE: u8 = 0x80;
+
+/// Create a TLS acceptor presenting a certificate for `localhost` with a key of `algorithm`,
+/// signed by the given CA.
+///
+/// With `verify_client`, clients must present a certificate signed by the same CA.
+pub fn tls_acceptor(
+    ca_cert: &X509Ref,
+    ca_key: &PKeyRef<Private>,
+    algorithm: KeyAlgorithm,
+    verify_client: bool,
+) -> Result<SslAcceptor, anyhow::Error> {
+    Ok(tls_acceptor_builder(ca_cert, ca_key, algorithm, verify_client)?.build())
+}
+
+/// Builder of [`tls_acceptor`], to customize the acceptor further.
+pub fn tls_acceptor_builder(
+    ca_cert: &X509Ref,
+    ca_key: &PKeyRef<Private>,
+    algorithm: KeyAlgorithm,
+    verify_client: bool,
+) -> Result<SslAcceptorBuilder, anyhow::Error> {
+    let (cert, key) = mk_ca_signed_cert(ca_cert, ca_key, "localhost", algorithm)
+        .context("Failed to sign certificate")?;
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    builder.set_certificate(&cert)?;
+    builder.set_private_key(&key)?;
+    builder.add_extra_chain_cert(ca_cert.to_owned())?;
+    builder.check_private_key()?;
+    if verify_client {
+        builder.cert_store_mut().add_cert(ca_cert.to_owned())?;
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    Ok(builder)
+}
+
+/// A broker listening on a single address.
+pub struct Broker {
+    listener: TcpListener,
+    tls: Option<SslAcceptor>,
+    websocket: bool,
+    router: Arc<Router>,
+}
+
+impl Broker {
+    /// Bind the broker to `addr`, accepting plain TCP connections.
+    pub async fn bind(addr: SocketAddr) -> Result<Self, anyhow::Error> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .context(format!("Failed to bind broker to {}", addr))?;
+        Ok(Self {
+            listener,
+            tls: None,
+            websocket: false,
+            router: Arc::new(Router::default()),
+        })
+    }
+
+    /// Accept TLS connections instead of plain TCP.
+    pub fn tls(mut self, acceptor: SslAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// Carry MQTT in WebSocket frames, over TLS if [`Broker::tls`] is set too. Any request path is
+    /// accepted.
+    pub fn websocket(mut self) -> Self {
+        self.websocket = true;
+        self
+    }
+
+    /// Share subscriptions with another broker, so that messages published on either listener
+    /// reach the subscribers of both.
+    pub fn share_with(mut self, other: &Broker) -> Self {
+        self.router = Arc::clone(&other.router);
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, anyhow::Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept connections until the task is dropped.
+    pub async fn run(self) {
+        loop {
+            let (stream, peer) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let _ = stream.set_nodelay(true);
+            let router = Arc::clone(&self.router);
+            let tls = self.tls.clone();
+            let websocket = self.websocket;
+            tokio::spawn(async move {
+                let result = match tls {
+                    Some(acceptor) => match accept_tls(&acceptor, stream).await {
+                        Ok(stream) => {
+                            if let Some(name) = peer_common_name(&stream) {
+                                debug!("Client certificate of {} has CN={}", peer, name);
+                            }
+                            serve_over(stream, websocket, router).await
+                        }
+                        Err(e) => Err(e),
+                    },
+                    None => serve_over(stream, websocket, router).await,
+                };
+                if let Err(e) = result {
+                    debug!("Connection from {} closed: {:#}", peer, e);
+                }
+            });
+        }
+    }
+
+    /// Run the broker in the background, returning the address it listens on.
+    pub fn spawn(self) -> Result<SocketAddr, anyhow::Error> {
+        let addr = self.local_addr()?;
+        info!("MQTT broker listening on {}", addr);
+        tokio::spawn(self.run());
+        Ok(addr)
+    }
+}
+
+async fn accept_tls<S>(acceptor: &SslAcceptor, stream: S) -> Result<SslStream<S>, anyhow::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ssl = Ssl::new(acceptor.context())?;
+    let mut stream = SslStream::new(ssl, stream)?;
+    Pin::new(&mut stream)
+        .accept()
+        .await
+        .context("TLS handshake failed")?;
+    Ok(stream)
+}
+
+/// Common name of the certificate the client presented, if any.
+fn peer_common_name<S>(stream: &SslStream<S>) -> Option<String> {
+    let cert = stream.ssl().peer_certificate()?;
+    let entry = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next()?;
+    Some(String::from_utf8_lossy(entry.data().as_slice()).into_owned())
+}
+
+/// A message routed to a connection, to be sent with the given QoS.
+#[derive(Debug)]
+struct Delivery {
+    qos: u8,
+    packet: Packet,
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    connection: u64,
+    filter: String,
+    qos: u8,
+    sender: UnboundedSender<Delivery>,
+}
+
+#[derive(Debug, Default)]
+struct ShareGroup {
+    members: Vec<Subscriber>,
+    next: AtomicUsize,
+}
+
+/// Subscriptions of all connections.
+#[derive(Debug, Default)]
+struct Router {
+    next_connection: AtomicU64,
+    subscribers: RwLock<Vec<Subscriber>>,
+    /// Shared subscriptions, keyed by group name and topic filter
+    share_groups: RwLock<HashMap<(String, String), ShareGroup>>,
+}
+
+impl Router {
+    fn subscribe(
+        &self,
+        connection: u64,
+        filter: &str,
+        qos: u8,
+        sender: &UnboundedSender<Delivery>,
+    ) -> bool {
+        let (group, filter) = match filter.strip_prefix("$share/") {
+            Some(shared) => match shared.split_once('/') {
+                Some((group, filter)) if !group.is_empty() => (Some(group), filter),
+                _ => return false,
+            },
+            None => (None, filter),
+        };
+        if !is_valid_filter(filter) {
+            return false;
+        }
+
+        let subscriber = Subscriber {
+            connection,
+            filter: filter.to_owned(),
+            qos,
+            sender: sender.clone(),
+        };
+        match group {
+            Some(group) => {
+                let mut share_groups = self.share_groups.write().unwrap();
+                let members = &mut share_groups
+                    .entry((group.to_owned(), filter.to_owned()))
+                    .or_default()
+                    .members;
+                members.retain(|member| member.connection != connection);
+                members.push(subscriber);
+            }
+            None => {
+                let mut subscribers = self.subscribers.write().unwrap();
+                subscribers.retain(|s| s.connection != connection || s.filter != filter);
+                subscribers.push(subscriber);
+            }
+        }
+        true
+    }
+
+    fn unsubscribe(&self, connection: u64, filter: &str) {
+        match filter
+            .strip_prefix("$share/")
+            .and_then(|shared| shared.split_once('/'))
+        {
+            Some((group, filter)) => {
+                let mut share_groups = self.share_groups.write().unwrap();
+                let key = (group.to_owned(), filter.to_owned());
+                if let Some(share_group) = share_groups.get_mut(&key) {
+                    share_group
+                        .members
+                        .retain(|member| member.connection != connection);
+                    if share_group.members.is_empty() {
+                        share_groups.remove(&key);
+                    }
+                }
+            }
+            None => self
+                .subscribers
+                .write()
+                .unwrap()
+                .retain(|s| s.connection != connection || s.filter != filter),
+        }
+    }
+
+    fn disconnect(&self, connection: u64) {
+        self.subscribers
+            .write()
+            .unwrap()
+            .retain(|s| s.connection != connection);
+        let mut share_groups = self.share_groups.write().unwrap();
+        for share_group in share_groups.values_mut() {
+            share_group
+                .members
+                .retain(|member| member.connection != connection);
+        }
+        share_groups.retain(|_, share_group| !share_group.members.is_empty());
+    }
+
+    /// Deliver a PUBLISH to every matching subscriber, and to one member of every matching
+    /// shared subscription.
+    fn route(&self, topic: &str, qos: u8, packet: &Packet) {
+        let deliver = |subscriber: &Subscriber| {
+            let _ = subscriber.sender.send(Delivery {
+                qos: qos.min(subscriber.qos),
+                packet: packet.clone(),
+            });
+        };
+
+        for subscriber in self.subscribers.read().unwrap().iter() {
+            if matches(&subscriber.filter, topic) {
+                deliver(subscriber);
+            }
+        }
+        for ((_, filter), share_group) in self.share_groups.read().unwrap().iter() {
+            if matches(filter, topic) {
+                let next = share_group.next.fetch_add(1, Ordering::Relaxed);
+                deliver(&share_group.members[next % share_group.members.len()]);
+            }
+        }
+    }
+}
+
+/// Serve a single client connection, carried in WebSocket frames if `websocket` is set.
+async fn serve_over<S>(stream: S, websocket: bool, router: Arc<Router>) -> Result<(), anyhow::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if websocket {
+        serve(websocket::accept(stream).await?, router).await
+    } else {
+        serve(stream, router).await
+    }
+}
+
+/// Serve a single client connection.
+async fn serve<S>(stream: S, router: Arc<Router>) -> Result<(), anyhow::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut reader, writer) = tokio::io::split(stream);
+    let mut buf = BytesMut::with_capacity(4096);
+
+    // CONNECT is decoded the same way whatever the protocol level
+    let (protocol_level, client_id) = loop {
+        match Packet::decode(&mut buf, MQTT_3_1_1)? {
+            Some(Packet::Connect {
+                protocol_level,
+                client_id,
+                ..
+            }) => break (protocol_level, client_id),
+            Some(packet) => anyhow::bail!("Expected CONNECT, got {:?}", packet),
+            None => {
+                if reader.read_buf(&mut buf).await? == 0 {
+                    return Ok(());
+                }
+            }
+        }
+    };
+
+    let connection = router.next_connection.fetch_add(1, Ordering::Relaxed);
+    let (sender, receiver) = unbounded_channel();
+    let (control, control_receiver) = unbounded_channel();
+    let writer = tokio::spawn(write_packets(
+        writer,
+        protocol_level,
+        receiver,
+        control_receiver,
+    ));
+
+    if ![MQTT_3_1, MQTT_3_1_1, MQTT_5].contains(&protocol_level) {
+        let code = if protocol_level > MQTT_5 {
+            UNSUPPORTED_PROTOCOL_VERSION
+        } else {
+            UNACCEPTABLE_PROTOCOL_VERSION
+        };
+        let _ = control.send(Packet::ConnAck {
+            session_present: false,
+            code,
+        });
+        drop(control);
+        drop(sender);
+        let _ = writer.await;
+        return Ok(());
+    }
+
+    debug!("Client[client-id={}] connected", client_id);
+    let _ = control.send(Packet::ConnAck {
+        session_present: false,
+        code: 0,
+    });
+
+    let result = read_packets(
+        &mut reader,
+        &mut buf,
+        protocol_level,
+        connection,
+        &router,
+        &sender,
+        &control,
+    )
+    .await;
+
+    router.disconnect(connection);
+    drop(sender);
+    drop(control);
+    let _ = writer.await;
+    debug!("Client[client-id={}] disconnected", client_id);
+    result
+}
+
+async fn read_packets<R>(
+    reader: &mut R,
+    buf: &mut BytesMut,
+    protocol_level: u8,
+    connection: u64,
+    router: &Router,
+    sender: &UnboundedSender<Delivery>,
+    control: &UnboundedSender<Packet>,
+) -> Result<(), anyhow::Error>
+where
+    R: AsyncRead + Unpin,
+{
+    // QoS 2 messages received but not yet released, to route them only once
+    let mut unreleased = std::collections::HashSet::new();
+    loop {
+        let Some(packet) = Packet::decode(buf, protocol_level)? else {
+            if reader.read_buf(buf).await? == 0 {
+                return Ok(());
+            }
+            continue;
+        };
+
+        match packet {
+            Packet::Publish {
+                qos,
+                ref topic,
+                packet_id,
+                ..
+            } => {
+                let first_delivery = match (qos, packet_id) {
+                    (1, Some(packet_id)) => {
+                        let _ = control.send(Packet::PubAck(packet_id));
+                        true
+                    }
+                    (2, Some(packet_id)) => {
+                        let _ = control.send(Packet::PubRec(packet_id));
+                        unreleased.insert(packet_id)
+                    }
+                    _ => true,
+                };
+                if first_delivery {
+                    router.route(topic, qos, &packet);
+                }
+            }
+            Packet::PubRel(packet_id) => {
+                unreleased.remove(&packet_id);
+                let _ = control.send(Packet::PubComp(packet_id));
+            }
+            Packet::PubRec(packet_id) => {
+                let _ = control.send(Packet::PubRel(packet_id));
+            }
+            // Outgoing messages are not retried, so their acknowledgements need no bookkeeping
+            Packet::PubAck(_) | Packet::PubComp(_) => {}
+            Packet::Subscribe { packet_id, filters } => {
+                let codes = filters
+                    .iter()
+                    .map(|(filter, qos)| {
+                        if router.subscribe(connection, filter, *qos, sender) {
+                            *qos
+                        } else {
+                            SUBSCRIBE_FAILURE
+                        }
+                    })
+                    .collect();
+                let _ = control.send(Packet::SubAck { packet_id, codes });
+            }
+            Packet::Unsubscribe { packet_id, filters } => {
+                for filter in &filters {
+                    router.unsubscribe(connection, filter);
+                }
+                let _ = control.send(Packet::UnsubAck {
+                    packet_id,
+                    count: filters.len(),
+                });
+            }
+            Packet::PingReq => {
+                let _ = control.send(Packet::PingResp);
+            }
+            Packet::Disconnect => return Ok(()),
+            packet => anyhow::bail!("Unexpected packet {:?}", packet),
+        }
+    }
+}
+
+/// Write acknowledgements and deliveries to the client until both channels are closed.
+async fn write_packets<W>(
+    mut writer: W,
+    protocol_level: u8,
+    mut deliveries: UnboundedReceiver<Delivery>,
+    mut control: UnboundedReceiver<Packet>,
+) -> Result<(), anyhow::Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut next_packet_id: u16 = 0;
+    let mut buf = BytesMut::with_capacity(4096);
+    let mut delivering = true;
+    loop {
+        tokio::select! {
+            packet = control.recv() => match packet {
+                Some(packet) => packet.encode(&mut buf, protocol_level),
+                None => break,
+            },
+            delivery = deliveries.recv(), if delivering => match delivery {
+                Some(Delivery { qos, packet }) => {
+                    let Packet::Publish { topic, properties, payload, .. } = packet else {
+                        continue;
+                    };
+                    let packet_id = if qos > 0 {
+                        next_packet_id = next_packet_id.checked_add(1).unwrap_or(1);
+                        Some(next_packet_id)
+                    } else {
+                        None
+                    };
+                    Packet::Publish {
+                        dup: false,
+                        qos,
+                        retain: false,
+                        topic,
+                        packet_id,
+                        properties,
+                        payload,
+                    }
+                    .encode(&mut buf, protocol_level);
+                }
+                // Deliveries stop once the connection is closed, along with the control channel
+                None => {
+                    delivering = false;
+                    continue;
+                }
+            },
+        }
+
+        // Batch whatever else is ready before writing
+        while let Ok(packet) = control.try_recv() {
+            packet.encode(&mut buf, protocol_level);
+        }
+        writer.write_all(&buf).await?;
+        writer.flush().await?;
+        buf.clear();
+    }
+    let _ = writer.shutdown().await;
+    Ok(())
+}
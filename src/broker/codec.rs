@@ -0,0 +1,449 @@
+use anyhow::{bail, ensure, Context};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// Largest remaining length encodable in the four bytes MQTT allows.
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+/// Protocol levels sent in CONNECT.
+pub(crate) const MQTT_3_1: u8 = 3;
+pub(crate) const MQTT_3_1_1: u8 = 4;
+pub(crate) const MQTT_5: u8 = 5;
+
+/// MQTT control packets understood by the broker.
+///
+/// MQTT 5 properties are kept as raw bytes: the broker does not act on any of them, but forwards
+/// those of PUBLISH packets to MQTT 5 subscribers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Packet {
+    Connect {
+        protocol_level: u8,
+        client_id: String,
+        keep_alive: u16,
+    },
+    ConnAck {
+        session_present: bool,
+        code: u8,
+    },
+    Publish {
+        dup: bool,
+        qos: u8,
+        retain: bool,
+        topic: String,
+        packet_id: Option<u16>,
+        properties: Bytes,
+        payload: Bytes,
+    },
+    PubAck(u16),
+    PubRec(u16),
+    PubRel(u16),
+    PubComp(u16),
+    Subscribe {
+        packet_id: u16,
+        /// Topic filters with their requested QoS
+        filters: Vec<(String, u8)>,
+    },
+    SubAck {
+        packet_id: u16,
+        codes: Vec<u8>,
+    },
+    Unsubscribe {
+        packet_id: u16,
+        filters: Vec<String>,
+    },
+    UnsubAck {
+        packet_id: u16,
+        count: usize,
+    },
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+impl Packet {
+    /// Decode a packet from the start of `buf`, or return `None` if it is not complete yet.
+    ///
+    /// `protocol_level` is the level of the connection, as agreed in CONNECT; it is ignored when
+    /// decoding CONNECT itself.
+    pub(crate) fn decode(buf: &mut BytesMut, protocol_level: u8) -> anyhow::Result<Option<Packet>> {
+        let Some((header_len, remaining_len)) = fixed_header(buf)? else {
+            return Ok(None);
+        };
+        if buf.len() < header_len + remaining_len {
+            return Ok(None);
+        }
+
+        let first = buf[0];
+        buf.advance(header_len);
+        let mut body = buf.split_to(remaining_len).freeze();
+        let v5 = protocol_level == MQTT_5;
+        let packet = match first >> 4 {
+            1 => decode_connect(&mut body)?,
+            3 => {
+                let qos = (first >> 1) & 0b11;
+                ensure!(qos < 3, "Invalid QoS 3 in PUBLISH");
+                let topic = get_string(&mut body)?;
+                let packet_id = if qos > 0 {
+                    Some(get_u16(&mut body)?)
+                } else {
+                    None
+                };
+                let properties = if v5 {
+                    get_properties(&mut body)?
+                } else {
+                    Bytes::new()
+                };
+                Packet::Publish {
+                    dup: first & 0b1000 != 0,
+                    qos,
+                    retain: first & 0b1 != 0,
+                    topic,
+                    packet_id,
+                    properties,
+                    payload: body,
+                }
+            }
+            // Reason codes and properties of acknowledgements are of no interest
+            4 => Packet::PubAck(get_u16(&mut body)?),
+            5 => Packet::PubRec(get_u16(&mut body)?),
+            6 => Packet::PubRel(get_u16(&mut body)?),
+            7 => Packet::PubComp(get_u16(&mut body)?),
+            8 => {
+                let packet_id = get_u16(&mut body)?;
+                if v5 {
+                    get_properties(&mut body)?;
+                }
+                let mut filters = vec![];
+                while body.has_remaining() {
+                    let filter = get_string(&mut body)?;
+                    let options = get_u8(&mut body)?;
+                    filters.push((filter, options & 0b11));
+                }
+                ensure!(!filters.is_empty(), "SUBSCRIBE without topic filters");
+                Packet::Subscribe { packet_id, filters }
+            }
+            10 => {
+                let packet_id = get_u16(&mut body)?;
+                if v5 {
+                    get_properties(&mut body)?;
+                }
+                let mut filters = vec![];
+                while body.has_remaining() {
+                    filters.push(get_string(&mut body)?);
+                }
+                Packet::Unsubscribe { packet_id, filters }
+            }
+            12 => Packet::PingReq,
+            14 => Packet::Disconnect,
+            kind => bail!("Unexpected packet type {} from client", kind),
+        };
+        Ok(Some(packet))
+    }
+
+    /// Encode the packet for a connection of the given protocol level.
+    pub(crate) fn encode(&self, buf: &mut BytesMut, protocol_level: u8) {
+        let v5 = protocol_level == MQTT_5;
+        let mut body = BytesMut::new();
+        let first = match self {
+            Packet::Connect {
+                protocol_level,
+                client_id,
+                keep_alive,
+            } => {
+                let name = if *protocol_level == MQTT_3_1 {
+                    "MQIsdp"
+                } else {
+                    "MQTT"
+                };
+                put_string(&mut body, name);
+                body.put_u8(*protocol_level);
+                // Clean session
+                body.put_u8(0b10);
+                body.put_u16(*keep_alive);
+                if *protocol_level == MQTT_5 {
+                    body.put_u8(0);
+                }
+                put_string(&mut body, client_id);
+                0x10
+            }
+            Packet::ConnAck {
+                session_present,
+                code,
+            } => {
+                body.put_u8(*session_present as u8);
+                body.put_u8(*code);
+                if v5 {
+                    body.put_u8(0);
+                }
+                0x20
+            }
+            Packet::Publish {
+                dup,
+                qos,
+                retain,
+                topic,
+                packet_id,
+                properties,
+                payload,
+            } => {
+                put_string(&mut body, topic);
+                if let Some(packet_id) = packet_id {
+                    body.put_u16(*packet_id);
+                }
+                if v5 {
+                    put_var_int(&mut body, properties.len());
+                    body.put_slice(properties);
+                }
+                body.put_slice(payload);
+                0x30 | (*dup as u8) << 3 | qos << 1 | *retain as u8
+            }
+            Packet::PubAck(packet_id) => {
+                body.put_u16(*packet_id);
+                0x40
+            }
+            Packet::PubRec(packet_id) => {
+                body.put_u16(*packet_id);
+                0x50
+            }
+            Packet::PubRel(packet_id) => {
+                body.put_u16(*packet_id);
+                0x62
+            }
+            Packet::PubComp(packet_id) => {
+                body.put_u16(*packet_id);
+                0x70
+            }
+            Packet::Subscribe { packet_id, filters } => {
+                body.put_u16(*packet_id);
+                if v5 {
+                    body.put_u8(0);
+                }
+                for (filter, qos) in filters {
+                    put_string(&mut body, filter);
+                    body.put_u8(*qos);
+                }
+                0x82
+            }
+            Packet::SubAck { packet_id, codes } => {
+                body.put_u16(*packet_id);
+                if v5 {
+                    body.put_u8(0);
+                }
+                body.put_slice(codes);
+                0x90
+            }
+            Packet::Unsubscribe { packet_id, filters } => {
+                body.put_u16(*packet_id);
+                if v5 {
+                    body.put_u8(0);
+                }
+                for filter in filters {
+                    put_string(&mut body, filter);
+                }
+                0xa2
+            }
+            Packet::UnsubAck { packet_id, count } => {
+                body.put_u16(*packet_id);
+                if v5 {
+                    body.put_u8(0);
+                    // Success for every topic filter
+                    body.put_bytes(0, *count);
+                }
+                0xb0
+            }
+            Packet::PingReq => 0xc0,
+            Packet::PingResp => 0xd0,
+            Packet::Disconnect => {
+                if v5 {
+                    // Normal disconnection, no properties
+                    body.put_u8(0);
+                    body.put_u8(0);
+                }
+                0xe0
+            }
+        };
+        buf.put_u8(first);
+        put_var_int(buf, body.len());
+        buf.put_slice(&body);
+    }
+}
+
+/// Length of the fixed header and the remaining length, if enough of `buf` is there to tell.
+fn fixed_header(buf: &[u8]) -> anyhow::Result<Option<(usize, usize)>> {
+    let mut remaining_len = 0;
+    for (index, &byte) in buf.iter().enumerate().skip(1).take(4) {
+        remaining_len += ((byte & 0x7f) as usize) << (7 * (index - 1));
+        if byte & 0x80 == 0 {
+            return Ok(Some((index + 1, remaining_len)));
+        }
+    }
+    ensure!(buf.len() < 5, "Malformed remaining length");
+    Ok(None)
+}
+
+fn decode_connect(body: &mut Bytes) -> anyhow::Result<Packet> {
+    let name = get_string(body)?;
+    let protocol_level = get_u8(body)?;
+    match (name.as_str(), protocol_level) {
+        ("MQIsdp", MQTT_3_1) | ("MQTT", MQTT_3_1_1) | ("MQTT", MQTT_5) => {}
+        // Answered with a CONNACK refusing the protocol version
+        _ => {
+            return Ok(Packet::Connect {
+                protocol_level: protocol_level.min(MQTT_3_1 - 1),
+                client_id: String::new(),
+                keep_alive: 0,
+            })
+        }
+    }
+
+    let flags = get_u8(body)?;
+    let keep_alive = get_u16(body)?;
+    if protocol_level == MQTT_5 {
+        get_properties(body)?;
+    }
+    let client_id = get_string(body)?;
+    if flags & 0b100 != 0 {
+        // Will properties, topic and payload
+        if protocol_level == MQTT_5 {
+            get_properties(body)?;
+        }
+        get_string(body)?;
+        get_binary(body)?;
+    }
+    // User name and password are accepted whatever they are
+    if flags & 0b1000_0000 != 0 {
+        get_binary(body)?;
+    }
+    if flags & 0b0100_0000 != 0 {
+        get_binary(body)?;
+    }
+    Ok(Packet::Connect {
+        protocol_level,
+        client_id,
+        keep_alive,
+    })
+}
+
+fn get_u8(body: &mut Bytes) -> anyhow::Result<u8> {
+    ensure!(body.remaining() >= 1, "Packet too short");
+    Ok(body.get_u8())
+}
+
+fn get_u16(body: &mut Bytes) -> anyhow::Result<u16> {
+    ensure!(body.remaining() >= 2, "Packet too short");
+    Ok(body.get_u16())
+}
+
+fn get_binary(body: &mut Bytes) -> anyhow::Result<Bytes> {
+    let len = get_u16(body)? as usize;
+    ensure!(body.remaining() >= len, "Packet too short");
+    Ok(body.split_to(len))
+}
+
+fn get_string(body: &mut Bytes) -> anyhow::Result<String> {
+    let data = get_binary(body)?;
+    String::from_utf8(data.to_vec()).context("Invalid UTF-8 string")
+}
+
+fn get_var_int(body: &mut Bytes) -> anyhow::Result<usize> {
+    let mut value = 0;
+    for shift in (0..28).step_by(7) {
+        let byte = get_u8(body)?;
+        value += ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("Malformed variable byte integer")
+}
+
+fn get_properties(body: &mut Bytes) -> anyhow::Result<Bytes> {
+    let len = get_var_int(body)?;
+    ensure!(body.remaining() >= len, "Packet too short");
+    Ok(body.split_to(len))
+}
+
+fn put_string(buf: &mut BytesMut, s: &str) {
+    buf.put_u16(s.len() as u16);
+    buf.put_slice(s.as_bytes());
+}
+
+fn put_var_int(buf: &mut BytesMut, mut value: usize) {
+    debug_assert!(value <= MAX_REMAINING_LENGTH);
+    loop {
+        let mut byte = (value % 128) as u8;
+        value /= 128;
+        if value > 0 {
+            byte |= 0x80;
+        }
+        buf.put_u8(byte);
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Packet, MQTT_3_1_1, MQTT_5};
+    use bytes::{Bytes, BytesMut};
+
+    #[test]
+    fn test_round_trip() -> anyhow::Result<()> {
+        let packets = [
+            Packet::Connect {
+                protocol_level: MQTT_5,
+                client_id: "client_0".to_owned(),
+                keep_alive: 30,
+            },
+            Packet::Publish {
+                dup: false,
+                qos: 1,
+                retain: false,
+                topic: "home/0".to_owned(),
+                packet_id: Some(7),
+                properties: Bytes::from_static(&[0x03, 0x00, 0x01, b'a']),
+                payload: Bytes::from(vec![b'a'; 200]),
+            },
+            Packet::PubRel(7),
+            Packet::Subscribe {
+                packet_id: 1,
+                filters: vec![("home/+".to_owned(), 2), ("$share/g/a/#".to_owned(), 0)],
+            },
+            Packet::Unsubscribe {
+                packet_id: 2,
+                filters: vec!["home/+".to_owned()],
+            },
+            Packet::PingReq,
+        ];
+        for level in [MQTT_3_1_1, MQTT_5] {
+            for packet in &packets {
+                let mut packet = packet.clone();
+                if let Packet::Publish { properties, .. } = &mut packet {
+                    if level != MQTT_5 {
+                        *properties = Bytes::new();
+                    }
+                }
+
+                let mut buf = BytesMut::new();
+                packet.encode(&mut buf, level);
+                // Incomplete packets are left for later
+                let mut partial = BytesMut::from(&buf[..buf.len() - 1]);
+                assert_eq!(None, Packet::decode(&mut partial, level)?);
+
+                assert_eq!(Some(packet), Packet::decode(&mut buf, level)?);
+                assert!(buf.is_empty());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        let mut buf = BytesMut::from(&[0x30, 0xff, 0xff, 0xff, 0xff, 0x01][..]);
+        assert!(Packet::decode(&mut buf, MQTT_3_1_1).is_err());
+
+        // PUBLISH with QoS 3
+        let mut buf = BytesMut::from(&[0x36, 0x02, 0x00, 0x00][..]);
+        assert!(Packet::decode(&mut buf, MQTT_3_1_1).is_err());
+    }
+}
@@ -0,0 +1,234 @@
+//! WebSocket transport of the broker, as in RFC 6455.
+//!
+//! After the HTTP upgrade, MQTT packets travel in binary frames. Frames are relayed to and from
+//! an in-memory stream, so that connections over WebSocket are served like any other.
+
+use anyhow::{bail, ensure, Context};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::debug;
+use openssl::base64;
+use openssl::sha::sha1;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+
+/// Appended to the key of the client to compute `Sec-WebSocket-Accept`.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest upgrade request accepted.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// Largest frame accepted, well above the largest MQTT packet the tool sends.
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// Complete the opening handshake on `stream`, returning the stream MQTT packets are read from
+/// and written to.
+pub(crate) async fn accept<S>(mut stream: S) -> Result<DuplexStream, anyhow::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut buf = BytesMut::with_capacity(1024);
+    let request_len = loop {
+        if let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+        ensure!(buf.len() < MAX_REQUEST_LEN, "Upgrade request too large");
+        if stream.read_buf(&mut buf).await? == 0 {
+            bail!("Connection closed during WebSocket handshake");
+        }
+    };
+    let request = buf.split_to(request_len);
+    let request = std::str::from_utf8(&request).context("Upgrade request is not UTF-8")?;
+
+    let mut lines = request.lines();
+    let path = match lines.next().map(|line| line.split(' ').collect::<Vec<_>>()) {
+        Some(parts) if parts.len() == 3 && parts[0] == "GET" => parts[1].to_owned(),
+        _ => bail!("Expected a GET request to upgrade"),
+    };
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim()))
+        .collect::<Vec<_>>();
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| *value)
+    };
+    ensure!(
+        header("upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket")),
+        "Expected an upgrade to WebSocket"
+    );
+    let key = header("sec-websocket-key").context("Missing Sec-WebSocket-Key")?;
+    debug!("WebSocket upgrade of {}", path);
+
+    let mut response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n",
+        accept_key(key)
+    );
+    let protocols = header("sec-websocket-protocol").unwrap_or_default();
+    if protocols
+        .split(',')
+        .any(|protocol| protocol.trim() == "mqtt")
+    {
+        response.push_str("Sec-WebSocket-Protocol: mqtt\r\n");
+    }
+    response.push_str("\r\n");
+    stream.write_all(response.as_bytes()).await?;
+
+    let (socket, mqtt) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        if let Err(e) = relay(stream, buf, socket).await {
+            debug!("WebSocket connection closed: {:#}", e);
+        }
+    });
+    Ok(mqtt)
+}
+
+/// `Sec-WebSocket-Accept` for the `Sec-WebSocket-Key` of a client.
+fn accept_key(key: &str) -> String {
+    base64::encode_block(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+/// Relay the payloads of frames read from `stream`, starting with those in `buf`, to `mqtt`, and
+/// what is written to `mqtt` back as binary frames.
+async fn relay<S>(stream: S, mut buf: BytesMut, mqtt: DuplexStream) -> Result<(), anyhow::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut socket_reader, mut socket_writer) = tokio::io::split(stream);
+    let (mut mqtt_reader, mut mqtt_writer) = tokio::io::split(mqtt);
+    let mut outgoing = vec![0; 16 * 1024];
+    loop {
+        while let Some((opcode, payload)) = decode(&mut buf)? {
+            match opcode {
+                CONTINUATION | TEXT | BINARY => mqtt_writer.write_all(&payload).await?,
+                CLOSE => {
+                    // Echo the status code, if any
+                    let code = payload.slice(..payload.len().min(2));
+                    socket_writer.write_all(&encode(CLOSE, &code)).await?;
+                    return Ok(());
+                }
+                PING => socket_writer.write_all(&encode(PONG, &payload)).await?,
+                _ => {}
+            }
+        }
+        tokio::select! {
+            read = socket_reader.read_buf(&mut buf) => {
+                if read? == 0 {
+                    return Ok(());
+                }
+            }
+            read = mqtt_reader.read(&mut outgoing) => {
+                let read = read?;
+                if read == 0 {
+                    socket_writer.write_all(&encode(CLOSE, &[])).await?;
+                    return Ok(());
+                }
+                socket_writer.write_all(&encode(BINARY, &outgoing[..read])).await?;
+            }
+        }
+    }
+}
+
+/// Decode a frame from the start of `buf` into its opcode and unmasked payload, or return `None`
+/// if it is not complete yet.
+fn decode(buf: &mut BytesMut) -> Result<Option<(u8, Bytes)>, anyhow::Error> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let opcode = buf[0] & 0x0F;
+    let masked = buf[1] & 0x80 != 0;
+    let (header_len, payload_len) = match buf[1] & 0x7F {
+        126 if buf.len() >= 4 => (4, u16::from_be_bytes([buf[2], buf[3]]) as usize),
+        127 if buf.len() >= 10 => {
+            let len = u64::from_be_bytes(buf[2..10].try_into()?);
+            (10, usize::try_from(len)?)
+        }
+        126 | 127 => return Ok(None),
+        len => (2, len as usize),
+    };
+    ensure!(payload_len <= MAX_FRAME_LEN, "Frame too large");
+    let mask_len = if masked { 4 } else { 0 };
+    if buf.len() < header_len + mask_len + payload_len {
+        return Ok(None);
+    }
+
+    buf.advance(header_len);
+    let mask = buf.split_to(mask_len);
+    let mut payload = buf.split_to(payload_len);
+    if masked {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+    Ok(Some((opcode, payload.freeze())))
+}
+
+/// Encode a final, unmasked frame, as sent by servers.
+fn encode(opcode: u8, payload: &[u8]) -> BytesMut {
+    let mut frame = BytesMut::with_capacity(payload.len() + 10);
+    frame.put_u8(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.put_u8(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.put_u8(126);
+            frame.put_u16(len as u16);
+        }
+        len => {
+            frame.put_u8(127);
+            frame.put_u64(len as u64);
+        }
+    }
+    frame.put_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept_key, decode, encode, BINARY, PING};
+    use bytes::BytesMut;
+
+    #[test]
+    fn test_accept_key() {
+        // Example of RFC 6455
+        assert_eq!(
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+            accept_key("dGhlIHNhbXBsZSBub25jZQ==")
+        );
+    }
+
+    #[test]
+    fn test_frames() -> anyhow::Result<()> {
+        for len in [0, 125, 126, 70_000] {
+            let payload = vec![7; len];
+            let mut buf = encode(BINARY, &payload);
+            let partial = buf.len() - 1;
+            assert_eq!(None, decode(&mut BytesMut::from(&buf[..partial]))?);
+            let (opcode, decoded) = decode(&mut buf)?.unwrap();
+            assert_eq!(BINARY, opcode);
+            assert_eq!(payload, decoded);
+            assert!(buf.is_empty());
+        }
+
+        // Masked "Hello" of RFC 6455, followed by the start of another frame
+        let mut buf = BytesMut::from(
+            &[
+                0x89, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58, 0x82,
+            ][..],
+        );
+        let (opcode, payload) = decode(&mut buf)?.unwrap();
+        assert_eq!(PING, opcode);
+        assert_eq!(&b"Hello"[..], payload);
+        assert_eq!(&[0x82][..], &buf[..]);
+        Ok(())
+    }
+}
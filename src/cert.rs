@@ -1,19 +1,31 @@
-use anyhow::{Context, Error};
+use anyhow::{bail, Context, Error};
+use clap::ValueEnum;
+use log::info;
 use openssl::asn1::Asn1Time;
 use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
 use openssl::error::ErrorStack;
 use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
 use openssl::pkey::Private;
-use openssl::pkey::{PKey, PKeyRef};
+use openssl::pkey::{Id, PKey, PKeyRef};
 use openssl::rsa::Rsa;
 use openssl::x509::extension::{
-    AuthorityKeyIdentifier, BasicConstraints, KeyUsage, SubjectAlternativeName,
+    AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
     SubjectKeyIdentifier,
 };
 use openssl::x509::{X509NameBuilder, X509Ref, X509Req, X509ReqBuilder, X509};
-use std::path::Path;
+use serde::Serialize;
+use std::fmt;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
 use std::{fs, fs::File, io::Read};
 
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
 fn read_pem(path: &Path) -> Result<Vec<u8>, Error> {
     let mut f = File::open(path).context("Failed to read CA key file")?;
     let metadata = fs::metadata(path).context("Failed to read metadata of CA key file")?;
@@ -22,10 +34,85 @@ fn read_pem(path: &Path) -> Result<Vec<u8>, Error> {
     Ok(buffer)
 }
 
+/// Algorithm of generated keys, which largely sets the cost of TLS handshakes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+pub enum KeyAlgorithm {
+    #[default]
+    #[value(name = "rsa2048")]
+    #[serde(rename = "rsa2048")]
+    Rsa2048,
+    #[value(name = "rsa3072")]
+    #[serde(rename = "rsa3072")]
+    Rsa3072,
+    #[value(name = "rsa4096")]
+    #[serde(rename = "rsa4096")]
+    Rsa4096,
+    #[value(name = "ecdsa-p256")]
+    #[serde(rename = "ecdsa-p256")]
+    EcdsaP256,
+    #[value(name = "ecdsa-p384")]
+    #[serde(rename = "ecdsa-p384")]
+    EcdsaP384,
+    #[value(name = "ed25519")]
+    #[serde(rename = "ed25519")]
+    Ed25519,
+}
+
+impl KeyAlgorithm {
+    pub const ALL: [KeyAlgorithm; 6] = [
+        KeyAlgorithm::Rsa2048,
+        KeyAlgorithm::Rsa3072,
+        KeyAlgorithm::Rsa4096,
+        KeyAlgorithm::EcdsaP256,
+        KeyAlgorithm::EcdsaP384,
+        KeyAlgorithm::Ed25519,
+    ];
+
+    /// Generate a key pair.
+    pub fn generate(self) -> Result<PKey<Private>, ErrorStack> {
+        match self {
+            KeyAlgorithm::Rsa2048 => PKey::from_rsa(Rsa::generate(2048)?),
+            KeyAlgorithm::Rsa3072 => PKey::from_rsa(Rsa::generate(3072)?),
+            KeyAlgorithm::Rsa4096 => PKey::from_rsa(Rsa::generate(4096)?),
+            KeyAlgorithm::EcdsaP256 => {
+                let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+                PKey::from_ec_key(EcKey::generate(&group)?)
+            }
+            KeyAlgorithm::EcdsaP384 => {
+                let group = EcGroup::from_curve_name(Nid::SECP384R1)?;
+                PKey::from_ec_key(EcKey::generate(&group)?)
+            }
+            KeyAlgorithm::Ed25519 => PKey::generate_ed25519(),
+        }
+    }
+}
+
+impl fmt::Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Names are always set above
+        let value = self.to_possible_value().unwrap();
+        write!(f, "{}", value.get_name())
+    }
+}
+
+/// Digest to sign with `key`: Ed25519 hashes on its own, and P-384 pairs with SHA-384.
+fn signature_digest(key: &PKeyRef<Private>) -> MessageDigest {
+    match key.id() {
+        Id::ED25519 => MessageDigest::null(),
+        Id::EC if key.bits() > 256 => MessageDigest::sha384(),
+        _ => MessageDigest::sha256(),
+    }
+}
+
+/// Load a CA private key of any of the [`KeyAlgorithm`]s.
 pub fn load_ca_pkey(key_path: &Path) -> Result<PKey<Private>, Error> {
+    load_pkey(key_path)
+}
+
+/// Load a PEM private key, e.g. to sign tokens with.
+pub fn load_pkey(key_path: &Path) -> Result<PKey<Private>, Error> {
     let buffer = read_pem(key_path)?;
-    let rsa = Rsa::private_key_from_pem(&buffer[..]).context("Failed to read RSA private key")?;
-    let pkey = PKey::from_rsa(rsa).context("Failed tp wrap RSA to Private Key")?;
+    let pkey = PKey::private_key_from_pem(&buffer[..]).context("Failed to read private key")?;
     Ok(pkey)
 }
 
@@ -36,9 +123,8 @@ pub fn load_ca_cert(cert_path: &Path) -> Result<X509, Error> {
 }
 
 /// Make a CA certificate and private key
-pub fn mk_ca_cert() -> Result<(X509, PKey<Private>), ErrorStack> {
-    let rsa = Rsa::generate(2048)?;
-    let key_pair = PKey::from_rsa(rsa)?;
+pub fn mk_ca_cert(algorithm: KeyAlgorithm) -> Result<(X509, PKey<Private>), ErrorStack> {
+    let key_pair = algorithm.generate()?;
 
     let mut x509_name = X509NameBuilder::new()?;
     x509_name.append_entry_by_text("C", "US")?;
@@ -76,7 +162,7 @@ pub fn mk_ca_cert() -> Result<(X509, PKey<Private>), ErrorStack> {
         SubjectKeyIdentifier::new().build(&cert_builder.x509v3_context(None, None))?;
     cert_builder.append_extension(subject_key_identifier)?;
 
-    cert_builder.sign(&key_pair, MessageDigest::sha256())?;
+    cert_builder.sign(&key_pair, signature_digest(&key_pair))?;
     let cert = cert_builder.build();
 
     Ok((cert, key_pair))
@@ -95,7 +181,7 @@ pub fn mk_request(key_pair: &PKey<Private>, common_name: &str) -> Result<X509Req
     let x509_name = x509_name.build();
     req_builder.set_subject_name(&x509_name)?;
 
-    req_builder.sign(key_pair, MessageDigest::sha256())?;
+    req_builder.sign(key_pair, signature_digest(key_pair))?;
     let req = req_builder.build();
     Ok(req)
 }
@@ -105,70 +191,447 @@ pub fn mk_ca_signed_cert(
     ca_cert: &X509Ref,
     ca_key_pair: &PKeyRef<Private>,
     common_name: &str,
+    algorithm: KeyAlgorithm,
 ) -> Result<(X509, PKey<Private>), ErrorStack> {
-    let rsa = Rsa::generate(2048)?;
-    let key_pair = PKey::from_rsa(rsa)?;
+    CertBuilder::new(common_name)
+        .key_algorithm(algorithm)
+        .sign(ca_cert, ca_key_pair)
+}
 
-    let req = mk_request(&key_pair, common_name)?;
+/// Entry of the subject alternative name extension.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AltName {
+    Dns(String),
+    Ip(IpAddr),
+    Uri(String),
+}
 
-    let mut cert_builder = X509::builder()?;
-    cert_builder.set_version(2)?;
-    let serial_number = {
-        let mut serial = BigNum::new()?;
-        serial.rand(159, MsbOption::MAYBE_ZERO, false)?;
-        serial.to_asn1_integer()?
+/// Builder of certificates signed by a CA.
+///
+/// Unless configured otherwise, certificates have subject `C=US, ST=TX, O=Some organization`
+/// along with the common name, DNS names `*.example.com` and `hello.com`, are valid for 365 days
+/// from now, and have no extended key usage.
+#[derive(Debug, Clone)]
+pub struct CertBuilder {
+    /// Subject fields in order, by short name such as `CN` or `O`
+    subject: Vec<(String, String)>,
+    alt_names: Vec<AltName>,
+    not_before: SystemTime,
+    not_after: SystemTime,
+    /// Extended key usages by OpenSSL name, such as `clientAuth`, or OID
+    extended_key_usages: Vec<String>,
+    algorithm: KeyAlgorithm,
+}
+
+impl CertBuilder {
+    pub fn new(common_name: &str) -> Self {
+        let now = SystemTime::now();
+        Self {
+            subject: [
+                ("C", "US"),
+                ("ST", "TX"),
+                ("O", "Some organization"),
+                ("CN", common_name),
+            ]
+            .iter()
+            .map(|&(field, value)| (field.to_owned(), value.to_owned()))
+            .collect(),
+            alt_names: vec![
+                AltName::Dns("*.example.com".to_owned()),
+                AltName::Dns("hello.com".to_owned()),
+            ],
+            not_before: now,
+            not_after: now + Duration::from_secs(365 * SECONDS_PER_DAY),
+            extended_key_usages: vec![],
+            algorithm: KeyAlgorithm::default(),
+        }
+    }
+
+    /// Set a subject field such as `O` or `OU`, replacing its value if it is already set. An empty
+    /// value removes the field.
+    pub fn subject(mut self, field: &str, value: &str) -> Self {
+        match self.subject.iter_mut().find(|(name, _)| name == field) {
+            Some(entry) => entry.1 = value.to_owned(),
+            None => self.subject.push((field.to_owned(), value.to_owned())),
+        }
+        self.subject.retain(|(_, value)| !value.is_empty());
+        self
+    }
+
+    pub fn common_name(self, common_name: &str) -> Self {
+        self.subject("CN", common_name)
+    }
+
+    /// Replace the subject alternative names; none leaves the extension out.
+    pub fn alt_names(mut self, alt_names: Vec<AltName>) -> Self {
+        self.alt_names = alt_names;
+        self
+    }
+
+    pub fn dns(mut self, name: &str) -> Self {
+        self.alt_names.push(AltName::Dns(name.to_owned()));
+        self
+    }
+
+    pub fn ip(mut self, ip: IpAddr) -> Self {
+        self.alt_names.push(AltName::Ip(ip));
+        self
+    }
+
+    pub fn uri(mut self, uri: &str) -> Self {
+        self.alt_names.push(AltName::Uri(uri.to_owned()));
+        self
+    }
+
+    /// Start of the validity window, which may be in the future to make a certificate that is not
+    /// valid yet.
+    pub fn not_before(mut self, not_before: SystemTime) -> Self {
+        self.not_before = not_before;
+        self
+    }
+
+    /// End of the validity window, which may be in the past to make an expired certificate.
+    pub fn not_after(mut self, not_after: SystemTime) -> Self {
+        self.not_after = not_after;
+        self
+    }
+
+    /// Add an extended key usage, by OpenSSL name such as `clientAuth` or `serverAuth`, or OID.
+    pub fn extended_key_usage(mut self, usage: &str) -> Self {
+        self.extended_key_usages.push(usage.to_owned());
+        self
+    }
+
+    pub fn key_algorithm(mut self, algorithm: KeyAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Replace `%d` in the subject and in DNS names and URIs with `id`.
+    pub fn expand(&self, id: usize) -> Self {
+        let id = id.to_string();
+        let mut builder = self.clone();
+        for (_, value) in &mut builder.subject {
+            *value = value.replace("%d", &id);
+        }
+        for alt_name in &mut builder.alt_names {
+            match alt_name {
+                AltName::Dns(value) | AltName::Uri(value) => *value = value.replace("%d", &id),
+                AltName::Ip(_) => {}
+            }
+        }
+        builder
+    }
+
+    /// Generate a key pair and sign a certificate for it with the given CA.
+    pub fn sign(
+        &self,
+        ca_cert: &X509Ref,
+        ca_key_pair: &PKeyRef<Private>,
+    ) -> Result<(X509, PKey<Private>), ErrorStack> {
+        let key_pair = self.algorithm.generate()?;
+
+        let mut x509_name = X509NameBuilder::new()?;
+        for (field, value) in &self.subject {
+            x509_name.append_entry_by_text(field, value)?;
+        }
+        let x509_name = x509_name.build();
+
+        let mut cert_builder = X509::builder()?;
+        cert_builder.set_version(2)?;
+        let serial_number = {
+            let mut serial = BigNum::new()?;
+            serial.rand(159, MsbOption::MAYBE_ZERO, false)?;
+            serial.to_asn1_integer()?
+        };
+        cert_builder.set_serial_number(&serial_number)?;
+        cert_builder.set_subject_name(&x509_name)?;
+        cert_builder.set_issuer_name(ca_cert.subject_name())?;
+        cert_builder.set_pubkey(&key_pair)?;
+        let not_before = asn1_time(self.not_before)?;
+        cert_builder.set_not_before(&not_before)?;
+        let not_after = asn1_time(self.not_after)?;
+        cert_builder.set_not_after(&not_after)?;
+
+        cert_builder.append_extension(BasicConstraints::new().build()?)?;
+
+        let mut key_usage = KeyUsage::new();
+        key_usage.critical().non_repudiation().digital_signature();
+        // Only RSA keys exchange keys by encryption
+        if key_pair.id() == Id::RSA {
+            key_usage.key_encipherment();
+        }
+        cert_builder.append_extension(key_usage.build()?)?;
+
+        if !self.extended_key_usages.is_empty() {
+            let mut extended_key_usage = ExtendedKeyUsage::new();
+            for usage in &self.extended_key_usages {
+                extended_key_usage.other(usage);
+            }
+            cert_builder.append_extension(extended_key_usage.build()?)?;
+        }
+
+        let subject_key_identifier =
+            SubjectKeyIdentifier::new().build(&cert_builder.x509v3_context(Some(ca_cert), None))?;
+        cert_builder.append_extension(subject_key_identifier)?;
+
+        let auth_key_identifier = AuthorityKeyIdentifier::new()
+            .keyid(false)
+            .issuer(false)
+            .build(&cert_builder.x509v3_context(Some(ca_cert), None))?;
+        cert_builder.append_extension(auth_key_identifier)?;
+
+        if !self.alt_names.is_empty() {
+            let mut subject_alt_name = SubjectAlternativeName::new();
+            for alt_name in &self.alt_names {
+                match alt_name {
+                    AltName::Dns(name) => subject_alt_name.dns(name),
+                    AltName::Ip(ip) => subject_alt_name.ip(&ip.to_string()),
+                    AltName::Uri(uri) => subject_alt_name.uri(uri),
+                };
+            }
+            let subject_alt_name =
+                subject_alt_name.build(&cert_builder.x509v3_context(Some(ca_cert), None))?;
+            cert_builder.append_extension(subject_alt_name)?;
+        }
+
+        cert_builder.sign(ca_key_pair, signature_digest(ca_key_pair))?;
+        let cert = cert_builder.build();
+
+        Ok((cert, key_pair))
+    }
+}
+
+fn asn1_time(time: SystemTime) -> Result<Asn1Time, ErrorStack> {
+    let secs = match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
     };
-    cert_builder.set_serial_number(&serial_number)?;
-    cert_builder.set_subject_name(req.subject_name())?;
-    cert_builder.set_issuer_name(ca_cert.subject_name())?;
-    cert_builder.set_pubkey(&key_pair)?;
-    let not_before = Asn1Time::days_from_now(0)?;
-    cert_builder.set_not_before(&not_before)?;
-    let not_after = Asn1Time::days_from_now(365)?;
-    cert_builder.set_not_after(&not_after)?;
+    Asn1Time::from_unix(secs)
+}
 
-    cert_builder.append_extension(BasicConstraints::new().build()?)?;
+/// Issues certificates for clients to present during the TLS handshake.
+///
+/// The MQTT client library only loads certificates and keys from files. Without a cache
+/// directory, certificates are signed by the CA as clients are created and written to a directory
+/// of their own under the temporary directory, which is removed once the issuer and all
+/// identities it issued are dropped. With a cache directory, certificates are loaded from it by
+/// client ID, and those missing are signed and kept there for later runs.
+pub struct Issuer {
+    /// CA signing the certificates that are not cached
+    ca: Option<(X509, PKey<Private>)>,
+    dir: PathBuf,
+    /// Whether `dir` is a cache shared by runs rather than a temporary directory
+    cached: bool,
+    issued: AtomicUsize,
+    /// Certificates to sign, whose common name is set for every client
+    template: CertBuilder,
+}
 
-    cert_builder.append_extension(
-        KeyUsage::new()
-            .critical()
-            .non_repudiation()
-            .digital_signature()
-            .key_encipherment()
-            .build()?,
-    )?;
+/// Certificate and private key files of a client, removed when dropped unless cached.
+#[derive(Debug)]
+pub struct Identity {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    cached: bool,
+}
 
-    let subject_key_identifier =
-        SubjectKeyIdentifier::new().build(&cert_builder.x509v3_context(Some(ca_cert), None))?;
-    cert_builder.append_extension(subject_key_identifier)?;
+impl Issuer {
+    /// Issuer signing certificates with the given CA into a temporary directory.
+    pub fn load(ca_cert_path: &Path, ca_key_path: &Path) -> Result<Self, Error> {
+        static ISSUERS: AtomicUsize = AtomicUsize::new(0);
+
+        let dir = std::env::temp_dir().join(format!(
+            "mqtt-bench-{}-{}",
+            std::process::id(),
+            ISSUERS.fetch_add(1, Ordering::Relaxed)
+        ));
+        Self::new(dir, Some(load_ca(ca_cert_path, ca_key_path)?), false)
+    }
 
-    let auth_key_identifier = AuthorityKeyIdentifier::new()
-        .keyid(false)
-        .issuer(false)
-        .build(&cert_builder.x509v3_context(Some(ca_cert), None))?;
-    cert_builder.append_extension(auth_key_identifier)?;
+    /// Issuer loading certificates from `dir`, signing those missing with the CA, if any.
+    pub fn cached(dir: &Path, ca: Option<(&Path, &Path)>) -> Result<Self, Error> {
+        let ca = match ca {
+            Some((ca_cert_path, ca_key_path)) => Some(load_ca(ca_cert_path, ca_key_path)?),
+            None => None,
+        };
+        Self::new(dir.to_owned(), ca, true)
+    }
 
-    let subject_alt_name = SubjectAlternativeName::new()
-        .dns("*.example.com")
-        .dns("hello.com")
-        .build(&cert_builder.x509v3_context(Some(ca_cert), None))?;
-    cert_builder.append_extension(subject_alt_name)?;
+    fn new(dir: PathBuf, ca: Option<(X509, PKey<Private>)>, cached: bool) -> Result<Self, Error> {
+        fs::create_dir_all(&dir).context(format!("Failed to create {}", dir.display()))?;
+        Ok(Self {
+            ca,
+            dir,
+            cached,
+            issued: AtomicUsize::new(0),
+            template: CertBuilder::new(""),
+        })
+    }
 
-    cert_builder.sign(ca_key_pair, MessageDigest::sha256())?;
-    let cert = cert_builder.build();
+    /// Sign certificates built from `template`, with `%d` expanded to the ID of each client.
+    /// Cached certificates are used as they are.
+    pub fn template(mut self, template: CertBuilder) -> Self {
+        self.template = template;
+        self
+    }
 
-    Ok((cert, key_pair))
+    /// Certificate of client `id` with `common_name` as CN and its key, signing it unless it is
+    /// cached.
+    pub fn issue(&self, id: usize, common_name: &str) -> Result<Identity, Error> {
+        let stem = if self.cached {
+            file_stem(common_name)
+        } else {
+            // Several clients may share a name
+            format!(
+                "{}-{}",
+                self.issued.fetch_add(1, Ordering::Relaxed),
+                file_stem(common_name)
+            )
+        };
+        let identity = Identity {
+            cert: self.dir.join(format!("{}.crt", stem)),
+            key: self.dir.join(format!("{}.key", stem)),
+            cached: self.cached,
+        };
+        if self.cached && identity.cert.exists() && identity.key.exists() {
+            return Ok(identity);
+        }
+
+        let Some((ca_cert, ca_key)) = &self.ca else {
+            bail!(
+                "No certificate for {} in {}",
+                common_name,
+                self.dir.display()
+            );
+        };
+        let (cert, key) = self
+            .template
+            .expand(id)
+            .common_name(common_name)
+            .sign(ca_cert, ca_key)
+            .context(format!("Failed to sign certificate for {}", common_name))?;
+        // The key goes first, so that a cached certificate always comes with its key
+        write_atomically(&identity.key, &key.private_key_to_pem_pkcs8()?)?;
+        write_atomically(&identity.cert, &cert.to_pem()?)?;
+        Ok(identity)
+    }
+
+    /// Issue certificates for all clients, given by ID and common name, on all cores, e.g. to fill
+    /// a cache ahead of a run.
+    pub fn generate(&self, clients: &[(usize, String)]) -> Result<(), Error> {
+        let next = AtomicUsize::new(0);
+        let threads = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        std::thread::scope(|scope| {
+            let workers = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| -> Result<(), Error> {
+                        loop {
+                            let index = next.fetch_add(1, Ordering::Relaxed);
+                            let Some((id, common_name)) = clients.get(index) else {
+                                return Ok(());
+                            };
+                            self.issue(*id, common_name)?;
+                            if (index + 1).is_multiple_of(1000) {
+                                info!("Issued {}/{} certificates", index + 1, clients.len());
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().expect("Certificate worker panicked"))
+        })
+    }
+}
+
+impl Drop for Issuer {
+    fn drop(&mut self) {
+        // Fails while identities are still in use; the last one to go removes the directory
+        if !self.cached {
+            let _ = fs::remove_dir(&self.dir);
+        }
+    }
+}
+
+impl Identity {
+    /// Certificate and key provisioned ahead of the run, which are never removed.
+    pub fn provisioned(cert: PathBuf, key: PathBuf) -> Self {
+        Self {
+            cert,
+            key,
+            cached: true,
+        }
+    }
+}
+
+impl Drop for Identity {
+    fn drop(&mut self) {
+        if self.cached {
+            return;
+        }
+        let _ = fs::remove_file(&self.cert);
+        let _ = fs::remove_file(&self.key);
+        if let Some(dir) = self.cert.parent() {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+}
+
+fn load_ca(ca_cert_path: &Path, ca_key_path: &Path) -> Result<(X509, PKey<Private>), Error> {
+    let ca_cert = load_ca_cert(ca_cert_path)
+        .context(format!("Invalid CA certificate {}", ca_cert_path.display()))?;
+    let ca_key =
+        load_ca_pkey(ca_key_path).context(format!("Invalid CA key {}", ca_key_path.display()))?;
+    Ok((ca_cert, ca_key))
+}
+
+/// File name for certificates of `common_name`, escaping characters that are not safe in file
+/// names, so that distinct names never share files.
+fn file_stem(common_name: &str) -> String {
+    let mut stem = String::with_capacity(common_name.len());
+    for byte in common_name.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => stem.push(byte as char),
+            _ => stem.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    stem
+}
+
+/// Write `contents` to a temporary file renamed to `path`, so that concurrent runs sharing a
+/// cache never read a partial file.
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+    let temporary = path.with_extension(format!(
+        "tmp-{}-{}",
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&temporary, contents).context(format!("Failed to write {}", path.display()))?;
+    fs::rename(&temporary, path).context(format!("Failed to write {}", path.display()))?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::cert::{load_ca_cert, load_ca_pkey, mk_ca_signed_cert};
+    use crate::broker::{tls_acceptor, Broker};
+    use crate::cert::{
+        load_ca_cert, load_ca_pkey, mk_ca_cert, mk_ca_signed_cert, CertBuilder, Issuer,
+        KeyAlgorithm, SECONDS_PER_DAY,
+    };
     use anyhow::Error;
     use log::info;
+    use openssl::asn1::Asn1Time;
     use openssl::nid::Nid;
-    use openssl::ssl::{NameType, SniError, SslConnector, SslMethod, SslVerifyMode};
+    use openssl::pkey::{Id, PKey};
+    use openssl::ssl::{NameType, SniError, SslConnector, SslMethod, SslVerifyMode, SslVersion};
+    use openssl::x509::X509;
     use std::net::TcpStream;
     use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
 
     #[test]
     fn test_load_ca_pkey() -> Result<(), Error> {
@@ -205,7 +668,7 @@ mod tests {
         ca_cert_path_buf.push("CA.crt");
         let ca_cert = load_ca_cert(&ca_cert_path_buf)?;
 
-        let (cert, _key) = mk_ca_signed_cert(&ca_cert, &ca_key, "abc.com")?;
+        let (cert, _key) = mk_ca_signed_cert(&ca_cert, &ca_key, "abc.com", KeyAlgorithm::Rsa2048)?;
 
         for entry in cert.subject_name().entries() {
             let asn1_object = entry.object();
@@ -218,6 +681,199 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_key_algorithms() -> Result<(), Error> {
+        // RSA 4096 is slow to generate and signs like the other RSA sizes
+        let algorithms = [
+            KeyAlgorithm::Rsa2048,
+            KeyAlgorithm::EcdsaP256,
+            KeyAlgorithm::EcdsaP384,
+            KeyAlgorithm::Ed25519,
+        ];
+        for ca_algorithm in algorithms {
+            let (ca_cert, ca_key) = mk_ca_cert(ca_algorithm)?;
+            assert!(ca_cert.verify(&ca_key)?, "{}", ca_algorithm);
+            for algorithm in algorithms {
+                let (cert, key) = mk_ca_signed_cert(&ca_cert, &ca_key, "abc.com", algorithm)?;
+                assert!(
+                    cert.verify(&ca_key)?,
+                    "{} signed by {}",
+                    algorithm,
+                    ca_algorithm
+                );
+                assert!(cert.public_key()?.public_eq(&key));
+            }
+        }
+
+        let key = KeyAlgorithm::EcdsaP384.generate()?;
+        assert_eq!((Id::EC, 384), (key.id(), key.bits()));
+        assert_eq!(3072, KeyAlgorithm::Rsa3072.generate()?.bits());
+        assert_eq!(Id::ED25519, KeyAlgorithm::Ed25519.generate()?.id());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cert_builder() -> Result<(), Error> {
+        let assets = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets");
+        let ca_cert = load_ca_cert(&assets.join("CA.crt"))?;
+        let ca_key = load_ca_pkey(&assets.join("CA.key"))?;
+
+        let (cert, _key) = CertBuilder::new("device-%d")
+            .subject("O", "Acme")
+            .subject("ST", "")
+            .subject("OU", "fleet-%d")
+            .alt_names(vec![])
+            .dns("device-%d.local")
+            .ip("10.0.0.1".parse()?)
+            .uri("urn:device:%d")
+            .not_after(SystemTime::now() + Duration::from_secs(30 * SECONDS_PER_DAY))
+            .extended_key_usage("clientAuth")
+            .expand(7)
+            .sign(&ca_cert, &ca_key)?;
+
+        let subject = cert
+            .subject_name()
+            .entries()
+            .map(|entry| {
+                let field = entry.object().nid().short_name().unwrap().to_owned();
+                (field, entry.data().as_utf8().unwrap().to_string())
+            })
+            .collect::<Vec<_>>();
+        let expected = [
+            ("C", "US"),
+            ("O", "Acme"),
+            ("CN", "device-7"),
+            ("OU", "fleet-7"),
+        ]
+        .map(|(field, value)| (field.to_owned(), value.to_owned()));
+        assert_eq!(expected.to_vec(), subject);
+
+        let alt_names = cert.subject_alt_names().unwrap();
+        assert_eq!(Some("device-7.local"), alt_names.get(0).unwrap().dnsname());
+        assert_eq!(
+            Some(&[10, 0, 0, 1][..]),
+            alt_names.get(1).unwrap().ipaddress()
+        );
+        assert_eq!(Some("urn:device:7"), alt_names.get(2).unwrap().uri());
+
+        assert!(cert.not_after() < Asn1Time::days_from_now(31)?);
+        let text = String::from_utf8(cert.to_text()?)?;
+        assert!(text.contains("TLS Web Client Authentication"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_client() -> Result<(), Error> {
+        let assets = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets");
+        let ca_cert = load_ca_cert(&assets.join("CA.crt"))?;
+        let ca_key = load_ca_pkey(&assets.join("CA.key"))?;
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let broker = runtime
+            .block_on(Broker::bind("127.0.0.1:0".parse()?))?
+            .tls(tls_acceptor(
+                &ca_cert,
+                &ca_key,
+                KeyAlgorithm::EcdsaP256,
+                true,
+            )?);
+        let addr = broker.local_addr()?;
+        runtime.spawn(broker.run());
+
+        let handshake = |builder: CertBuilder| -> Result<(), Error> {
+            let (cert, key) = builder
+                .key_algorithm(KeyAlgorithm::EcdsaP256)
+                .sign(&ca_cert, &ca_key)?;
+            let mut ssl_connector_builder = SslConnector::builder(SslMethod::tls_client())?;
+            ssl_connector_builder.set_certificate(&cert)?;
+            ssl_connector_builder.set_private_key(&key)?;
+            ssl_connector_builder.set_verify(SslVerifyMode::NONE);
+            // With TLS 1.3, the client learns that its certificate was rejected only after the
+            // handshake
+            ssl_connector_builder.set_max_proto_version(Some(SslVersion::TLS1_2))?;
+            let tcp_stream = TcpStream::connect(addr)?;
+            ssl_connector_builder
+                .build()
+                .connect("localhost", tcp_stream)?;
+            Ok(())
+        };
+
+        let now = SystemTime::now();
+        let day = Duration::from_secs(SECONDS_PER_DAY);
+        handshake(CertBuilder::new("valid").extended_key_usage("clientAuth"))?;
+        let expired = CertBuilder::new("expired")
+            .not_before(now - 2 * day)
+            .not_after(now - day);
+        assert!(handshake(expired).is_err());
+        let not_yet_valid = CertBuilder::new("not-yet-valid")
+            .not_before(now + day)
+            .not_after(now + 2 * day);
+        assert!(handshake(not_yet_valid).is_err());
+        let server_only = CertBuilder::new("server").extended_key_usage("serverAuth");
+        assert!(handshake(server_only).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_issue() -> Result<(), Error> {
+        let assets = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets");
+        let issuer = Issuer::load(&assets.join("CA.crt"), &assets.join("CA.key"))?;
+        let ca_cert = load_ca_cert(&assets.join("CA.crt"))?;
+
+        let identity = issuer.issue(1, "BenchClient/1")?;
+        let cert = X509::from_pem(&std::fs::read(&identity.cert)?)?;
+        let key = PKey::private_key_from_pem(&std::fs::read(&identity.key)?)?;
+        let common_name = cert
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .map(|entry| entry.data().as_slice().to_vec());
+        assert_eq!(Some(b"BenchClient/1".to_vec()), common_name);
+        assert!(cert.verify(&*ca_cert.public_key()?)?);
+        assert!(cert.public_key()?.public_eq(&key));
+
+        // Clients sharing an ID get files of their own, removed along with the identity
+        let other = issuer.issue(1, "BenchClient/1")?;
+        assert_ne!(identity.cert, other.cert);
+        let dir = identity.cert.parent().unwrap().to_owned();
+        drop(identity);
+        drop(issuer);
+        assert!(dir.exists());
+        drop(other);
+        assert!(!dir.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate() -> Result<(), Error> {
+        let assets = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets");
+        let dir = std::env::temp_dir().join(format!("mqtt-bench-cache-{}", std::process::id()));
+        let clients = (0..8)
+            .map(|id| (id, format!("device/{}", id)))
+            .chain([(0, "device_0".to_owned())])
+            .collect::<Vec<_>>();
+        Issuer::cached(&dir, Some((&assets.join("CA.crt"), &assets.join("CA.key"))))?
+            .generate(&clients)?;
+        assert_eq!(2 * clients.len(), std::fs::read_dir(&dir)?.count());
+
+        // Cached certificates are loaded without the CA and kept after use
+        let issuer = Issuer::cached(&dir, None)?;
+        let identity = issuer.issue(3, "device/3")?;
+        let cert = X509::from_pem(&std::fs::read(&identity.cert)?)?;
+        let common_name = cert
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .map(|entry| entry.data().as_slice().to_vec());
+        assert_eq!(Some(b"device/3".to_vec()), common_name);
+        drop(identity);
+        assert!(issuer.issue(3, "device/3")?.cert.exists());
+        assert!(issuer.issue(8, "device/8").is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
     #[test]
     fn test_tls_connect() -> Result<(), Error> {
         let _ = env_logger::builder().is_test(true).try_init();
@@ -231,9 +887,21 @@ mod tests {
         ca_cert_path_buf.push("CA.crt");
         let ca_cert = load_ca_cert(&ca_cert_path_buf)?;
 
-        let (cert, key) = mk_ca_signed_cert(&ca_cert, &ca_key, "abc.com")?;
-
-        let tcp_stream = TcpStream::connect("www.baidu.com:443")?;
+        let (cert, key) = mk_ca_signed_cert(&ca_cert, &ca_key, "abc.com", KeyAlgorithm::Rsa2048)?;
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let broker = runtime
+            .block_on(Broker::bind("127.0.0.1:0".parse()?))?
+            .tls(tls_acceptor(
+                &ca_cert,
+                &ca_key,
+                KeyAlgorithm::Rsa2048,
+                false,
+            )?);
+        let addr = broker.local_addr()?;
+        runtime.spawn(broker.run());
+
+        let tcp_stream = TcpStream::connect(addr)?;
         let mut ssl_connector_builder = SslConnector::builder(SslMethod::tls_client())?;
         ssl_connector_builder.set_certificate(&cert)?;
         ssl_connector_builder.set_private_key(&key)?;
@@ -247,7 +915,7 @@ mod tests {
             },
         );
         let ssl_connector = ssl_connector_builder.build();
-        let _ssl_stream = ssl_connector.connect("www.baidu.com", tcp_stream)?;
+        let _ssl_stream = ssl_connector.connect("localhost", tcp_stream)?;
         info!("SSL connected");
         Ok(())
     }
@@ -255,7 +923,8 @@ mod tests {
 
 #[cfg(test)]
 mod async_tests {
-    use crate::cert::{load_ca_cert, load_ca_pkey, mk_ca_signed_cert};
+    use crate::broker::{tls_acceptor, Broker};
+    use crate::cert::{load_ca_cert, load_ca_pkey, mk_ca_signed_cert, KeyAlgorithm};
     use anyhow::Error;
     use log::info;
     use openssl::ssl::{Ssl, SslContext, SslMethod, SslVerifyMode};
@@ -276,7 +945,7 @@ mod async_tests {
         ca_cert_path_buf.push("CA.crt");
         let ca_cert = load_ca_cert(&ca_cert_path_buf)?;
 
-        let (cert, key) = mk_ca_signed_cert(&ca_cert, &ca_key, "abc.com")?;
+        let (cert, key) = mk_ca_signed_cert(&ca_cert, &ca_key, "abc.com", KeyAlgorithm::Rsa2048)?;
 
         let mut ssl_context_builder = SslContext::builder(SslMethod::tls_client())?;
         ssl_context_builder.set_certificate(&cert)?;
@@ -285,7 +954,16 @@ mod async_tests {
         let ssl_context = ssl_context_builder.build();
         let ssl = Ssl::new(&ssl_context)?;
 
-        let tcp_stream = TcpStream::connect("www.baidu.com:443").await?;
+        let addr = Broker::bind("127.0.0.1:0".parse()?)
+            .await?
+            .tls(tls_acceptor(
+                &ca_cert,
+                &ca_key,
+                KeyAlgorithm::Rsa2048,
+                false,
+            )?)
+            .spawn()?;
+        let tcp_stream = TcpStream::connect(addr).await?;
         let ssl_stream = SslStream::new(ssl, tcp_stream)?;
         let ssl_stream = pin!(ssl_stream);
         ssl_stream.connect().await?;
@@ -1,4 +1,20 @@
-use clap::{Args, Parser, Subcommand};
+use crate::auth::{AuthMethod, Hmac, Jwt, JwtKey, PasswordGenerator, Plain, SignatureEncoding};
+use crate::cert::{load_pkey, AltName, CertBuilder, Issuer, KeyAlgorithm};
+use crate::credentials::{Credential, Credentials};
+use crate::payload::{PayloadSource, Payloads, SizeDistribution};
+use crate::profile::Profile;
+use crate::topic::{Template, TopicMapper, TopicMapping, Topics};
+use anyhow::{bail, ensure, Context};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use log::info;
+use openssl::ssl::SslVersion;
+use paho_mqtt as mqtt;
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 #[derive(Debug, Parser)]
 #[command(name = "mqtt-bench", author, version, about, long_about = None)]
@@ -7,29 +23,108 @@ pub struct Cli {
     pub command: Option<Commands>,
 }
 
-#[derive(Debug, Clone, Args)]
+#[derive(Debug, Clone, Args, Serialize)]
 pub struct Common {
+    /// Host of the MQTT server. With `--ssl`, it is also the server name sent for SNI, which the
+    /// MQTT client library cannot override.
     #[arg(long)]
     pub host: String,
 
     #[arg(short = 'p', long)]
     pub port: Option<u16>,
 
+    /// User name of every client. Can contain a `%d` placeholder.
     #[arg(short = 'u', long)]
     pub username: String,
 
+    /// Password of every client. Can contain a `%d` placeholder.
     #[arg(short = 'P', long)]
+    #[serde(skip_serializing)]
     pub password: String,
 
+    /// CSV file of `client_id,username,password[,cert,key]` lines, the `n`th being used by client
+    /// `n` instead of `--client-id`, `--username` and `--password`. Certificates and keys are
+    /// presented during the TLS handshake.
+    #[arg(long)]
+    pub credentials_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub auth_options: AuthOptions,
+
     #[arg(short = 's', long)]
     pub ssl: bool,
 
+    /// Transport to carry MQTT over. WebSocket connections use TLS, i.e. `wss://`, with `--ssl`.
+    #[arg(long, value_enum, default_value_t = Transport::Tcp)]
+    pub transport: Transport,
+
+    /// Path of the WebSocket endpoint of the server.
+    #[arg(long, default_value_t = String::from("/mqtt"))]
+    pub ws_path: String,
+
+    /// Header of the WebSocket upgrade request as `name=value`. Can be repeated.
+    #[arg(long = "ws-header", value_parser = parse_key_value)]
+    // Headers such as `Authorization` carry credentials
+    #[serde(skip_serializing)]
+    pub ws_headers: Vec<(String, String)>,
+
     #[arg(short, long)]
     pub verify: bool,
 
     #[arg(short, long)]
     pub auth_server_certificate: bool,
 
+    /// PEM file of CA certificates to verify the server certificate with, e.g. those of a private
+    /// CA, instead of the default trust store of OpenSSL. Verification needs
+    /// `--auth-server-certificate`.
+    #[arg(long, requires = "ssl")]
+    pub ca_bundle: Option<PathBuf>,
+
+    /// Directory of CA certificates to verify the server certificate with, named by subject hash
+    /// as done by `openssl rehash`.
+    #[arg(long, requires = "ssl")]
+    pub ca_path: Option<PathBuf>,
+
+    /// OpenSSL cipher list to offer with TLS 1.2 and below, e.g. `ECDHE-ECDSA-AES128-GCM-SHA256`.
+    ///
+    /// It does not choose TLS 1.3 cipher suites: OpenSSL offers its default ones, so servers that
+    /// only accept TLS 1.3 negotiate one of those whatever this list is.
+    #[arg(long, requires = "ssl")]
+    pub ciphers: Option<String>,
+
+    /// Protocol to offer with ALPN during the TLS handshake, e.g. `mqtt`. Can be repeated.
+    #[arg(long, requires = "ssl")]
+    pub alpn: Vec<String>,
+
+    /// TLS version to connect with: `1.0`, `1.1`, `1.2`, or `default` to negotiate the highest
+    /// version both sides support, TLS 1.3 included. The MQTT client library cannot pin `1.3`.
+    #[arg(
+        long,
+        requires = "ssl",
+        value_parser = parse_client_tls_version,
+        default_value = "default"
+    )]
+    pub tls_version: ClientTlsVersion,
+
+    /// CA certificate to sign a certificate for every client with, presented to the server during
+    /// the TLS handshake. The common name of each certificate is the client ID.
+    #[arg(long, requires_all = ["ca_key", "ssl"])]
+    pub ca_cert: Option<PathBuf>,
+
+    /// Private key of the CA certificate given by `--ca-cert`.
+    #[arg(long, requires = "ca_cert")]
+    pub ca_key: Option<PathBuf>,
+
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub cert_options: CertOptions,
+
+    /// Directory of client certificates written by `cert generate`, loaded by client ID. With
+    /// `--ca-cert`, missing certificates are signed and added to it.
+    #[arg(long, requires = "ssl")]
+    pub cert_dir: Option<PathBuf>,
+
     #[arg(short = 'q', long, default_value_t = 1)]
     pub qos: i32,
 
@@ -66,87 +161,715 @@ pub struct Common {
 
     #[arg(long, default_value_t = 1024)]
     pub max_inflight: i32,
+
+    /// MQTT protocol version to speak to the server.
+    #[arg(long, value_enum, default_value_t = MqttVersion::V3_1_1)]
+    pub mqtt_version: MqttVersion,
+
+    /// File to write the final report of the run to.
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+
+    /// Format of the report written to `--output`.
+    #[arg(long, value_enum, default_value_t = ReportFormat::Json)]
+    pub format: ReportFormat,
+
+    /// File to write the per-second time series of the run to.
+    #[arg(long)]
+    pub series: Option<PathBuf>,
+
+    /// Format of the time series written to `--series`.
+    #[arg(long, value_enum, default_value_t = SeriesFormat::Csv)]
+    pub series_format: SeriesFormat,
+
+    /// Address to serve Prometheus metrics on during the run, e.g. `0.0.0.0:9090`.
+    #[arg(long)]
+    pub metrics_listen: Option<SocketAddr>,
 }
 
 impl Common {
     pub fn connection_string(&self) -> String {
-        if self.ssl {
-            format!("ssl://{}:{}", self.host, self.port.unwrap_or(8883))
-        } else {
-            format!("tcp://{}:{}", self.host, self.port.unwrap_or(1883))
+        match (self.transport, self.ssl) {
+            (Transport::Tcp, false) => format!("tcp://{}:{}", self.host, self.port.unwrap_or(1883)),
+            (Transport::Tcp, true) => format!("ssl://{}:{}", self.host, self.port.unwrap_or(8883)),
+            (Transport::Ws, ssl) => {
+                let (scheme, port) = if ssl { ("wss", 8084) } else { ("ws", 8083) };
+                let separator = if self.ws_path.starts_with('/') {
+                    ""
+                } else {
+                    "/"
+                };
+                format!(
+                    "{}://{}:{}{}{}",
+                    scheme,
+                    self.host,
+                    self.port.unwrap_or(port),
+                    separator,
+                    self.ws_path
+                )
+            }
         }
     }
 
+    /// Issuer of client certificates, if `--cert-dir` or `--ca-cert` and `--ca-key` are given.
+    pub fn issuer(&self) -> Result<Option<Issuer>, anyhow::Error> {
+        let ca = match (&self.ca_cert, &self.ca_key) {
+            (Some(ca_cert), Some(ca_key)) => Some((ca_cert.as_path(), ca_key.as_path())),
+            _ => None,
+        };
+        match (&self.cert_dir, ca) {
+            (Some(cert_dir), ca) => Issuer::cached(cert_dir, ca),
+            (None, Some((ca_cert, ca_key))) => Issuer::load(ca_cert, ca_key),
+            (None, None) => return Ok(None),
+        }
+        .map(|issuer| Some(issuer.template(self.cert_options.template())))
+    }
+
+    /// Credentials of `--credentials-file`, if given, checked to cover every client.
+    pub fn credentials(&self) -> Result<Option<Credentials>, anyhow::Error> {
+        let Some(path) = &self.credentials_file else {
+            return Ok(None);
+        };
+        let credentials = Credentials::load(path)?;
+        let needed = self.start_number + self.total;
+        if credentials.len() < needed {
+            bail!(
+                "{} has {} credentials, but clients up to {} need one",
+                path.display(),
+                credentials.len(),
+                needed - 1
+            );
+        }
+        Ok(Some(credentials))
+    }
+
     pub fn client_id_of(&self, id: usize) -> String {
-        if self.client_id.contains("%d") {
-            return self.client_id.replace("%d", &id.to_string());
+        expand_id(&self.client_id, id)
+    }
+
+    /// Credentials of the client of the given ID: its entry of `credentials` if given, or else
+    /// the expansion of `--client-id`, `--username` and `--password`.
+    pub fn credential_of(&self, id: usize, credentials: Option<&Credentials>) -> Credential {
+        if let Some(credential) = credentials.and_then(|credentials| credentials.get(id)) {
+            return credential.clone();
+        }
+        Credential {
+            client_id: self.client_id_of(id),
+            username: expand_id(&self.username, id),
+            password: expand_id(&self.password, id),
+            cert: None,
+        }
+    }
+}
+
+/// Expand `%d` in a template to `id`.
+fn expand_id(template: &str, id: usize) -> String {
+    if template.contains("%d") {
+        return template.replace("%d", &id.to_string());
+    }
+    template.to_owned()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+pub enum MqttVersion {
+    #[value(name = "3.1")]
+    #[serde(rename = "3.1")]
+    V3_1,
+    #[value(name = "3.1.1")]
+    #[serde(rename = "3.1.1")]
+    V3_1_1,
+    #[value(name = "5")]
+    #[serde(rename = "5")]
+    V5,
+}
+
+impl MqttVersion {
+    /// Protocol version number as understood by the Paho client.
+    pub fn as_u32(&self) -> u32 {
+        match self {
+            MqttVersion::V3_1 => mqtt::MQTT_VERSION_3_1,
+            MqttVersion::V3_1_1 => mqtt::MQTT_VERSION_3_1_1,
+            MqttVersion::V5 => mqtt::MQTT_VERSION_5,
+        }
+    }
+
+    pub fn is_v5(&self) -> bool {
+        *self == MqttVersion::V5
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// MQTT over TCP, or TLS with `--ssl`
+    Tcp,
+    /// MQTT over WebSocket, or WebSocket over TLS with `--ssl`
+    Ws,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+pub enum TlsVersion {
+    #[value(name = "1.2")]
+    #[serde(rename = "1.2")]
+    V1_2,
+    #[value(name = "1.3")]
+    #[serde(rename = "1.3")]
+    V1_3,
+}
+
+impl TlsVersion {
+    pub fn as_ssl_version(&self) -> SslVersion {
+        match self {
+            TlsVersion::V1_2 => SslVersion::TLS1_2,
+            TlsVersion::V1_3 => SslVersion::TLS1_3,
+        }
+    }
+}
+
+/// TLS version clients connect with, among those the MQTT client library can pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ClientTlsVersion {
+    #[serde(rename = "default")]
+    Default,
+    #[serde(rename = "1.0")]
+    V1_0,
+    #[serde(rename = "1.1")]
+    V1_1,
+    #[serde(rename = "1.2")]
+    V1_2,
+}
+
+impl ClientTlsVersion {
+    pub fn as_ssl_version(&self) -> mqtt::SslVersion {
+        match self {
+            ClientTlsVersion::Default => mqtt::SslVersion::Default,
+            ClientTlsVersion::V1_0 => mqtt::SslVersion::Tls_1_0,
+            ClientTlsVersion::V1_1 => mqtt::SslVersion::Tls_1_1,
+            ClientTlsVersion::V1_2 => mqtt::SslVersion::Tls_1_2,
         }
-        self.client_id.clone()
     }
 }
 
-#[derive(Debug, Clone, Args)]
+fn parse_client_tls_version(s: &str) -> Result<ClientTlsVersion, String> {
+    match s {
+        "default" => Ok(ClientTlsVersion::Default),
+        "1.0" => Ok(ClientTlsVersion::V1_0),
+        "1.1" => Ok(ClientTlsVersion::V1_1),
+        "1.2" => Ok(ClientTlsVersion::V1_2),
+        "1.3" => Err(
+            "the MQTT client library cannot pin TLS 1.3; `default` negotiates it with servers that \
+             support it"
+                .to_owned(),
+        ),
+        _ => Err(format!(
+            "expected `default`, `1.0`, `1.1` or `1.2`, got `{}`",
+            s
+        )),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SeriesFormat {
+    Csv,
+    /// JSON Lines, one object per line
+    Jsonl,
+}
+
+#[derive(Debug, Clone, Args, Serialize)]
 pub struct PubOptions {
-    /// Topic pattern to publish messages to.
+    /// Topic template to publish messages to.
     ///
-    /// The topic pattern can contain a `%d` placeholder which will be replaced by an ID.
+    /// The template can contain a `%d` or `{index}` placeholder which will be replaced by the
+    /// number of the topic of each client. For example, if the template is `home/%d`, the actual
+    /// topics will be `home/0`, `home/1`, etc.
     ///
-    /// For example, if the topic pattern is `home/%d`, the actual topic will be `home/0`, `home/1`, etc.
+    /// Other placeholders are `{client_id}`, `{group}`, `{uuid}`, `{rand:<a>..<b>}` and `{seq}`,
+    /// the latter two changing with every message, as well as `{level:<n>}` and named levels like
+    /// `{site}`, declared with `--level-fanout`, which spread the topics over a hierarchy.
     #[arg(long, default_value_t = String::from("home/%d"))]
     pub topic: String,
 
-    /// If `topic` contains `%i`, this is the number of topics to publish messages to.
+    /// If `topic` contains `%d`, `{index}` or levels, this is the number of topics to
+    /// publish messages to.
     ///
-    /// If `topic_total` is less than number of the clients: `total`, the topics will be reused;
-    /// If the `topic_total` is greater than the number of clients, only the first `total` topics
-    /// will be used during benchmark;
+    /// Clients are mapped onto the topics by `--topic-mapping`, so if `topic_total` is less than
+    /// the number of clients: `total`, the topics will be shared; If the `topic_total` is greater
+    /// than the number of clients, only some of the topics will be used during the benchmark;
     ///
-    /// If `topic_total` is 0, it will be set to `total`.
+    /// If `topic_total` is 0, it will be set to `total`, or in a `benchmark` with `--publishers` or
+    /// `--subscribers`, to the number of publishers.
     #[arg(long, default_value_t = 0)]
     pub topic_total: usize,
 
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub topic_mapping: TopicMappingOptions,
+
     #[arg(long, default_value_t = 64)]
     pub message_size: u32,
 
     #[arg(long)]
     pub payload: Option<String>,
+
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub payload_options: PayloadOptions,
+
+    /// MQTT 5 user property attached to every message, in the form `key=value`. Repeatable.
+    ///
+    /// Both key and value can contain a `%d` placeholder which will be replaced by the number of
+    /// the client, e.g. `5` for `BenchClient5`, not by its client ID.
+    #[arg(long = "user-property", value_parser = parse_key_value)]
+    pub user_properties: Vec<(String, String)>,
+
+    /// MQTT 5 content type of every message. Can contain a `%d` placeholder.
+    #[arg(long)]
+    pub content_type: Option<String>,
+
+    /// MQTT 5 response topic of every message. Can contain a `%d` placeholder.
+    #[arg(long)]
+    pub response_topic: Option<String>,
+
+    /// MQTT 5 correlation data of every message. Can contain a `%d` placeholder.
+    #[arg(long)]
+    pub correlation_data: Option<String>,
+
+    /// MQTT 5 message expiry interval of every message in seconds.
+    #[arg(long)]
+    pub message_expiry_interval: Option<u32>,
+
+    /// Target rate in messages per second, shared by all publishers. Overrides `--interval`.
+    ///
+    /// Sends are scheduled at fixed times regardless of how long publishing takes, and latencies
+    /// are measured from the scheduled time.
+    #[arg(long, value_parser = parse_rate)]
+    pub rate: Option<f64>,
+
+    /// Target rate changing over time, e.g. `ramp:0->5000/s over 60s, hold 300s, step +1000/s every 60s`.
+    ///
+    /// Like `--rate`, the rate is shared by all publishers and overrides `--interval`. Phases are
+    /// `rate:<r>/s [for <d>]`, `ramp:<from>-><to>/s over <d>`, `hold [<d>]`,
+    /// `step +<r>/s every <d> [x<n>]` and `spike:<r>/s for <d>`.
+    #[arg(long, conflicts_with = "rate")]
+    pub profile: Option<Profile>,
 }
 
 impl PubOptions {
-    pub fn topic_of(&self, id: usize) -> String {
-        if self.topic.contains("%d") {
-            return self.topic.replace("%d", &id.to_string());
+    /// Topics of the clients of `common`.
+    pub fn topics(&self, common: &Common) -> Result<Topics, anyhow::Error> {
+        let clients = common.start_number..common.start_number + common.total;
+        self.topic_mapping
+            .topics(clients, common.start_number, &self.topic, self.topic_total)
+    }
+
+    /// Payloads of the messages, of `--payload-source`.
+    pub fn payloads(&self) -> Result<Payloads, anyhow::Error> {
+        let options = &self.payload_options;
+        let sizes = options.message_size_distribution.clone();
+        ensure!(
+            sizes.is_none()
+                || matches!(
+                    options.payload_source,
+                    PayloadSource::Text | PayloadSource::Random
+                ),
+            "`--message-size-distribution` only applies to text and random payloads"
+        );
+        match options.payload_source {
+            PayloadSource::Text => {
+                let text = self
+                    .payload
+                    .clone()
+                    .unwrap_or_else(|| "a".repeat(self.message_size as usize));
+                Ok(Payloads::text(text.into_bytes(), sizes))
+            }
+            PayloadSource::Random => Ok(Payloads::random(self.message_size as usize, sizes)),
+            PayloadSource::File => {
+                let path = options
+                    .payload_file
+                    .as_ref()
+                    .context("`--payload-source file` requires `--payload-file`")?;
+                Payloads::samples(path)
+            }
+            PayloadSource::Json => {
+                let path = options
+                    .payload_template
+                    .as_ref()
+                    .context("`--payload-source json` requires `--payload-template`")?;
+                Payloads::json(path)
+            }
+        }
+    }
+
+    /// Whether any MQTT 5 message property is configured.
+    pub fn has_properties(&self) -> bool {
+        !self.user_properties.is_empty()
+            || self.content_type.is_some()
+            || self.response_topic.is_some()
+            || self.correlation_data.is_some()
+            || self.message_expiry_interval.is_some()
+    }
+
+    /// Rate profile publishers follow, if either `--rate` or `--profile` is given.
+    pub fn load_profile(&self) -> Option<Profile> {
+        self.profile
+            .clone()
+            .or_else(|| self.rate.map(Profile::constant))
+    }
+
+    /// MQTT 5 properties of messages published by client number `id`, which replaces `%d`.
+    pub fn properties_of(&self, id: usize) -> Result<mqtt::Properties, mqtt::Error> {
+        let id = id.to_string();
+        let expand = |template: &str| template.replace("%d", &id);
+
+        let mut properties = mqtt::Properties::new();
+        for (key, value) in &self.user_properties {
+            properties.push_string_pair(
+                mqtt::PropertyCode::UserProperty,
+                &expand(key),
+                &expand(value),
+            )?;
         }
-        self.topic.clone()
+        if let Some(content_type) = &self.content_type {
+            properties.push_string(mqtt::PropertyCode::ContentType, &expand(content_type))?;
+        }
+        if let Some(response_topic) = &self.response_topic {
+            properties.push_string(mqtt::PropertyCode::ResponseTopic, &expand(response_topic))?;
+        }
+        if let Some(correlation_data) = &self.correlation_data {
+            properties.push_binary(
+                mqtt::PropertyCode::CorrelationData,
+                expand(correlation_data),
+            )?;
+        }
+        if let Some(interval) = self.message_expiry_interval {
+            properties.push_u32(mqtt::PropertyCode::MessageExpiryInterval, interval)?;
+        }
+        Ok(properties)
+    }
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("expected `key=value`, got `{}`", s)),
+    }
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(format!(
+            "expected a positive number of messages per second, got `{}`",
+            s
+        )),
     }
 }
 
-#[derive(Debug, Clone, Args)]
+#[derive(Debug, Clone, Args, Serialize)]
 pub struct SubOptions {
-    #[arg(long)]
-    pub topic: String,
+    /// Topic filter template to subscribe to, with the placeholders of `pub --topic`. Repeatable,
+    /// every client subscribing to all the filters in a single SUBSCRIBE.
+    ///
+    /// Filters can contain the `+` and `#` wildcards. Levels containing `{rand:<a>..<b>}` or
+    /// `{seq}` are subscribed to as `+`.
+    #[arg(long, required = true)]
+    pub topic: Vec<String>,
 
-    /// If `topic` contains `%i`, this is the number of topics to publish messages to.
+    /// If `topic` contains `%d`, `{index}` or levels, this is the number of topics to
+    /// subscribe to.
     ///
-    /// If `topic_total` is less than number of the clients: `total`, the topics will be reused;
-    /// If the `topic_total` is greater than the number of clients,
-    /// only the first `total` topics will be used during the benchmark.
+    /// Clients are mapped onto the topics by `--topic-mapping`, so if `topic_total` is less than
+    /// the number of clients: `total`, the topics will be shared; If the `topic_total` is greater
+    /// than the number of clients, only some of the topics will be used during the benchmark.
     ///
     /// If `topic_total` is 0, it will be set to `total`.
     #[arg(long, default_value_t = 0)]
     pub topic_total: usize,
+
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub topic_mapping: TopicMappingOptions,
+
+    /// Number of shared subscription groups to spread the clients across.
+    ///
+    /// If non-zero, clients subscribe to `$share/<group>/<topic>` and are assigned to the groups
+    /// round-robin by ID. If 0, clients use ordinary subscriptions.
+    #[arg(long, default_value_t = 0)]
+    pub share_group_total: usize,
+
+    /// Shared subscription group name pattern.
+    ///
+    /// The pattern can contain a `%d` placeholder which will be replaced by the group index.
+    #[arg(long, default_value_t = String::from("group%d"))]
+    pub share_group: String,
 }
 
 impl SubOptions {
-    pub fn topic_of(&self, id: usize) -> String {
-        if self.topic.contains("%d") {
-            return self.topic.replace("%d", &id.to_string());
+    /// Topic filters of the clients of `common`, one for each `--topic`.
+    pub fn topics(&self, common: &Common) -> Result<Vec<Topics>, anyhow::Error> {
+        self.topic
+            .iter()
+            .map(|topic| {
+                let clients = common.start_number..common.start_number + common.total;
+                self.topic_mapping
+                    .topics(clients, common.start_number, topic, self.topic_total)
+            })
+            .collect()
+    }
+
+    /// Shared subscription group of the client of the given ID, if shared subscriptions are used.
+    pub fn share_group_of(&self, id: usize) -> Option<String> {
+        if 0 == self.share_group_total {
+            return None;
         }
-        self.topic.clone()
+        let group = id % self.share_group_total;
+        Some(self.share_group.replace("%d", &group.to_string()))
     }
 }
 
-#[derive(Subcommand, Debug)]
+/// Where the payloads of messages come from, and how large they are.
+#[derive(Debug, Clone, Args, Serialize)]
+pub struct PayloadOptions {
+    /// Source of the payloads of messages.
+    ///
+    /// Payloads start with a 32-byte header to measure latencies and losses, written over the
+    /// first bytes of text and random payloads, and followed by sample files and JSON documents.
+    #[arg(long, value_enum, default_value_t = PayloadSource::Text)]
+    pub payload_source: PayloadSource,
+
+    /// Sample file of `--payload-source file`, or directory of sample files, one of which is
+    /// drawn for every message.
+    #[arg(long)]
+    pub payload_file: Option<PathBuf>,
+
+    /// JSON template of `--payload-source json`, whose strings can contain field generators:
+    /// `{int:<a>..<b>}`, `{float:<a>..<b>}`, `{bool}`, `{choice:<a>|<b>}`, `{string:<n>}`,
+    /// `{uuid}`, `{client_id}`, `{seq}` and `{timestamp}`.
+    #[arg(long)]
+    pub payload_template: Option<PathBuf>,
+
+    /// Distribution of the sizes of text and random payloads in bytes, drawn for every message
+    /// instead of `--message-size`: `<size>`, `uniform:<min>..<max>`, `normal:<mean>,<stddev>` or
+    /// `empirical:<size>=<weight>,...`.
+    ///
+    /// Drawn sizes are at least 32 bytes, so that every message carries the header.
+    #[arg(long)]
+    pub message_size_distribution: Option<SizeDistribution>,
+}
+
+/// How clients are mapped onto `--topic-total` topics, and how topic templates are expanded.
+#[derive(Debug, Clone, Args, Serialize)]
+pub struct TopicMappingOptions {
+    /// How clients are mapped onto the topics replacing `%d` or `{index}` in `topic`. Topics are
+    /// numbered from `start_number`, like clients.
+    #[arg(long, value_enum, default_value_t = TopicMapping::Modulo)]
+    pub topic_mapping: TopicMapping,
+
+    /// Seed of `--topic-mapping random` and `zipf`, which map clients the same way for the same
+    /// seed.
+    #[arg(long, default_value_t = 0)]
+    pub topic_seed: u64,
+
+    /// Exponent of `--topic-mapping zipf`; the larger, the hotter the first topics.
+    #[arg(long, default_value_t = 1.0)]
+    pub zipf_exponent: f64,
+
+    /// Number of values of each level of `{level:<n>}` in `topic`.
+    ///
+    /// With as many topics as the product of the fan-outs of the levels, every topic of the
+    /// hierarchy is used once; beyond that, topics repeat.
+    #[arg(long, default_value_t = 10)]
+    pub topic_fanout: usize,
+
+    /// Number of values of a named level of `topic`, in the form `name=fanout`, e.g. `floor=5`
+    /// for `{floor}`. Repeatable; every named level needs one.
+    #[arg(long = "level-fanout", value_parser = parse_fanout)]
+    pub level_fanouts: Vec<(String, usize)>,
+
+    /// Number of consecutive topics in each `{group}` of `topic`.
+    #[arg(long, default_value_t = 10)]
+    pub topic_group_size: usize,
+}
+
+impl TopicMappingOptions {
+    /// Topics of `clients`, expanded from `topic` with topic numbers starting from `first_topic`.
+    fn topics(
+        &self,
+        clients: Range<usize>,
+        first_topic: usize,
+        topic: &str,
+        topic_total: usize,
+    ) -> Result<Topics, anyhow::Error> {
+        let template = Template::parse(topic, &self.level_fanouts, self.topic_fanout)
+            .context(format!("Invalid topic `{}`", topic))?;
+        let mapper = TopicMapper::new(
+            self.topic_mapping,
+            clients.start,
+            clients.len(),
+            topic_total,
+            self.topic_seed,
+            self.zipf_exponent,
+        )
+        .numbered_from(first_topic);
+        Ok(Topics::new(template, mapper, self.topic_group_size))
+    }
+}
+
+fn parse_fanout(s: &str) -> Result<(String, usize), String> {
+    match s.split_once('=') {
+        Some((name, fanout)) if !name.is_empty() => match fanout.parse::<usize>() {
+            Ok(fanout) if fanout > 0 => Ok((name.to_owned(), fanout)),
+            _ => Err(format!("expected a positive fan-out, got `{}`", fanout)),
+        },
+        _ => Err(format!("expected `name=fanout`, got `{}`", s)),
+    }
+}
+
+/// Publishers and subscribers of `benchmark`, when they are separate clients.
+#[derive(Debug, Clone, Args, Serialize)]
+pub struct BenchOptions {
+    /// Number of clients that only publish, the first ones.
+    ///
+    /// If neither `--publishers` nor `--subscribers` is given, every client subscribes to the
+    /// topic it publishes to. If only one is, the other clients of `total` are of the other kind;
+    /// if both are, they set `total`.
+    #[arg(long)]
+    pub publishers: Option<usize>,
+
+    /// Number of clients that only subscribe, the ones after the publishers.
+    #[arg(long)]
+    pub subscribers: Option<usize>,
+
+    /// Topic filter template subscribers subscribe to, with the placeholders of `--topic`.
+    /// Repeatable. Defaults to `--topic`.
+    #[arg(long)]
+    pub sub_topic: Vec<String>,
+
+    /// Number of topics subscribers are mapped onto, numbered like the topics of publishers.
+    ///
+    /// If 0, it will be set to `--topic-total`.
+    #[arg(long, default_value_t = 0)]
+    pub sub_topic_total: usize,
+
+    /// How subscribers are mapped onto their topics. Defaults to `--topic-mapping`.
+    #[arg(long, value_enum)]
+    pub sub_topic_mapping: Option<TopicMapping>,
+}
+
+impl BenchOptions {
+    /// Number of clients, if both `--publishers` and `--subscribers` are given.
+    pub fn total(&self) -> Option<usize> {
+        Some(self.publishers? + self.subscribers?)
+    }
+
+    /// Publishers and subscribers among the clients of `common`, unless every client does both.
+    pub fn populations(&self, common: &Common) -> Result<Option<Populations>, anyhow::Error> {
+        let (publishers, subscribers) = match (self.publishers, self.subscribers) {
+            (None, None) => return Ok(None),
+            (Some(publishers), Some(subscribers)) => (publishers, subscribers),
+            (Some(publishers), None) => (publishers, common.total.saturating_sub(publishers)),
+            (None, Some(subscribers)) => (common.total.saturating_sub(subscribers), subscribers),
+        };
+        ensure!(
+            publishers + subscribers == common.total,
+            "{} publishers and {} subscribers do not add up to --total {}",
+            publishers,
+            subscribers,
+            common.total
+        );
+        ensure!(
+            publishers > 0 && subscribers > 0,
+            "A benchmark needs at least one publisher and one subscriber"
+        );
+        Ok(Some(Populations {
+            start_number: common.start_number,
+            publishers,
+            subscribers,
+        }))
+    }
+
+    /// Topics of the publishers of `populations`.
+    pub fn pub_topics(
+        &self,
+        populations: &Populations,
+        pub_options: &PubOptions,
+    ) -> Result<Topics, anyhow::Error> {
+        pub_options.topic_mapping.topics(
+            populations.publishers(),
+            populations.start_number,
+            &pub_options.topic,
+            pub_options.topic_total,
+        )
+    }
+
+    /// Topic filters of the subscribers of `populations`, one for each `--sub-topic`.
+    pub fn sub_topics(
+        &self,
+        populations: &Populations,
+        pub_options: &PubOptions,
+    ) -> Result<Vec<Topics>, anyhow::Error> {
+        let mut topic_mapping = pub_options.topic_mapping.clone();
+        if let Some(mapping) = self.sub_topic_mapping {
+            topic_mapping.topic_mapping = mapping;
+        }
+        let topic_total = match self.sub_topic_total {
+            0 => pub_options.topic_total,
+            topic_total => topic_total,
+        };
+        let templates = if self.sub_topic.is_empty() {
+            std::slice::from_ref(&pub_options.topic)
+        } else {
+            &self.sub_topic[..]
+        };
+        templates
+            .iter()
+            .map(|topic| {
+                topic_mapping.topics(
+                    populations.subscribers(),
+                    populations.start_number,
+                    topic,
+                    topic_total,
+                )
+            })
+            .collect()
+    }
+}
+
+/// Publishers of a benchmark followed by its subscribers.
+#[derive(Debug, Clone, Copy)]
+pub struct Populations {
+    /// ID of the first publisher
+    pub start_number: usize,
+    pub publishers: usize,
+    pub subscribers: usize,
+}
+
+impl Populations {
+    /// IDs of the publishers.
+    pub fn publishers(&self) -> Range<usize> {
+        self.start_number..self.start_number + self.publishers
+    }
+
+    /// IDs of the subscribers.
+    pub fn subscribers(&self) -> Range<usize> {
+        let first = self.start_number + self.publishers;
+        first..first + self.subscribers
+    }
+
+    pub fn is_publisher(&self, id: usize) -> bool {
+        self.publishers().contains(&id)
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
 pub enum Commands {
     Connect {
         #[command(flatten)]
@@ -175,5 +898,480 @@ pub enum Commands {
 
         #[command(flatten)]
         pub_options: PubOptions,
+
+        #[command(flatten)]
+        bench_options: BenchOptions,
+    },
+
+    /// Run the client groups described by a scenario file concurrently.
+    Run {
+        /// TOML file describing the client groups. YAML is not supported.
+        scenario: PathBuf,
+    },
+
+    /// Run a minimal MQTT broker, e.g. to measure the ceiling of the tool itself.
+    Broker {
+        #[command(flatten)]
+        broker_options: BrokerOptions,
+    },
+
+    /// Manage client certificates.
+    Cert {
+        #[command(subcommand)]
+        command: CertCommands,
+    },
+}
+
+impl Commands {
+    /// Fill in the options whose defaults depend on other options: the `total` of a benchmark
+    /// with both `--publishers` and `--subscribers`, and a `topic_total` of 0, which becomes the
+    /// number of clients publishing or subscribing to the topics.
+    pub fn normalize(&mut self) -> Result<(), anyhow::Error> {
+        match self {
+            Commands::Pub {
+                common,
+                pub_options,
+            } => set_topic_total(&mut pub_options.topic_total, common.total),
+            Commands::Sub {
+                common,
+                sub_options,
+            } => set_topic_total(&mut sub_options.topic_total, common.total),
+            Commands::Benchmark {
+                common,
+                pub_options,
+                bench_options,
+            } => {
+                if let Some(total) = bench_options.total() {
+                    common.total = total;
+                    info!(
+                        "Now that --publishers and --subscribers are given, --total={}",
+                        total
+                    );
+                }
+                // Clients that only subscribe publish to no topic
+                let publishers = match bench_options.populations(common)? {
+                    Some(populations) => populations.publishers,
+                    None => common.total,
+                };
+                set_topic_total(&mut pub_options.topic_total, publishers);
+            }
+            Commands::Connect { .. }
+            | Commands::Run { .. }
+            | Commands::Broker { .. }
+            | Commands::Cert { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+fn set_topic_total(topic_total: &mut usize, clients: usize) {
+    if 0 == *topic_total {
+        *topic_total = clients;
+        info!(
+            "Now that --topic-total is 0, it will be set to --topic-total={}",
+            clients
+        );
+    }
+}
+
+// Parsed once per run, so the size of the variants does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug, Clone)]
+pub enum CertCommands {
+    /// Sign a certificate for every client ahead of a run, to load with `--cert-dir`.
+    Generate {
+        #[command(flatten)]
+        generate_options: GenerateOptions,
+    },
+
+    /// Create a self-signed CA, written to `CA.crt` and `CA.key`.
+    Ca {
+        /// Algorithm of the key of the CA.
+        #[arg(long, value_enum, default_value_t = KeyAlgorithm::Rsa2048)]
+        key_algorithm: KeyAlgorithm,
+
+        /// Directory to write the CA certificate and key to.
+        #[arg(long)]
+        out: PathBuf,
     },
 }
+
+#[derive(Debug, Clone, Args, Serialize)]
+pub struct GenerateOptions {
+    /// Number of client certificates to sign.
+    #[arg(long, default_value_t = 16)]
+    pub total: usize,
+
+    #[arg(short = 'n', long, default_value_t = 0)]
+    pub start_number: usize,
+
+    /// Client ID template, whose expansion is the common name of each certificate.
+    #[arg(long, default_value_t = String::from("BenchClient%d"))]
+    pub client_id: String,
+
+    /// CA certificate to sign the client certificates with.
+    #[arg(long, default_value = "assets/CA.crt")]
+    pub ca_cert: PathBuf,
+
+    /// Private key of the CA certificate.
+    #[arg(long, default_value = "assets/CA.key")]
+    pub ca_key: PathBuf,
+
+    #[command(flatten)]
+    pub cert_options: CertOptions,
+
+    /// Directory to write certificates and keys to. Clients that already have both are skipped.
+    #[arg(long)]
+    pub out: PathBuf,
+}
+
+impl GenerateOptions {
+    /// IDs and client IDs of the clients to sign certificates for.
+    pub fn clients(&self) -> Vec<(usize, String)> {
+        (self.start_number..self.start_number + self.total)
+            .map(|id| (id, expand_id(&self.client_id, id)))
+            .collect()
+    }
+}
+
+/// Contents of the client certificates signed by a CA.
+#[derive(Debug, Clone, Args, Serialize)]
+pub struct CertOptions {
+    /// Algorithm of the keys of client certificates.
+    #[arg(long, value_enum, default_value_t = KeyAlgorithm::Rsa2048)]
+    pub key_algorithm: KeyAlgorithm,
+
+    /// Subject field of client certificates as `field=value`, e.g. `O=Acme`, replacing the default
+    /// value of the field; an empty value removes it. Can contain a `%d` placeholder. Can be
+    /// repeated.
+    #[arg(long = "cert-subject", value_parser = parse_key_value)]
+    pub subject: Vec<(String, String)>,
+
+    /// DNS name in the subject alternative names of client certificates. Can contain a `%d`
+    /// placeholder. Can be repeated.
+    #[arg(long = "cert-dns")]
+    pub dns: Vec<String>,
+
+    /// IP address in the subject alternative names of client certificates. Can be repeated.
+    #[arg(long = "cert-ip")]
+    pub ip: Vec<IpAddr>,
+
+    /// URI in the subject alternative names of client certificates. Can contain a `%d`
+    /// placeholder. Can be repeated.
+    #[arg(long = "cert-uri")]
+    pub uri: Vec<String>,
+
+    /// Start of the validity of client certificates in days from now. Negative values are in the
+    /// past, positive values make certificates that are not valid yet.
+    #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+    pub cert_not_before: i64,
+
+    /// End of the validity of client certificates in days from now. Negative values make expired
+    /// certificates.
+    #[arg(long, default_value_t = 365, allow_negative_numbers = true)]
+    pub cert_not_after: i64,
+
+    /// Extended key usage of client certificates, e.g. `clientAuth`; none by default. Can be
+    /// repeated.
+    #[arg(long = "cert-eku")]
+    pub extended_key_usages: Vec<String>,
+}
+
+impl CertOptions {
+    /// Template of client certificates, whose common name is set for every client.
+    pub fn template(&self) -> CertBuilder {
+        let mut builder = CertBuilder::new("")
+            .key_algorithm(self.key_algorithm)
+            .not_before(days_from_now(self.cert_not_before))
+            .not_after(days_from_now(self.cert_not_after));
+        for (field, value) in &self.subject {
+            builder = builder.subject(field, value);
+        }
+        if !self.dns.is_empty() || !self.ip.is_empty() || !self.uri.is_empty() {
+            let alt_names = self
+                .dns
+                .iter()
+                .map(|name| AltName::Dns(name.clone()))
+                .chain(self.ip.iter().map(|ip| AltName::Ip(*ip)))
+                .chain(self.uri.iter().map(|uri| AltName::Uri(uri.clone())))
+                .collect();
+            builder = builder.alt_names(alt_names);
+        }
+        for usage in &self.extended_key_usages {
+            builder = builder.extended_key_usage(usage);
+        }
+        builder
+    }
+}
+
+fn days_from_now(days: i64) -> SystemTime {
+    let offset = Duration::from_secs(days.unsigned_abs() * 24 * 60 * 60);
+    if days < 0 {
+        SystemTime::now() - offset
+    } else {
+        SystemTime::now() + offset
+    }
+}
+
+/// Passwords signed by clients for every connection attempt.
+#[derive(Debug, Clone, Args, Serialize)]
+pub struct AuthOptions {
+    /// How the password presented by each client is derived from its configured password, which
+    /// is the device secret of signed passwords.
+    #[arg(long, value_enum, default_value_t = AuthMethod::Plain)]
+    pub auth: AuthMethod,
+
+    /// PEM private key to sign JSON Web Tokens with, for `--auth jwt-rs256`.
+    #[arg(long, required_if_eq("auth", "jwt-rs256"))]
+    pub jwt_key: Option<PathBuf>,
+
+    /// Audience claim of JSON Web Tokens, e.g. the project ID of the IoT platform.
+    #[arg(long)]
+    pub jwt_audience: Option<String>,
+
+    /// Validity of JSON Web Tokens in seconds.
+    #[arg(long, default_value_t = 3600)]
+    pub jwt_ttl: u64,
+
+    /// Content signed by `--auth hmac-sha256`, in which `{client_id}`, `{username}`,
+    /// `{timestamp}` and `{timestamp_ms}` are expanded, the timestamps being the current UNIX
+    /// time in seconds and milliseconds.
+    #[arg(long, default_value_t = String::from("{client_id}{timestamp}"))]
+    pub hmac_content: String,
+
+    /// Encoding of the signature of `--auth hmac-sha256`.
+    #[arg(long, value_enum, default_value_t = SignatureEncoding::Hex)]
+    pub signature_encoding: SignatureEncoding,
+}
+
+impl AuthOptions {
+    /// Generator of the passwords of clients, shared by all of them.
+    pub fn generator(&self) -> Result<Arc<dyn PasswordGenerator>, anyhow::Error> {
+        let ttl = Duration::from_secs(self.jwt_ttl);
+        let generator: Arc<dyn PasswordGenerator> = match self.auth {
+            AuthMethod::Plain => Arc::new(Plain),
+            AuthMethod::JwtHs256 => {
+                Arc::new(Jwt::new(JwtKey::Hs256, self.jwt_audience.clone(), ttl)?)
+            }
+            AuthMethod::JwtRs256 => {
+                let Some(path) = &self.jwt_key else {
+                    bail!("--auth jwt-rs256 needs --jwt-key");
+                };
+                let key = load_pkey(path)?;
+                Arc::new(Jwt::new(
+                    JwtKey::Rs256(key),
+                    self.jwt_audience.clone(),
+                    ttl,
+                )?)
+            }
+            AuthMethod::HmacSha256 => Arc::new(Hmac::new(
+                self.hmac_content.clone(),
+                self.signature_encoding,
+            )),
+        };
+        Ok(generator)
+    }
+}
+
+#[derive(Debug, Clone, Args, Serialize)]
+pub struct BrokerOptions {
+    /// Address to accept plain TCP connections on.
+    #[arg(long, default_value = "0.0.0.0:1883")]
+    pub listen: SocketAddr,
+
+    /// Address to accept TLS connections on, if any.
+    #[arg(long)]
+    pub tls_listen: Option<SocketAddr>,
+
+    /// Address to accept MQTT over WebSocket on, if any.
+    #[arg(long)]
+    pub ws_listen: Option<SocketAddr>,
+
+    /// Address to accept MQTT over WebSocket over TLS on, if any.
+    #[arg(long)]
+    pub wss_listen: Option<SocketAddr>,
+
+    /// CA certificate that signs the certificate of the TLS listener.
+    #[arg(long, default_value = "assets/CA.crt")]
+    pub tls_ca_cert: PathBuf,
+
+    /// Private key of the CA certificate.
+    #[arg(long, default_value = "assets/CA.key")]
+    pub tls_ca_key: PathBuf,
+
+    /// Algorithm of the key of the certificate of the TLS listener.
+    #[arg(long, value_enum, default_value_t = KeyAlgorithm::Rsa2048)]
+    pub tls_key_algorithm: KeyAlgorithm,
+
+    /// Require clients to present a certificate signed by the CA.
+    #[arg(long)]
+    pub tls_verify_client: bool,
+
+    /// Lowest TLS version the TLS listener accepts, e.g. `1.3` to act like a TLS 1.3-only load
+    /// balancer.
+    #[arg(long, value_enum, default_value_t = TlsVersion::V1_2)]
+    pub tls_min_version: TlsVersion,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CertCommands, Cli, ClientTlsVersion, Commands};
+    use clap::Parser;
+    use paho_mqtt as mqtt;
+
+    #[test]
+    fn test_properties_of() -> anyhow::Result<()> {
+        let cli = Cli::try_parse_from([
+            "mqtt-bench",
+            "pub",
+            "--host",
+            "localhost",
+            "-u",
+            "user",
+            "-P",
+            "secret",
+            "--user-property",
+            "device=dev-%d",
+            "--content-type",
+            "application/json",
+            "--message-expiry-interval",
+            "30",
+        ])?;
+        let Some(Commands::Pub { pub_options, .. }) = cli.command else {
+            panic!("Expected pub subcommand");
+        };
+        assert!(pub_options.has_properties());
+
+        let properties = pub_options.properties_of(7)?;
+        let user_property = properties
+            .get(mqtt::PropertyCode::UserProperty)
+            .and_then(|p| p.get_string_pair());
+        assert_eq!(
+            Some(("device".to_owned(), "dev-7".to_owned())),
+            user_property
+        );
+        assert_eq!(
+            Some("application/json".to_owned()),
+            properties.get_val::<String>(mqtt::PropertyCode::ContentType)
+        );
+        assert_eq!(
+            Some(30),
+            properties.get_val::<u32>(mqtt::PropertyCode::MessageExpiryInterval)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cert_template() -> anyhow::Result<()> {
+        let cli = Cli::try_parse_from([
+            "mqtt-bench",
+            "cert",
+            "generate",
+            "--ca-cert",
+            "CA.crt",
+            "--ca-key",
+            "CA.key",
+            "--cert-subject",
+            "O=Acme",
+            "--cert-uri",
+            "urn:device:%d",
+            "--cert-not-before",
+            "-2",
+            "--cert-not-after",
+            "-1",
+            "--cert-eku",
+            "clientAuth",
+            "--out",
+            "certs",
+        ])?;
+        let Some(Commands::Cert {
+            command: CertCommands::Generate { generate_options },
+        }) = cli.command
+        else {
+            panic!("Expected cert generate subcommand");
+        };
+        let cert_options = &generate_options.cert_options;
+        assert_eq!(
+            vec![("O".to_owned(), "Acme".to_owned())],
+            cert_options.subject
+        );
+        assert_eq!(vec!["urn:device:%d".to_owned()], cert_options.uri);
+        assert_eq!(-2, cert_options.cert_not_before);
+        assert_eq!(-1, cert_options.cert_not_after);
+        assert_eq!(
+            vec!["clientAuth".to_owned()],
+            cert_options.extended_key_usages
+        );
+        assert!(super::days_from_now(-1) < std::time::SystemTime::now());
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize() -> anyhow::Result<()> {
+        let topic_totals = [
+            (&["pub", "--total", "3"][..], 3, 3),
+            (&["sub", "--total", "3", "--topic-total", "2"][..], 3, 2),
+            (&["benchmark", "--total", "3"][..], 3, 3),
+            (
+                &["benchmark", "--publishers", "2", "--subscribers", "6"][..],
+                8,
+                2,
+            ),
+            (
+                &["benchmark", "--total", "5", "--subscribers", "1"][..],
+                5,
+                4,
+            ),
+        ];
+        for (args, total, topic_total) in topic_totals {
+            let mut command_line = vec!["mqtt-bench"];
+            command_line.extend_from_slice(args);
+            command_line.extend(["--host", "localhost", "-u", "user", "-P", "secret"]);
+            let mut command = Cli::try_parse_from(command_line)?.command.unwrap();
+            command.normalize()?;
+            let (common, actual) = match &command {
+                Commands::Pub {
+                    common,
+                    pub_options,
+                }
+                | Commands::Benchmark {
+                    common,
+                    pub_options,
+                    ..
+                } => (common, pub_options.topic_total),
+                Commands::Sub {
+                    common,
+                    sub_options,
+                } => (common, sub_options.topic_total),
+                _ => unreachable!(),
+            };
+            assert_eq!((total, topic_total), (common.total, actual), "{:?}", args);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_key_value() {
+        assert_eq!(
+            Ok(("a".to_owned(), "b=c".to_owned())),
+            super::parse_key_value("a=b=c")
+        );
+        assert!(super::parse_key_value("=b").is_err());
+        assert!(super::parse_key_value("ab").is_err());
+    }
+
+    #[test]
+    fn test_parse_client_tls_version() {
+        assert_eq!(
+            Ok(ClientTlsVersion::V1_2),
+            super::parse_client_tls_version("1.2")
+        );
+        assert_eq!(
+            Ok(ClientTlsVersion::Default),
+            super::parse_client_tls_version("default")
+        );
+        let error = super::parse_client_tls_version("1.3").unwrap_err();
+        assert!(error.contains("cannot pin TLS 1.3"), "{}", error);
+        assert!(super::parse_client_tls_version("1.4").is_err());
+    }
+}
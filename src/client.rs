@@ -1,79 +1,119 @@
-use super::cli::Common;
-use crate::state::State;
-use crate::statistics::LatencyHistogram;
+use super::cli::{Common, Transport};
+use crate::auth::{Login, PasswordGenerator};
+use crate::cert::{Identity, Issuer};
+use crate::credentials::Credentials;
+use crate::header::Header;
+use crate::state::{Ack, State};
+use crate::statistics::{LatencyHistogram, LatencyRecorder};
 use crate::subscription::Subscription;
 use anyhow::Context;
-use byteorder::ReadBytesExt;
-use bytes::Buf;
 use log::{debug, error, trace};
 use mqtt::AsyncClient;
 use paho_mqtt as mqtt;
-use std::io::Cursor;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, OnceLock};
 use std::time::{Duration, SystemTime};
+use tokio::runtime::Handle;
 use tokio::time::Instant;
 
 pub struct Client {
-    opts: Common,
-    subscription: OnceLock<Subscription>,
+    opts: Arc<Common>,
+    connector: Connector,
+    /// Certificate presented to the server, if clients authenticate with certificates
+    identity: Option<Identity>,
+    /// Filters the client subscribes to once connected, if any
+    subscription: Arc<OnceLock<Subscription>>,
     pub inner: AsyncClient,
-    latency: LatencyHistogram,
+    latency: LatencyRecorder,
     state: Arc<State>,
 }
 
 impl Client {
     pub fn new(
         opts: Common,
-        client_id: String,
+        id: usize,
+        issuer: Option<&Issuer>,
+        credentials: Option<&Credentials>,
+        generator: Arc<dyn PasswordGenerator>,
         latency: LatencyHistogram,
         state: Arc<State>,
     ) -> Result<Self, anyhow::Error> {
-        let server_uri = if opts.ssl {
-            format!("ssl://{}:{}", opts.host, opts.port.unwrap_or(8883))
+        let credential = opts.credential_of(id, credentials);
+        let client_id = credential.client_id;
+        let server_uri = opts.connection_string();
+
+        let create_opts_builder = if opts.mqtt_version.is_v5() {
+            mqtt::CreateOptionsBuilder::new()
         } else {
-            format!("tcp://{}:{}", opts.host, opts.port.unwrap_or(1883))
+            mqtt::CreateOptionsBuilder::new_v3()
         };
-
-        let create_opts = mqtt::CreateOptionsBuilder::new_v3()
-            .client_id(client_id)
+        let create_opts = create_opts_builder
+            .client_id(&client_id)
             .server_uri(server_uri)
-            .mqtt_version(mqtt::MQTT_VERSION_3_1_1)
+            .mqtt_version(opts.mqtt_version.as_u32())
             .persistence(mqtt::PersistenceType::None)
             .send_while_disconnected(false)
             .allow_disconnected_send_at_anytime(false)
             .finalize();
 
+        // Provisioned certificates take precedence over those of the issuer
+        let identity = match credential.cert {
+            Some((cert, key)) => Some(Identity::provisioned(cert, key)),
+            None => issuer
+                .map(|issuer| issuer.issue(id, &client_id))
+                .transpose()?,
+        };
         let client = AsyncClient::new(create_opts).context("Failed to create MQTT AsyncClient")?;
-        let e2e_histogram = latency.subscribe.clone();
+        let latency = latency.recorder();
+        let e2e_latency = latency.clone();
         let _state = Arc::clone(&state);
+        let received = state.register_receiver(&client_id);
+        let sequences = state.register_tracker();
+        let subscription = Arc::new(OnceLock::<Subscription>::new());
+        let filters = Arc::clone(&subscription);
         client.set_message_callback(move |_client, message| {
             if let Some(message) = message {
                 _state.on_receive();
-                let payload = message.payload();
-                let mut cursor = Cursor::new(payload);
-                if cursor.remaining() > std::mem::size_of::<u128>() {
-                    match cursor.read_u128::<byteorder::LittleEndian>() {
-                        Ok(ts) => {
-                            let now = SystemTime::now()
-                                .duration_since(SystemTime::UNIX_EPOCH)
-                                .unwrap()
-                                .as_millis();
-                            if now >= ts {
-                                e2e_histogram.observe((now - ts) as f64);
-                            }
-                        }
-                        Err(e) => {
-                            error!("Failed to read timestamp from payload: {}", e);
-                        }
+                received.fetch_add(1, Ordering::Relaxed);
+                if let Some(filters) = filters.get() {
+                    if !filters.matches(message.topic()) {
+                        _state.on_unmatched();
+                    }
+                }
+                if let Some(header) = Header::read_from(message.payload()) {
+                    let now = SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap()
+                        .as_micros();
+                    if now >= header.timestamp {
+                        e2e_latency
+                            .subscribe(Duration::from_micros((now - header.timestamp) as u64));
+                    }
+                    if header.sequence != Header::UNTRACKED {
+                        _state.on_delivery(sequences.lock().unwrap().on_message(
+                            message.topic(),
+                            header.publisher,
+                            header.sequence,
+                        ));
                     }
                 }
                 trace!("Received message, topic={}", message.topic());
             }
         });
 
+        let opts = Arc::new(opts);
+        let connector = Connector {
+            opts: Arc::clone(&opts),
+            client_id,
+            username: credential.username,
+            secret: credential.password,
+            generator,
+        };
         Ok(Self {
             opts,
-            subscription: OnceLock::new(),
+            connector,
+            identity,
+            subscription,
             inner: client,
             latency,
             state,
@@ -84,26 +124,47 @@ impl Client {
         self.inner.client_id()
     }
 
+    /// TLS options, presenting the certificate of the client if it has one.
+    fn ssl_options(&self) -> Result<mqtt::SslOptions, anyhow::Error> {
+        let mut builder = mqtt::SslOptionsBuilder::new();
+        builder
+            .verify(self.opts.verify)
+            .enable_server_cert_auth(self.opts.auth_server_certificate)
+            .ssl_version(self.opts.tls_version.as_ssl_version());
+        if let Some(ca_bundle) = &self.opts.ca_bundle {
+            builder.trust_store(ca_bundle)?;
+        }
+        if let Some(ca_path) = &self.opts.ca_path {
+            builder.ca_path(ca_path)?;
+        }
+        if let Some(ciphers) = &self.opts.ciphers {
+            builder.enabled_cipher_suites(ciphers.as_str());
+        }
+        if !self.opts.alpn.is_empty() {
+            let protos = self
+                .opts
+                .alpn
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            builder.alpn_protos(&protos);
+        }
+        if let Some(identity) = &self.identity {
+            builder
+                .key_store(&identity.cert)?
+                .private_key(&identity.key)?;
+        }
+        Ok(builder.finalize())
+    }
+
     pub async fn connect(&self) -> Result<(), anyhow::Error> {
-        let connect_opts = mqtt::ConnectOptionsBuilder::new_v3()
-            .clean_session(true)
-            .user_name(&self.opts.username)
-            .password(&self.opts.password)
-            .connect_timeout(Duration::from_secs(self.opts.connect_timeout))
-            .keep_alive_interval(Duration::from_secs(self.opts.keep_alive_interval))
-            .max_inflight(self.opts.max_inflight)
-            .automatic_reconnect(Duration::from_millis(100), Duration::from_secs(3))
-            .ssl_options(
-                mqtt::SslOptionsBuilder::new()
-                    .verify(self.opts.verify)
-                    .enable_server_cert_auth(self.opts.auth_server_certificate)
-                    .ssl_version(mqtt::SslVersion::Tls_1_2)
-                    .finalize(),
-            )
-            .finalize();
+        let ssl_options = self.ssl_options()?;
+        let connect_opts = self.connector.options(ssl_options.clone()).await?;
 
         let connected_state = Arc::clone(&self.state);
         let sub = self.subscription.get().cloned();
+        let runtime = Handle::current();
+        let reconnect_runtime = runtime.clone();
         self.inner.set_connected_callback(move |cli| {
             debug!(
                 "Client[client-id={}] connected to server_uri={}",
@@ -112,17 +173,51 @@ impl Client {
             );
             connected_state.on_connected();
             if let Some(subscription) = &sub {
-                cli.subscribe(&subscription.topic_filter, subscription.qos);
+                let filters = subscription.filters();
+                let qos = vec![subscription.qos; filters.len()];
+                let token = cli.subscribe_many(&filters, &qos);
+                let client_id = cli.client_id();
+                let suback_state = Arc::clone(&connected_state);
+                runtime.spawn(async move {
+                    match token.await {
+                        Ok(response) => {
+                            // Granted QoS for MQTT 3.x, reason codes for MQTT 5, one per filter
+                            let codes = response
+                                .subscribe_many_response()
+                                .unwrap_or_else(|| vec![response.reason_code() as i32]);
+                            for code in codes {
+                                suback_state.on_reason_code(Ack::SubAck, code);
+                            }
+                        }
+                        Err(e) => {
+                            error!("Client[client-id={}] failed to subscribe: {}", client_id, e);
+                            for _ in &filters {
+                                suback_state.on_reason_code(Ack::SubAck, return_code(&e));
+                            }
+                        }
+                    }
+                });
             }
         });
 
         let state_ = Arc::clone(&self.state);
+        let connector = self.connector.clone();
         self.inner.set_connection_lost_callback(move |c| {
             debug!(
                 "Client[client-id={}] lost connection, reconnecting...",
                 c.client_id()
             );
-            c.reconnect();
+            if connector.generator.is_static() {
+                c.reconnect();
+            } else {
+                let connector = connector.clone();
+                let client = c.clone();
+                let ssl_options = ssl_options.clone();
+                let state = Arc::clone(&state_);
+                reconnect_runtime.spawn(async move {
+                    connector.reconnect(&client, ssl_options, &state).await;
+                });
+            }
             state_.on_disconnected();
         });
 
@@ -131,14 +226,21 @@ impl Client {
         }
 
         let instant = Instant::now();
-        self.inner
-            .connect(connect_opts)
-            .await
-            .context("Failed to connect to the MQTT server")?;
+        match self.inner.connect(connect_opts).await {
+            Ok(response) => {
+                self.state
+                    .on_reason_code(Ack::ConnAck, response.reason_code() as i32);
+            }
+            Err(e) => {
+                self.state.on_reason_code(Ack::ConnAck, return_code(&e));
+                return Err(e).context("Failed to connect to the MQTT server");
+            }
+        }
 
-        self.latency
-            .connect
-            .observe(instant.elapsed().as_millis() as f64);
+        match self.opts.transport {
+            Transport::Tcp => self.latency.connect(instant.elapsed()),
+            Transport::Ws => self.latency.ws_connect(instant.elapsed()),
+        }
         Ok(())
     }
 
@@ -147,30 +249,152 @@ impl Client {
     }
 
     pub async fn publish(&self, message: mqtt::Message) -> Result<(), anyhow::Error> {
+        self.publish_at(message, Instant::now()).await
+    }
+
+    /// Publish a message that was meant to be sent at `intended`, which is where its latency is
+    /// measured from.
+    pub async fn publish_at(
+        &self,
+        message: mqtt::Message,
+        intended: Instant,
+    ) -> Result<(), anyhow::Error> {
         let topic = message.topic().to_owned();
-        let instant = Instant::now();
-        if let Err(e) = self
-            .inner
-            .publish(message)
-            .await
-            .context("Failed to publish message")
-        {
+        if let Err(e) = self.inner.publish(message).await {
             self.state.on_publish_failure();
-            return Err(e);
+            self.state.on_reason_code(Ack::PubAck, return_code(&e));
+            return Err(e).context("Failed to publish message");
         }
 
-        self.latency
-            .publish
-            .observe(instant.elapsed().as_millis() as f64);
+        self.latency.publish(intended.elapsed());
         self.state.on_publish();
         trace!("{} published a message to {}", self.client_id(), topic);
         Ok(())
     }
 
-    pub fn subscribe(&self, topic: &str, qos: i32) {
-        let subscription = Subscription::new(topic.to_owned(), qos);
+    /// Subscribe to `topic_filters` once connected.
+    pub fn subscribe(&self, topic_filters: Vec<String>, qos: i32) {
+        let subscription = Subscription::new(topic_filters, qos);
         self.subscription.get_or_init(|| subscription);
     }
+
+    /// Subscribe to `topic_filters` as a member of the shared subscription `group`.
+    pub fn subscribe_shared(&self, group: &str, topic_filters: Vec<String>, qos: i32) {
+        let subscription = Subscription::shared(group.to_owned(), topic_filters, qos);
+        self.subscription.get_or_init(|| subscription);
+    }
+}
+
+/// Builds the CONNECT options of a client, generating its password for every connection attempt.
+#[derive(Clone)]
+struct Connector {
+    opts: Arc<Common>,
+    client_id: String,
+    username: String,
+    /// Configured password, from which the password presented is generated
+    secret: String,
+    generator: Arc<dyn PasswordGenerator>,
+}
+
+impl Connector {
+    /// Start CONNECT options for the configured protocol version with a clean session.
+    fn builder(&self) -> mqtt::ConnectOptionsBuilder {
+        if self.opts.mqtt_version.is_v5() {
+            let mut builder = mqtt::ConnectOptionsBuilder::new_v5();
+            builder.clean_start(true);
+            builder
+        } else {
+            let mut builder =
+                mqtt::ConnectOptionsBuilder::with_mqtt_version(self.opts.mqtt_version.as_u32());
+            builder.clean_session(true);
+            builder
+        }
+    }
+
+    fn login(&self) -> Login<'_> {
+        Login {
+            client_id: &self.client_id,
+            username: &self.username,
+            secret: &self.secret,
+        }
+    }
+
+    /// Password for the next connection attempt.
+    ///
+    /// Signed passwords are generated on the blocking pool: RS256 signatures in particular would
+    /// otherwise stall the runtime while thousands of clients connect.
+    async fn password(&self) -> Result<String, anyhow::Error> {
+        if self.generator.is_static() {
+            return self.generator.password(&self.login());
+        }
+        let connector = self.clone();
+        tokio::task::spawn_blocking(move || connector.generator.password(&connector.login()))
+            .await
+            .context("Password generation panicked")?
+    }
+
+    /// CONNECT options with a freshly generated password.
+    async fn options(
+        &self,
+        ssl_options: mqtt::SslOptions,
+    ) -> Result<mqtt::ConnectOptions, anyhow::Error> {
+        let password = self
+            .password()
+            .await
+            .context("Failed to generate password")?;
+        let mut builder = self.builder();
+        builder
+            .user_name(&self.username)
+            .password(password)
+            .connect_timeout(Duration::from_secs(self.opts.connect_timeout))
+            .keep_alive_interval(Duration::from_secs(self.opts.keep_alive_interval))
+            .max_inflight(self.opts.max_inflight)
+            .ssl_options(ssl_options);
+        // Automatic reconnects would present the first password again
+        if self.generator.is_static() {
+            builder.automatic_reconnect(Duration::from_millis(100), Duration::from_secs(3));
+        }
+        if !self.opts.ws_headers.is_empty() {
+            builder.http_headers(&self.opts.ws_headers);
+        }
+        Ok(builder.finalize())
+    }
+
+    /// Reconnect with a fresh password, retrying like automatic reconnects do until connected or
+    /// the run stops.
+    async fn reconnect(&self, client: &AsyncClient, ssl_options: mqtt::SslOptions, state: &State) {
+        let mut retry_interval = Duration::from_millis(100);
+        while !state.stopped() {
+            match self.options(ssl_options.clone()).await {
+                Ok(options) => {
+                    if client.connect(options).await.is_ok() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    error!("Client[client-id={}] {:#}", self.client_id, e);
+                    return;
+                }
+            }
+            tokio::time::sleep(retry_interval).await;
+            retry_interval = (retry_interval * 2).min(Duration::from_secs(3));
+        }
+    }
+}
+
+/// Paho's `MQTTASYNC_FAILURE`, used when an error carries no code of its own.
+const GENERAL_FAILURE: i32 = -1;
+
+/// Extract the return code carried by an MQTT error.
+///
+/// For failed acknowledgements this is the reason code sent by the server; errors raised locally
+/// by the Paho client map to its negative error codes.
+fn return_code(e: &mqtt::Error) -> i32 {
+    match e {
+        mqtt::Error::ReasonCode(reason) => *reason as i32,
+        mqtt::Error::Paho(rc) | mqtt::Error::PahoDescr(rc, _) | mqtt::Error::Publish(rc, _) => *rc,
+        _ => GENERAL_FAILURE,
+    }
 }
 
 impl Drop for Client {
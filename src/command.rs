@@ -1,21 +1,53 @@
-use crate::cli::{Common, PubOptions, SubOptions};
+use crate::cli::{BenchOptions, Commands, Common, PubOptions, SubOptions};
+use crate::credentials::Credentials;
+use crate::header::Header;
+use crate::payload::PayloadGenerator;
+use crate::report::Report;
+use crate::scenario::Scenario;
+use crate::schedule::{system_time_of, Schedule};
+use crate::series;
 use crate::state::State;
-use crate::statistics::Statistics;
-use anyhow::Context;
-use byteorder::WriteBytesExt;
+use crate::statistics::{Distribution, LatencyHistogram, Statistics};
+use crate::topic::{is_valid_filter, Audience, Reach, TopicCursor};
+use anyhow::{bail, Context};
 use log::{debug, error, info, trace, warn};
-use paho_mqtt::MessageBuilder;
+use paho_mqtt::{MessageBuilder, Properties};
 use ratelimit::Ratelimiter;
-use std::io::Cursor;
-use std::mem::size_of;
+use std::collections::BTreeMap;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::task::JoinSet;
 
 pub async fn connect(
     common: &Common,
     state: &Arc<State>,
     statistics: &Statistics,
 ) -> Result<(), anyhow::Error> {
+    launch_connectors(common, state, &statistics.latency).await?;
+
+    await_connection(common.total, state).await;
+    await_running(common, state).await;
+
+    if common.show_statistics {
+        statistics.show_statistics();
+        state.show_reason_codes(common.mqtt_version);
+    }
+
+    if let Some(path) = &common.output {
+        Report::new("connect", common, state, statistics).write(path, common.format)?;
+    }
+    write_series(common, state)?;
+    Ok(())
+}
+
+async fn launch_connectors(
+    common: &Common,
+    state: &Arc<State>,
+    latency: &LatencyHistogram,
+) -> Result<(), anyhow::Error> {
+    let issuer = common.issuer()?;
+    let credentials = common.credentials()?;
+    let generator = common.auth_options.generator()?;
     let rate_limiter = Ratelimiter::builder(1, Duration::from_millis(common.interval))
         .max_tokens(common.concurrency as u64)
         .build()?;
@@ -33,8 +65,11 @@ pub async fn connect(
         }
         let client = match crate::client::Client::new(
             common.clone(),
-            common.client_id_of(id),
-            statistics.latency.clone(),
+            id,
+            issuer.as_ref(),
+            credentials.as_ref(),
+            Arc::clone(&generator),
+            latency.clone(),
             Arc::clone(state),
         )
         .context(format!("Failed to create MQTT client client_{}", id))
@@ -72,13 +107,66 @@ pub async fn connect(
                 }
             });
     }
+    Ok(())
+}
+
+/// Run all groups of the scenario concurrently, sharing `state` and `statistics`.
+pub async fn run(
+    scenario: &Scenario,
+    state: &Arc<State>,
+    statistics: &Statistics,
+) -> Result<(), anyhow::Error> {
+    let mut launches = JoinSet::new();
+    for group in &scenario.groups {
+        info!(
+            "Launching group {} of {} clients",
+            group.name,
+            group.common().total
+        );
+        let group = group.clone();
+        let state = Arc::clone(state);
+        let latency = statistics.latency.clone();
+        launches.spawn(async move {
+            match &group.command {
+                Commands::Connect { common } => launch_connectors(common, &state, &latency).await,
+                Commands::Pub {
+                    common,
+                    pub_options,
+                } => launch_publishers(common, &state, &latency, pub_options).await,
+                Commands::Sub {
+                    common,
+                    sub_options,
+                } => launch_subscribers(common, &state, &latency, sub_options).await,
+                Commands::Benchmark {
+                    common,
+                    pub_options,
+                    bench_options,
+                } => launch_benchmark(common, &state, &latency, pub_options, bench_options).await,
+                Commands::Run { .. } | Commands::Broker { .. } | Commands::Cert { .. } => {
+                    unreachable!("Scenario groups only run clients")
+                }
+            }
+            .context(format!("Failed to launch group {}", group.name))
+        });
+    }
+    while let Some(launched) = launches.join_next().await {
+        launched??;
+    }
 
-    await_connection(common.total, state).await;
+    let common = &scenario.common;
+    await_connection(scenario.total(), state).await;
     await_running(common, state).await;
 
     if common.show_statistics {
         statistics.show_statistics();
+        state.show_reason_codes(common.mqtt_version);
+        state.show_delivery();
+    }
+
+    if let Some(path) = &common.output {
+        Report::new("run", common, state, statistics).write(path, common.format)?;
     }
+    write_series(common, state)?;
     Ok(())
 }
 
@@ -107,6 +195,41 @@ pub async fn publish(
     statistics: &Statistics,
     pub_options: &PubOptions,
 ) -> Result<(), anyhow::Error> {
+    launch_publishers(common, state, &statistics.latency, pub_options).await?;
+
+    await_connection(common.total, state).await;
+    await_running(common, state).await;
+
+    if common.show_statistics {
+        statistics.show_statistics();
+        state.show_reason_codes(common.mqtt_version);
+    }
+
+    if let Some(path) = &common.output {
+        Report::new("pub", common, state, statistics)
+            .pub_options(pub_options)
+            .write(path, common.format)?;
+    }
+    write_series(common, state)?;
+    Ok(())
+}
+
+async fn launch_publishers(
+    common: &Common,
+    state: &Arc<State>,
+    latency: &LatencyHistogram,
+    pub_options: &PubOptions,
+) -> Result<(), anyhow::Error> {
+    warn_ignored_properties(common, pub_options);
+    let profile = pub_options.load_profile().map(Arc::new);
+    if let Some(profile) = &profile {
+        state.add_profile(Arc::clone(profile));
+    }
+    let issuer = common.issuer()?;
+    let credentials = common.credentials()?;
+    let generator = common.auth_options.generator()?;
+    let topics = Arc::new(pub_options.topics(common)?);
+    let payloads = Arc::new(pub_options.payloads()?);
     let rate_limiter = Ratelimiter::builder(1, Duration::from_millis(common.interval))
         .max_tokens(common.concurrency as u64)
         .build()?;
@@ -124,8 +247,11 @@ pub async fn publish(
         }
         let client = match crate::client::Client::new(
             common.clone(),
-            common.client_id_of(id),
-            statistics.latency.clone(),
+            id,
+            issuer.as_ref(),
+            credentials.as_ref(),
+            Arc::clone(&generator),
+            latency.clone(),
             Arc::clone(state),
         )
         .context(format!("Failed to create MQTT client client_{}", id))
@@ -136,14 +262,19 @@ pub async fn publish(
                 break;
             }
         };
-        let payload = pub_options
-            .payload
-            .clone()
-            .unwrap_or_else(|| "a".repeat(pub_options.message_size as usize));
-
-        let topic = pub_options.topic_of(id);
+        let payloads = Arc::clone(&payloads);
+        let topics = Arc::clone(&topics);
+        let properties = if common.mqtt_version.is_v5() {
+            pub_options
+                .properties_of(id)
+                .context("Failed to build MQTT 5 message properties")?
+        } else {
+            Properties::new()
+        };
 
         let pub_interval = Duration::from_millis(common.interval);
+        let profile = profile.clone();
+        let (start, start_number, total) = (state.started(), common.start_number, common.total);
         let qos = common.qos;
 
         let client_state = Arc::clone(state);
@@ -151,31 +282,41 @@ pub async fn publish(
             .name(&client.client_id())
             .spawn(async move {
                 let _ = client.connect().await;
-                let mut payload: Vec<u8> = payload.into();
 
+                let mut schedule =
+                    profile.map(|profile| Schedule::new(profile, start, id - start_number, total));
                 let mut warning_count = 0;
+                let mut topic = TopicCursor::new(topics, id, client.client_id());
+                let mut payloads = PayloadGenerator::new(payloads, id, client.client_id());
                 loop {
-                    if let Err(e) = tag_timestamp(&mut payload[..]) {
+                    let intended = match schedule.as_mut() {
+                        Some(schedule) => schedule.tick().await,
+                        None => Instant::now(),
+                    };
+                    let payload = payloads.generate();
+                    if let Err(e) = tag_timestamp(payload, intended, id as u64, topic.sequence()) {
                         error!("{}", e.to_string());
                         break;
                     }
 
                     let message = MessageBuilder::new()
-                        .topic(&topic)
+                        .topic(topic.topic())
                         .payload(&payload[..])
                         .qos(qos)
+                        .properties(properties.clone())
                         .finalize();
                     if client_state.stopped() {
                         break;
                     }
 
                     if client.connected() {
-                        if let Err(e) = client.publish(message.clone()).await {
+                        if let Err(e) = client.publish_at(message.clone(), intended.into()).await {
                             error!("Failed to publish message: {}", e.to_string());
                             break;
                         }
+                        topic.advance();
 
-                        if pub_interval.as_millis() > 0 {
+                        if schedule.is_none() && pub_interval.as_millis() > 0 {
                             tokio::time::sleep(pub_interval).await;
                         }
                         continue;
@@ -190,22 +331,47 @@ pub async fn publish(
                 }
             });
     }
+    Ok(())
+}
+
+pub async fn subscribe(
+    common: &Common,
+    state: &Arc<State>,
+    statistics: &Statistics,
+    sub_options: &SubOptions,
+) -> Result<(), anyhow::Error> {
+    launch_subscribers(common, state, &statistics.latency, sub_options).await?;
 
     await_connection(common.total, state).await;
     await_running(common, state).await;
 
     if common.show_statistics {
         statistics.show_statistics();
+        state.show_reason_codes(common.mqtt_version);
+        state.show_delivery();
+        let credentials = common.credentials()?;
+        show_receive_distribution(common, credentials.as_ref(), state, sub_options);
+    }
+
+    if let Some(path) = &common.output {
+        Report::new("sub", common, state, statistics)
+            .sub_options(sub_options)
+            .write(path, common.format)?;
     }
+    write_series(common, state)?;
     Ok(())
 }
 
-pub async fn subscribe(
+async fn launch_subscribers(
     common: &Common,
     state: &Arc<State>,
-    statistics: &Statistics,
+    latency: &LatencyHistogram,
     sub_options: &SubOptions,
 ) -> Result<(), anyhow::Error> {
+    let issuer = common.issuer()?;
+    let credentials = common.credentials()?;
+    let generator = common.auth_options.generator()?;
+    let topics = sub_options.topics(common)?;
     let rate_limiter = Ratelimiter::builder(1, Duration::from_millis(common.interval))
         .max_tokens(common.concurrency as u64)
         .build()?;
@@ -223,8 +389,11 @@ pub async fn subscribe(
         }
         let client = match crate::client::Client::new(
             common.clone(),
-            common.client_id_of(id),
-            statistics.latency.clone(),
+            id,
+            issuer.as_ref(),
+            credentials.as_ref(),
+            Arc::clone(&generator),
+            latency.clone(),
             Arc::clone(state),
         )
         .context(format!("Failed to create MQTT client client_{}", id))
@@ -235,11 +404,21 @@ pub async fn subscribe(
                 break;
             }
         };
-        let topic = sub_options.topic_of(id);
+        let client_id = client.client_id();
+        let filters = topics
+            .iter()
+            .map(|topics| topics.filter_of(id, &client_id))
+            .collect::<Vec<_>>();
+        if let Some(filter) = filters.iter().find(|filter| !is_valid_filter(filter)) {
+            bail!("Invalid topic filter `{}`", filter);
+        }
         let client_state = Arc::clone(state);
         let qos = common.qos;
 
-        client.subscribe(&topic, qos);
+        match sub_options.share_group_of(id) {
+            Some(group) => client.subscribe_shared(&group, filters, qos),
+            None => client.subscribe(filters, qos),
+        }
 
         let _ = tokio::task::Builder::new()
             .name(&client.client_id())
@@ -254,22 +433,98 @@ pub async fn subscribe(
                 }
             });
     }
+    Ok(())
+}
+
+pub async fn benchmark(
+    common: &Common,
+    state: &Arc<State>,
+    statistics: &Statistics,
+    pub_options: &PubOptions,
+    bench_options: &BenchOptions,
+) -> Result<(), anyhow::Error> {
+    launch_benchmark(
+        common,
+        state,
+        &statistics.latency,
+        pub_options,
+        bench_options,
+    )
+    .await?;
 
     await_connection(common.total, state).await;
     await_running(common, state).await;
 
     if common.show_statistics {
         statistics.show_statistics();
+        state.show_reason_codes(common.mqtt_version);
+        state.show_delivery();
+    }
+
+    if let Some(path) = &common.output {
+        Report::new("benchmark", common, state, statistics)
+            .pub_options(pub_options)
+            .bench_options(bench_options)
+            .write(path, common.format)?;
     }
+    write_series(common, state)?;
     Ok(())
 }
 
-pub async fn benchmark(
+async fn launch_benchmark(
     common: &Common,
     state: &Arc<State>,
-    statistics: &Statistics,
+    latency: &LatencyHistogram,
     pub_options: &PubOptions,
+    bench_options: &BenchOptions,
 ) -> Result<(), anyhow::Error> {
+    warn_ignored_properties(common, pub_options);
+    let profile = pub_options.load_profile().map(Arc::new);
+    if let Some(profile) = &profile {
+        state.add_profile(Arc::clone(profile));
+    }
+    let issuer = common.issuer()?;
+    let credentials = common.credentials()?;
+    let generator = common.auth_options.generator()?;
+    let populations = bench_options.populations(common)?;
+    let (topics, sub_topics) = match &populations {
+        Some(populations) => (
+            bench_options.pub_topics(populations, pub_options)?,
+            bench_options.sub_topics(populations, pub_options)?,
+        ),
+        // Every client subscribes to the topic it publishes to
+        None => (pub_options.topics(common)?, vec![]),
+    };
+    let topics = Arc::new(topics);
+    let payloads = Arc::new(pub_options.payloads()?);
+    let publishers = populations.map_or(common.total, |populations| populations.publishers);
+
+    // Filters of all subscribers, known upfront to count the deliveries every message should make
+    let mut filters = BTreeMap::new();
+    for id in common.start_number..common.total + common.start_number {
+        let client_id = common.credential_of(id, credentials.as_ref()).client_id;
+        let client_filters = match &populations {
+            Some(populations) if populations.is_publisher(id) => continue,
+            Some(_) => sub_topics
+                .iter()
+                .map(|topics| topics.filter_of(id, &client_id))
+                .collect::<Vec<_>>(),
+            None => vec![topics.filter_of(id, &client_id)],
+        };
+        if let Some(filter) = client_filters
+            .iter()
+            .find(|filter| !is_valid_filter(filter))
+        {
+            bail!("Invalid topic filter `{}`", filter);
+        }
+        filters.insert(id, client_filters);
+    }
+    let mut audience = Audience::default();
+    for client_filters in filters.values() {
+        audience.add(client_filters.clone());
+    }
+    let audience = Arc::new(audience);
+
     let rate_limiter = Ratelimiter::builder(1, Duration::from_millis(common.interval))
         .max_tokens(common.concurrency as u64)
         .build()?;
@@ -289,8 +544,11 @@ pub async fn benchmark(
 
         let client = match crate::client::Client::new(
             common.clone(),
-            common.client_id_of(id),
-            statistics.latency.clone(),
+            id,
+            issuer.as_ref(),
+            credentials.as_ref(),
+            Arc::clone(&generator),
+            latency.clone(),
             Arc::clone(state),
         )
         .context(format!("Failed to create MQTT client client_{}", id))
@@ -302,48 +560,94 @@ pub async fn benchmark(
             }
         };
 
-        let payload = pub_options
-            .payload
-            .clone()
-            .unwrap_or_else(|| "a".repeat(pub_options.message_size as usize));
+        let qos = common.qos;
+        if let Some(client_filters) = filters.remove(&id) {
+            client.subscribe(client_filters, qos);
+        }
+        let client_state = Arc::clone(state);
 
-        let topic = pub_options.topic_of(id);
+        if populations.is_some_and(|populations| !populations.is_publisher(id)) {
+            let _ = tokio::task::Builder::new()
+                .name(&client.client_id())
+                .spawn(async move {
+                    let _ = client.connect().await;
+                    // Loop to keep client ref alive
+                    loop {
+                        if client_state.stopped() {
+                            break;
+                        }
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                });
+            continue;
+        }
+
+        let payloads = Arc::clone(&payloads);
+        let topics = Arc::clone(&topics);
+        let audience = Arc::clone(&audience);
+        let properties = if common.mqtt_version.is_v5() {
+            pub_options
+                .properties_of(id)
+                .context("Failed to build MQTT 5 message properties")?
+        } else {
+            Properties::new()
+        };
 
         let pub_interval = Duration::from_millis(common.interval);
-        let qos = common.qos;
+        let profile = profile.clone();
+        let (start, start_number) = (state.started(), common.start_number);
 
-        client.subscribe(&topic, qos);
-        let client_state = Arc::clone(state);
         let _ = tokio::task::Builder::new()
             .name(&client.client_id())
             .spawn(async move {
                 let _ = client.connect().await;
 
-                let mut payload: Vec<u8> = payload.into();
-
+                let mut schedule = profile
+                    .map(|profile| Schedule::new(profile, start, id - start_number, publishers));
                 let mut warning_count = 0;
+                let per_message = topics.is_per_message();
+                let mut topic = TopicCursor::new(topics, id, client.client_id());
+                let mut payloads = PayloadGenerator::new(payloads, id, client.client_id());
+                let mut audience = Reach::new(audience);
+                // Subscribers a message reaches, the same for all messages to a fixed topic
+                let mut reach = audience.count(topic.topic());
                 loop {
                     if client_state.stopped() {
                         break;
                     }
 
-                    if let Err(e) = tag_timestamp(&mut payload[..]) {
+                    let intended = match schedule.as_mut() {
+                        Some(schedule) => schedule.tick().await,
+                        None => Instant::now(),
+                    };
+                    let payload = payloads.generate();
+                    if let Err(e) = tag_timestamp(payload, intended, id as u64, topic.sequence()) {
                         error!("{}", e.to_string());
                         break;
                     }
 
                     let message = MessageBuilder::new()
-                        .topic(&topic)
+                        .topic(topic.topic())
                         .payload(&payload[..])
                         .qos(qos)
+                        .properties(properties.clone())
                         .finalize();
 
                     if client.connected() {
-                        if client.publish(message.clone()).await.is_err() {
-                            client_state.on_publish_failure();
+                        // The client counts failures
+                        if client
+                            .publish_at(message.clone(), intended.into())
+                            .await
+                            .is_ok()
+                        {
+                            client_state.on_expected(reach);
+                        }
+                        topic.advance();
+                        if per_message {
+                            reach = audience.count(topic.topic());
                         }
 
-                        if pub_interval.as_millis() > 0 {
+                        if schedule.is_none() && pub_interval.as_millis() > 0 {
                             tokio::time::sleep(pub_interval).await;
                         }
                         continue;
@@ -360,16 +664,73 @@ pub async fn benchmark(
                 }
             });
     }
+    Ok(())
+}
 
-    await_connection(common.total, state).await;
-    await_running(common, state).await;
+/// Report how received messages are spread across subscribers and, if shared subscriptions are
+/// used, across the members of each group.
+fn show_receive_distribution(
+    common: &Common,
+    credentials: Option<&Credentials>,
+    state: &Arc<State>,
+    sub_options: &SubOptions,
+) {
+    let mut overall = vec![];
+    let mut groups: BTreeMap<String, Vec<(String, usize)>> = BTreeMap::new();
+    for id in common.start_number..common.total + common.start_number {
+        let client_id = common.credential_of(id, credentials).client_id;
+        let received = state.received_by(&client_id);
+        debug!(
+            "Client[client-id={}] received {} messages",
+            client_id, received
+        );
+        overall.push((client_id.clone(), received));
+        if let Some(group) = sub_options.share_group_of(id) {
+            groups.entry(group).or_default().push((client_id, received));
+        }
+    }
 
-    if common.show_statistics {
-        statistics.show_statistics();
+    show_distribution("Messages per subscriber", &overall);
+    for (group, members) in groups.iter() {
+        show_distribution(&format!("Messages per member of $share/{}", group), members);
+    }
+}
+
+fn show_distribution(title: &str, received: &[(String, usize)]) {
+    let counts = received.iter().map(|(_, n)| *n).collect::<Vec<_>>();
+    let Some(distribution) = Distribution::of(&counts) else {
+        return;
+    };
+    // Both exist as the distribution is not empty
+    let (min_client, _) = received.iter().min_by_key(|(_, n)| *n).unwrap();
+    let (max_client, _) = received.iter().max_by_key(|(_, n)| *n).unwrap();
+    info!(
+        "{}: subscribers: {}, total: {}, min: {} ({}), max: {} ({}), mean: {:.2}, stddev: {:.2}",
+        title,
+        distribution.population,
+        distribution.total,
+        distribution.min,
+        min_client,
+        distribution.max,
+        max_client,
+        distribution.mean,
+        distribution.stddev
+    );
+}
+
+fn write_series(common: &Common, state: &Arc<State>) -> Result<(), anyhow::Error> {
+    if let Some(path) = &common.series {
+        series::write(&state.samples(), path, common.series_format)?;
     }
     Ok(())
 }
 
+fn warn_ignored_properties(common: &Common, pub_options: &PubOptions) {
+    if pub_options.has_properties() && !common.mqtt_version.is_v5() {
+        warn!("Message properties are only sent with --mqtt-version=5, ignoring them");
+    }
+}
+
 async fn await_running(common: &Common, state: &Arc<State>) {
     for i in 0..common.time {
         if state.stopped() {
@@ -382,40 +743,51 @@ async fn await_running(common: &Common, state: &Arc<State>) {
     }
 }
 
-fn tag_timestamp(data: &mut [u8]) -> anyhow::Result<()> {
-    if data.len() < size_of::<u128>() {
-        return Ok(());
-    }
-
-    let ts = SystemTime::now()
+/// Tag the payload with the intended send timestamp, the publisher and the sequence number of the
+/// message.
+fn tag_timestamp(
+    data: &mut [u8],
+    intended: Instant,
+    publisher: u64,
+    sequence: u64,
+) -> anyhow::Result<()> {
+    let timestamp = system_time_of(intended)
         .duration_since(SystemTime::UNIX_EPOCH)?
-        .as_millis();
+        .as_micros();
 
-    let mut cursor = Cursor::new(data);
-    cursor
-        .write_u128::<byteorder::LittleEndian>(ts)
-        .context("Failed to tag timestamp")?;
-    Ok(())
+    Header {
+        timestamp,
+        publisher,
+        sequence,
+    }
+    .write_to(data)
 }
 
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
+    use std::time::Instant;
 
     use byteorder::ReadBytesExt;
 
+    use crate::header::Header;
+
     #[test]
     fn test_tag_timestamp() -> anyhow::Result<()> {
         let mut data = [0u8; 32];
-        super::tag_timestamp(&mut data)?;
+        super::tag_timestamp(&mut data, Instant::now(), 3, 5)?;
 
         let mut cursor = Cursor::new(&data);
         let ts = cursor.read_u128::<byteorder::LittleEndian>()?;
 
         let current_ts = std::time::SystemTime::now()
             .duration_since(std::time::SystemTime::UNIX_EPOCH)?
-            .as_millis();
-        assert!(current_ts - ts < 100);
+            .as_micros();
+        assert!(current_ts - ts < 100_000);
+
+        let header = Header::read_from(&data).unwrap();
+        assert_eq!(3, header.publisher);
+        assert_eq!(5, header.sequence);
         Ok(())
     }
 }
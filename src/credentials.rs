@@ -0,0 +1,116 @@
+use anyhow::{bail, Context};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Identity a client logs in with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credential {
+    pub client_id: String,
+    pub username: String,
+    pub password: String,
+    /// Certificate and private key files presented during the TLS handshake, if provisioned
+    pub cert: Option<(PathBuf, PathBuf)>,
+}
+
+/// Credentials provisioned for devices, read from a CSV file with one line per client:
+/// `client_id,username,password[,cert,key]`.
+///
+/// The `n`th line holds the credentials of client `n`, i.e. the client whose ID expands `%d` to
+/// `n`, counting from 0. Empty lines and lines starting with `#` are skipped, as is a first line
+/// starting with `client_id,`. Relative certificate and key paths are resolved against the
+/// directory of the file. Fields cannot contain commas.
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    entries: Vec<Credential>,
+}
+
+impl Credentials {
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let content = fs::read_to_string(path).context(format!(
+            "Failed to read credentials from {}",
+            path.display()
+        ))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        Self::parse(&content, dir).context(format!("Invalid credentials in {}", path.display()))
+    }
+
+    fn parse(content: &str, dir: &Path) -> Result<Self, anyhow::Error> {
+        let mut entries = vec![];
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if number == 0 && line.starts_with("client_id,") {
+                continue;
+            }
+            let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+            let cert = match fields[..] {
+                [_, _, _] => None,
+                [_, _, _, cert, key] => Some((dir.join(cert), dir.join(key))),
+                _ => bail!(
+                    "Line {}: expected `client_id,username,password[,cert,key]`",
+                    number + 1
+                ),
+            };
+            entries.push(Credential {
+                client_id: fields[0].to_owned(),
+                username: fields[1].to_owned(),
+                password: fields[2].to_owned(),
+                cert,
+            });
+        }
+        Ok(Self { entries })
+    }
+
+    /// Credentials of client `id`, if the file has that many lines.
+    pub fn get(&self, id: usize) -> Option<&Credential> {
+        self.entries.get(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Credential, Credentials};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_parse() -> anyhow::Result<()> {
+        let content = "client_id,username,password,cert,key\n\
+                       device-0,user0,secret0\n\
+                       \n\
+                       # Provisioned with a certificate\n\
+                       device-1, user1, secret1, certs/device-1.crt, /keys/device-1.key\n";
+        let credentials = Credentials::parse(content, Path::new("/etc/fleet"))?;
+        assert_eq!(2, credentials.len());
+        assert_eq!(
+            Some(&Credential {
+                client_id: "device-0".to_owned(),
+                username: "user0".to_owned(),
+                password: "secret0".to_owned(),
+                cert: None,
+            }),
+            credentials.get(0)
+        );
+        assert_eq!(
+            Some((
+                PathBuf::from("/etc/fleet/certs/device-1.crt"),
+                PathBuf::from("/keys/device-1.key")
+            )),
+            credentials.get(1).unwrap().cert
+        );
+        assert_eq!(None, credentials.get(2));
+
+        assert!(Credentials::parse("device-0,user0", Path::new("")).is_err());
+        assert!(Credentials::parse("device-0,user0,secret0,cert", Path::new("")).is_err());
+        Ok(())
+    }
+}
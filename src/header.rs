@@ -0,0 +1,116 @@
+use anyhow::Context;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::Cursor;
+use std::mem::size_of;
+
+/// Header written at the start of every published payload.
+///
+/// Layout, all little endian:
+/// - send timestamp in microseconds since the UNIX epoch, `u128`;
+/// - ID of the publishing client, `u64`;
+/// - sequence number of the message for that publisher and topic, `u64`.
+///
+/// Payloads too short for the whole header but not for the timestamp carry the timestamp only, so
+/// that they still measure end-to-end latency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub(crate) timestamp: u128,
+    pub(crate) publisher: u64,
+    pub(crate) sequence: u64,
+}
+
+impl Header {
+    pub(crate) const LEN: usize = Self::TIMESTAMP_LEN + 2 * size_of::<u64>();
+
+    /// Length of the timestamp, the only part of the header shorter payloads carry.
+    pub(crate) const TIMESTAMP_LEN: usize = size_of::<u128>();
+
+    /// Sequence number of messages that receivers do not check for losses.
+    pub(crate) const UNTRACKED: u64 = u64::MAX;
+
+    /// Write the header to the start of `data`. Payloads shorter than [`Header::LEN`] get the
+    /// timestamp only, and those shorter than [`Header::TIMESTAMP_LEN`] are left untouched.
+    pub(crate) fn write_to(&self, data: &mut [u8]) -> anyhow::Result<()> {
+        if data.len() < Self::TIMESTAMP_LEN {
+            return Ok(());
+        }
+
+        let full = data.len() >= Self::LEN;
+        let mut cursor = Cursor::new(data);
+        cursor
+            .write_u128::<LittleEndian>(self.timestamp)
+            .context("Failed to tag timestamp")?;
+        if !full {
+            return Ok(());
+        }
+        cursor
+            .write_u64::<LittleEndian>(self.publisher)
+            .context("Failed to tag publisher")?;
+        cursor
+            .write_u64::<LittleEndian>(self.sequence)
+            .context("Failed to tag sequence")?;
+        Ok(())
+    }
+
+    /// Read the header from the start of `data`, if it is long enough to carry one. Headers of
+    /// payloads shorter than [`Header::LEN`] have an [`Header::UNTRACKED`] sequence number.
+    pub(crate) fn read_from(data: &[u8]) -> Option<Header> {
+        if data.len() < Self::TIMESTAMP_LEN {
+            return None;
+        }
+
+        let full = data.len() >= Self::LEN;
+        let mut cursor = Cursor::new(data);
+        let timestamp = cursor.read_u128::<LittleEndian>().ok()?;
+        if !full {
+            return Some(Header {
+                timestamp,
+                publisher: 0,
+                sequence: Self::UNTRACKED,
+            });
+        }
+        let publisher = cursor.read_u64::<LittleEndian>().ok()?;
+        let sequence = cursor.read_u64::<LittleEndian>().ok()?;
+        Some(Header {
+            timestamp,
+            publisher,
+            sequence,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Header;
+
+    #[test]
+    fn test_round_trip() -> anyhow::Result<()> {
+        let header = Header {
+            timestamp: 1_733_191_347_000,
+            publisher: 42,
+            sequence: 7,
+        };
+        let mut data = [0u8; 64];
+        header.write_to(&mut data)?;
+        assert_eq!(Some(header), Header::read_from(&data));
+
+        // Payloads of 16 to 31 bytes only carry the timestamp
+        for len in [Header::TIMESTAMP_LEN, Header::LEN - 1] {
+            let mut short = vec![0u8; len];
+            header.write_to(&mut short)?;
+            assert_eq!(
+                vec![0u8; len - Header::TIMESTAMP_LEN],
+                &short[Header::TIMESTAMP_LEN..]
+            );
+            let read = Header::read_from(&short).unwrap();
+            assert_eq!(header.timestamp, read.timestamp);
+            assert_eq!(Header::UNTRACKED, read.sequence);
+        }
+
+        let mut shorter = [0u8; Header::TIMESTAMP_LEN - 1];
+        header.write_to(&mut shorter)?;
+        assert_eq!([0u8; Header::TIMESTAMP_LEN - 1], shorter);
+        assert_eq!(None, Header::read_from(&shorter));
+        Ok(())
+    }
+}
@@ -1,7 +1,20 @@
+pub mod auth;
+pub mod broker;
 pub mod cert;
 pub mod cli;
 pub mod client;
 pub mod command;
+pub mod credentials;
+mod header;
+pub mod metrics;
+pub mod payload;
+pub mod profile;
+pub mod report;
+pub mod scenario;
+mod schedule;
+mod sequence;
+pub mod series;
 pub mod state;
 pub mod statistics;
 mod subscription;
+pub mod topic;
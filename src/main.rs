@@ -1,12 +1,18 @@
+use std::fs;
 use std::sync::Arc;
+use std::time::Instant;
 
 use clap::Parser;
 use log::{info, trace};
 
-use mqtt_bench::cli::{Cli, Commands};
+use mqtt_bench::broker::{tls_acceptor_builder, Broker};
+use mqtt_bench::cert::{load_ca_cert, load_ca_pkey, mk_ca_cert, Issuer};
+use mqtt_bench::cli::{CertCommands, Cli, Commands, Common};
 use mqtt_bench::state::{ctrl_c, print_stats, State};
 
-use mqtt_bench::command::{benchmark, connect, publish, subscribe};
+use mqtt_bench::command::{benchmark, connect, publish, run, subscribe};
+use mqtt_bench::metrics::{serve, StateCollector};
+use mqtt_bench::scenario::Scenario;
 use mqtt_bench::statistics::Statistics;
 use tokio::sync::mpsc::{channel, Receiver};
 
@@ -17,9 +23,21 @@ use tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
-fn watch_state(state: Arc<State>, rx: Receiver<()>) {
+async fn watch_state(
+    common: &Common,
+    state: Arc<State>,
+    statistics: &Statistics,
+    rx: Receiver<()>,
+) -> Result<(), anyhow::Error> {
     ctrl_c(Arc::clone(&state));
-    print_stats(state, rx);
+    if let Some(addr) = common.metrics_listen {
+        statistics
+            .registry
+            .register(Box::new(StateCollector::new(Arc::clone(&state))))?;
+        serve(addr, statistics.registry.clone()).await?;
+    }
+    print_stats(state, Arc::clone(&statistics.latency.hdr), rx);
+    Ok(())
 }
 
 #[tokio::main]
@@ -28,7 +46,10 @@ async fn main() -> Result<(), anyhow::Error> {
 
     console_subscriber::init();
 
-    let cli = Cli::parse();
+    let mut cli = Cli::parse();
+    if let Some(command) = &mut cli.command {
+        command.normalize()?;
+    }
     let (tx, rx) = channel::<()>(1);
     let statistics = Statistics::new();
 
@@ -37,59 +58,118 @@ async fn main() -> Result<(), anyhow::Error> {
         Some(cmd) => match cmd {
             Commands::Connect { common } => {
                 state = State::new(common.total);
-                watch_state(Arc::clone(&state), rx);
+                watch_state(&common, Arc::clone(&state), &statistics, rx).await?;
                 connect(&common, &state, &statistics).await?;
             }
 
             Commands::Pub {
                 common,
-                mut pub_options,
+                pub_options,
             } => {
                 state = State::new(common.total);
-                watch_state(Arc::clone(&state), rx);
-                if 0 == pub_options.topic_total {
-                    pub_options.topic_total = common.total;
-                    info!(
-                        "Now that --topic-total is 0, it will be set to --topic-total={}",
-                        common.total
-                    );
-                }
-
+                watch_state(&common, Arc::clone(&state), &statistics, rx).await?;
                 publish(&common, &state, &statistics, &pub_options).await?;
             }
 
             Commands::Sub {
                 common,
-                mut sub_options,
+                sub_options,
             } => {
                 state = State::new(common.total);
-                watch_state(Arc::clone(&state), rx);
-                if 0 == sub_options.topic_total {
-                    sub_options.topic_total = common.total;
-                    info!(
-                        "Now that --topic-total is 0, it will be set to --topic-total={}",
-                        common.total
-                    );
-                }
-
+                watch_state(&common, Arc::clone(&state), &statistics, rx).await?;
                 subscribe(&common, &state, &statistics, &sub_options).await?;
             }
 
             Commands::Benchmark {
                 common,
-                mut pub_options,
+                pub_options,
+                bench_options,
             } => {
                 state = State::new(common.total);
-                watch_state(Arc::clone(&state), rx);
-                if 0 == pub_options.topic_total {
-                    pub_options.topic_total = common.total;
-                    info!(
-                        "Now that --topic-total is 0, it will be set to --topic-total={}",
-                        common.total
-                    );
+                watch_state(&common, Arc::clone(&state), &statistics, rx).await?;
+                benchmark(&common, &state, &statistics, &pub_options, &bench_options).await?;
+            }
+
+            Commands::Broker { broker_options } => {
+                let broker = Broker::bind(broker_options.listen).await?;
+                let acceptor =
+                    if broker_options.tls_listen.is_some() || broker_options.wss_listen.is_some() {
+                        let ca_cert = load_ca_cert(&broker_options.tls_ca_cert)?;
+                        let ca_key = load_ca_pkey(&broker_options.tls_ca_key)?;
+                        let mut acceptor = tls_acceptor_builder(
+                            &ca_cert,
+                            &ca_key,
+                            broker_options.tls_key_algorithm,
+                            broker_options.tls_verify_client,
+                        )?;
+                        acceptor.set_min_proto_version(Some(
+                            broker_options.tls_min_version.as_ssl_version(),
+                        ))?;
+                        Some(acceptor.build())
+                    } else {
+                        None
+                    };
+                if let (Some(addr), Some(acceptor)) = (broker_options.tls_listen, &acceptor) {
+                    Broker::bind(addr)
+                        .await?
+                        .tls(acceptor.clone())
+                        .share_with(&broker)
+                        .spawn()?;
                 }
+                if let Some(addr) = broker_options.ws_listen {
+                    Broker::bind(addr)
+                        .await?
+                        .websocket()
+                        .share_with(&broker)
+                        .spawn()?;
+                }
+                if let (Some(addr), Some(acceptor)) = (broker_options.wss_listen, &acceptor) {
+                    Broker::bind(addr)
+                        .await?
+                        .tls(acceptor.clone())
+                        .websocket()
+                        .share_with(&broker)
+                        .spawn()?;
+                }
+                broker.spawn()?;
+                tokio::signal::ctrl_c().await?;
+                info!("Ctrl-C received, stopping");
+            }
+
+            Commands::Cert {
+                command: CertCommands::Generate { generate_options },
+            } => {
+                let issuer = Issuer::cached(
+                    &generate_options.out,
+                    Some((&generate_options.ca_cert, &generate_options.ca_key)),
+                )?
+                .template(generate_options.cert_options.template());
+                let clients = generate_options.clients();
+                let instant = Instant::now();
+                issuer.generate(&clients)?;
+                info!(
+                    "{} client certificates in {} took {:?}",
+                    clients.len(),
+                    generate_options.out.display(),
+                    instant.elapsed()
+                );
+            }
+
+            Commands::Cert {
+                command: CertCommands::Ca { key_algorithm, out },
+            } => {
+                let (ca_cert, ca_key) = mk_ca_cert(key_algorithm)?;
+                fs::create_dir_all(&out)?;
+                fs::write(out.join("CA.crt"), ca_cert.to_pem()?)?;
+                fs::write(out.join("CA.key"), ca_key.private_key_to_pem_pkcs8()?)?;
+                info!("{} CA written to {}", key_algorithm, out.display());
+            }
 
-                benchmark(&common, &state, &statistics, &pub_options).await?;
+            Commands::Run { scenario } => {
+                let scenario = Scenario::load(&scenario)?;
+                state = State::new(scenario.total());
+                watch_state(&scenario.common, Arc::clone(&state), &statistics, rx).await?;
+                run(&scenario, &state, &statistics).await?;
             }
         },
 
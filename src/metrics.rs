@@ -0,0 +1,235 @@
+use crate::state::State;
+use anyhow::Context;
+use log::{debug, info, warn};
+use prometheus::core::{Collector, Desc, Describer};
+use prometheus::proto::MetricFamily;
+use prometheus::{Encoder, IntCounter, IntGauge, Opts, Registry, TextEncoder};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Upper bound of the request head read from a scraper.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+type Read = fn(&State) -> usize;
+
+/// Counters that only grow over a run.
+const COUNTERS: [(&str, &str, Read); 8] = [
+    (
+        "connect_attempted_total",
+        "Number of CONNECT attempts",
+        State::attempted,
+    ),
+    (
+        "published_total",
+        "Number of messages published",
+        State::published_total,
+    ),
+    (
+        "publish_failures_total",
+        "Number of messages that failed to publish",
+        State::publish_failures_total,
+    ),
+    (
+        "received_total",
+        "Number of messages received",
+        State::received_total,
+    ),
+    (
+        "expected_total",
+        "Number of deliveries expected for the messages published",
+        State::expected,
+    ),
+    (
+        "duplicated_total",
+        "Number of messages received more than once",
+        State::duplicated,
+    ),
+    (
+        "out_of_order_total",
+        "Number of messages received after a later message of the same publisher",
+        State::out_of_order,
+    ),
+    (
+        "unmatched_total",
+        "Number of messages received that match none of the filters of their receiver",
+        State::unmatched,
+    ),
+];
+
+/// Values that go up and down over a run.
+const GAUGES: [(&str, &str, Read); 3] = [
+    (
+        "clients_connected",
+        "Number of connected clients",
+        State::connected,
+    ),
+    (
+        "clients_disconnected",
+        "Number of disconnected clients",
+        State::disconnected,
+    ),
+    (
+        "lost",
+        "Number of messages missing from the sequence of their publisher",
+        State::lost,
+    ),
+];
+
+/// Exposes the counters of [`State`] to a Prometheus registry, read at scrape time.
+pub struct StateCollector {
+    state: Arc<State>,
+    descs: Vec<Desc>,
+}
+
+impl StateCollector {
+    pub fn new(state: Arc<State>) -> Self {
+        let descs = COUNTERS
+            .iter()
+            .chain(GAUGES.iter())
+            .map(|&(name, help, _)| Opts::new(name, help).describe().unwrap())
+            .collect();
+        Self { state, descs }
+    }
+}
+
+impl Collector for StateCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = vec![];
+        for (name, help, read) in COUNTERS {
+            let counter = IntCounter::with_opts(Opts::new(name, help)).unwrap();
+            counter.inc_by(read(&self.state) as u64);
+            families.extend(counter.collect());
+        }
+        for (name, help, read) in GAUGES {
+            let gauge = IntGauge::with_opts(Opts::new(name, help)).unwrap();
+            gauge.set(read(&self.state) as i64);
+            families.extend(gauge.collect());
+        }
+        families
+    }
+}
+
+/// Serve the registry in Prometheus text format on `GET /metrics` at the given address.
+///
+/// Returns the address actually bound, which differs from `addr` when it asks for port 0.
+pub async fn serve(addr: SocketAddr, registry: Registry) -> Result<SocketAddr, anyhow::Error> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .context(format!("Failed to bind metrics endpoint to {}", addr))?;
+    let local_addr = listener.local_addr()?;
+    info!("Serving metrics at http://{}/metrics", local_addr);
+
+    let _ = tokio::task::Builder::new()
+        .name("metrics_server")
+        .spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept metrics connection: {}", e);
+                        continue;
+                    }
+                };
+                let registry = registry.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = respond(stream, &registry).await {
+                        debug!("Failed to serve metrics to {}: {}", peer, e);
+                    }
+                });
+            }
+        });
+    Ok(local_addr)
+}
+
+async fn respond(mut stream: TcpStream, registry: &Registry) -> Result<(), anyhow::Error> {
+    let mut request = vec![];
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_LEN {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let encoder = TextEncoder::new();
+            let mut body = vec![];
+            encoder.encode(&registry.gather(), &mut body)?;
+            ("200 OK", encoder.format_type().to_owned(), body)
+        }
+        _ => (
+            "404 Not Found",
+            "text/plain".to_owned(),
+            b"Not Found\n".to_vec(),
+        ),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve, StateCollector};
+    use crate::state::State;
+    use crate::statistics::Statistics;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn test_serve() -> anyhow::Result<()> {
+        let statistics = Statistics::new();
+        let state = State::new(2);
+        state.on_connected();
+        state.on_publish();
+        state.on_publish();
+        statistics
+            .registry
+            .register(Box::new(StateCollector::new(Arc::clone(&state))))?;
+        statistics
+            .latency
+            .recorder()
+            .publish(Duration::from_millis(3));
+
+        let addr = serve("127.0.0.1:0".parse()?, statistics.registry.clone()).await?;
+
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\nclients_connected 1\n"));
+        assert!(response.contains("\nclients_disconnected 1\n"));
+        assert!(response.contains("# TYPE published_total counter\npublished_total 2\n"));
+        assert!(response.contains("pub_histogram_count{type=\"publish\",unit=\"ms\"} 1\n"));
+
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,450 @@
+//! Payloads of published messages.
+//!
+//! Payloads come from a [`PayloadSource`], text and random bytes with sizes drawn from a
+//! [`SizeDistribution`]. Every payload starts with room for the [`Header`]: text and random bytes
+//! are overwritten by it, while sample files and JSON documents follow it, so that subscribers get
+//! them intact after the first [`Header::LEN`] bytes.
+
+mod json;
+
+use crate::header::Header;
+use crate::topic::splitmix64;
+use anyhow::{ensure, Context};
+use clap::ValueEnum;
+use json::JsonTemplate;
+use serde::{Serialize, Serializer};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Length of the random bytes payloads are cut from, beyond the window of common compressors.
+const RANDOM_POOL_LEN: usize = 1 << 20;
+
+/// Where the payloads of messages come from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadSource {
+    /// `--payload`, or else `a` repeated `--message-size` times
+    #[default]
+    Text,
+    /// Random bytes, which do not compress
+    Random,
+    /// Content of `--payload-file`, or of a file of that directory drawn for every message
+    File,
+    /// JSON document expanded from `--payload-template` for every message
+    Json,
+}
+
+/// Distribution of payload sizes, in bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SizeDistribution {
+    spec: String,
+    shape: Shape,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Shape {
+    Fixed(usize),
+    /// Sizes between the bounds, inclusive
+    Uniform(usize, usize),
+    Normal {
+        mean: f64,
+        stddev: f64,
+    },
+    /// Sizes with the cumulative probability of drawing them or any size before
+    Empirical(Vec<(usize, f64)>),
+}
+
+impl SizeDistribution {
+    fn draw(&self, random: &mut Random) -> usize {
+        match &self.shape {
+            Shape::Fixed(size) => *size,
+            Shape::Uniform(low, high) => low + random.below((high - low) as u64 + 1) as usize,
+            Shape::Normal { mean, stddev } => {
+                // Box-Muller transform, `1 - unit` keeping the logarithm finite
+                let (u, v) = (1.0 - random.unit(), random.unit());
+                let z = (-2.0 * u.ln()).sqrt() * (std::f64::consts::TAU * v).cos();
+                (mean + stddev * z).round().max(0.0) as usize
+            }
+            Shape::Empirical(sizes) => {
+                let p = random.unit();
+                let index = sizes.partition_point(|(_, cumulative)| *cumulative <= p);
+                sizes[index.min(sizes.len() - 1)].0
+            }
+        }
+    }
+}
+
+impl FromStr for SizeDistribution {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let shape = match spec.split_once(':') {
+            None => Shape::Fixed(parse_size(spec)?),
+            Some(("uniform", range)) => {
+                let (low, high) = range
+                    .split_once("..")
+                    .ok_or_else(|| format!("expected `uniform:<min>..<max>`, got `{}`", spec))?;
+                let (low, high) = (parse_size(low)?, parse_size(high)?);
+                if low > high {
+                    return Err(format!("empty range in `{}`", spec));
+                }
+                Shape::Uniform(low, high)
+            }
+            Some(("normal", parameters)) => {
+                let (mean, stddev) = parameters
+                    .split_once(',')
+                    .and_then(|(mean, stddev)| {
+                        Some((
+                            mean.trim().parse::<f64>().ok()?,
+                            stddev.trim().parse().ok()?,
+                        ))
+                    })
+                    .filter(|(mean, stddev): &(f64, f64)| {
+                        mean.is_finite() && *mean >= 0.0 && stddev.is_finite() && *stddev >= 0.0
+                    })
+                    .ok_or_else(|| format!("expected `normal:<mean>,<stddev>`, got `{}`", spec))?;
+                Shape::Normal { mean, stddev }
+            }
+            Some(("empirical", sizes)) => {
+                let mut sizes = sizes
+                    .split(',')
+                    .map(|entry| {
+                        let Some((size, weight)) = entry.split_once('=') else {
+                            return Ok((parse_size(entry)?, 1.0));
+                        };
+                        match weight.trim().parse::<f64>() {
+                            Ok(weight) if weight.is_finite() && weight > 0.0 => {
+                                Ok((parse_size(size)?, weight))
+                            }
+                            _ => Err(format!("expected a positive weight, got `{}`", weight)),
+                        }
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+                let total = sizes.iter().map(|(_, weight)| weight).sum::<f64>();
+                let mut cumulative = 0.0;
+                for (_, weight) in &mut sizes {
+                    cumulative += *weight / total;
+                    *weight = cumulative;
+                }
+                Shape::Empirical(sizes)
+            }
+            Some(_) => {
+                return Err(format!(
+                    "expected `<size>`, `uniform:<min>..<max>`, `normal:<mean>,<stddev>` or \
+                     `empirical:<size>=<weight>,...`, got `{}`",
+                    spec
+                ))
+            }
+        };
+        Ok(Self {
+            spec: spec.to_owned(),
+            shape,
+        })
+    }
+}
+
+impl Serialize for SizeDistribution {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.spec)
+    }
+}
+
+fn parse_size(s: &str) -> Result<usize, String> {
+    s.trim()
+        .parse::<usize>()
+        .map_err(|_| format!("expected a size in bytes, got `{}`", s))
+}
+
+/// Random numbers of a publisher: SplitMix64 of a counter.
+#[derive(Debug, Clone)]
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(1);
+        splitmix64(self.0)
+    }
+
+    /// Random number from 0 to `n`, exclusive.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// Random number from 0 to 1, exclusive.
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Payloads of the publishers of a run, each publisher generating its own with a
+/// [`PayloadGenerator`].
+#[derive(Debug)]
+pub struct Payloads {
+    content: Content,
+    /// Sizes of text and random payloads, if drawn for every message
+    sizes: Option<SizeDistribution>,
+}
+
+#[derive(Debug)]
+enum Content {
+    Text(Vec<u8>),
+    Random { pool: Vec<u8>, size: usize },
+    Samples(Vec<Vec<u8>>),
+    Json(JsonTemplate),
+}
+
+impl Payloads {
+    /// `text` as is, or repeated or cut to sizes drawn from `sizes`.
+    pub fn text(text: Vec<u8>, sizes: Option<SizeDistribution>) -> Self {
+        Self {
+            content: Content::Text(text),
+            sizes,
+        }
+    }
+
+    /// Random bytes, `size` of them or as many as drawn from `sizes`.
+    pub fn random(size: usize, sizes: Option<SizeDistribution>) -> Self {
+        let mut random = Random(0);
+        let pool = (0..RANDOM_POOL_LEN / 8)
+            .flat_map(|_| random.next().to_le_bytes())
+            .collect();
+        Self {
+            content: Content::Random { pool, size },
+            sizes,
+        }
+    }
+
+    /// Content of the file `path`, or of the files of the directory `path`.
+    pub fn samples(path: &Path) -> Result<Self, anyhow::Error> {
+        let paths = if path.is_dir() {
+            let mut paths = fs::read_dir(path)
+                .and_then(|entries| {
+                    entries
+                        .map(|entry| entry.map(|entry| entry.path()))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .context(format!(
+                    "Failed to list payload samples in {}",
+                    path.display()
+                ))?;
+            paths.retain(|path| path.is_file());
+            paths.sort();
+            paths
+        } else {
+            vec![path.to_owned()]
+        };
+        ensure!(
+            !paths.is_empty(),
+            "No payload samples in {}",
+            path.display()
+        );
+        let samples = paths
+            .iter()
+            .map(|path| {
+                fs::read(path).context(format!("Failed to read payload sample {}", path.display()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            content: Content::Samples(samples),
+            sizes: None,
+        })
+    }
+
+    /// JSON documents expanded from the template in the file `path`.
+    pub fn json(path: &Path) -> Result<Self, anyhow::Error> {
+        let template = fs::read_to_string(path).context(format!(
+            "Failed to read payload template {}",
+            path.display()
+        ))?;
+        let template = JsonTemplate::parse(&template)
+            .context(format!("Invalid payload template in {}", path.display()))?;
+        Ok(Self {
+            content: Content::Json(template),
+            sizes: None,
+        })
+    }
+}
+
+/// Payloads of the messages of a publisher, generated into a buffer reused from message to
+/// message.
+#[derive(Debug)]
+pub struct PayloadGenerator {
+    payloads: Arc<Payloads>,
+    client_id: String,
+    random: Random,
+    /// Number of payloads generated so far
+    count: u64,
+    buffer: Vec<u8>,
+}
+
+impl PayloadGenerator {
+    pub fn new(payloads: Arc<Payloads>, id: usize, client_id: String) -> Self {
+        Self {
+            payloads,
+            client_id,
+            random: Random(splitmix64(id as u64)),
+            count: 0,
+            buffer: vec![],
+        }
+    }
+
+    /// Payload of the next message, its first [`Header::LEN`] bytes left for the header.
+    pub fn generate(&mut self) -> &mut [u8] {
+        let Self {
+            payloads,
+            client_id,
+            random,
+            count,
+            buffer,
+        } = self;
+        // At least the header, so that every message can be tracked
+        let mut draw = |default: usize| {
+            payloads
+                .sizes
+                .as_ref()
+                .map_or(default, |sizes| sizes.draw(random).max(Header::LEN))
+        };
+        buffer.clear();
+        match &payloads.content {
+            Content::Text(text) => {
+                let size = draw(text.len());
+                buffer.extend(text.iter().cycle().take(size));
+                // Empty text repeats into nothing
+                buffer.resize(size, 0);
+            }
+            Content::Random { pool, size } => {
+                let size = draw(*size);
+                let offset = random.below(pool.len() as u64) as usize;
+                buffer.extend(pool[offset..].iter().chain(pool.iter().cycle()).take(size));
+            }
+            Content::Samples(samples) => {
+                let sample = &samples[random.below(samples.len() as u64) as usize];
+                buffer.resize(Header::LEN, 0);
+                buffer.extend_from_slice(sample);
+            }
+            Content::Json(template) => {
+                let context = json::Context {
+                    client_id,
+                    sequence: *count,
+                };
+                buffer.resize(Header::LEN, 0);
+                template.render_to(&context, random, buffer);
+            }
+        }
+        *count += 1;
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Content, PayloadGenerator, Payloads, Random, SizeDistribution};
+    use crate::header::Header;
+    use std::sync::Arc;
+
+    fn generator(payloads: Payloads) -> PayloadGenerator {
+        PayloadGenerator::new(Arc::new(payloads), 7, "device-7".to_owned())
+    }
+
+    #[test]
+    fn test_size_distribution_from_str() {
+        for spec in [
+            "64",
+            "uniform:32..1024",
+            "normal:512,64",
+            "empirical:64=70,1024=25,65536=5",
+            "empirical:64,128",
+        ] {
+            let sizes = spec.parse::<SizeDistribution>();
+            assert!(sizes.is_ok(), "{}: {:?}", spec, sizes);
+        }
+        for spec in [
+            "",
+            "-1",
+            "uniform:1024..32",
+            "uniform:32",
+            "normal:512",
+            "normal:-1,2",
+            "empirical:",
+            "empirical:64=0",
+            "zipf:1",
+        ] {
+            assert!(spec.parse::<SizeDistribution>().is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn test_draw() -> Result<(), String> {
+        let mut random = Random(0);
+        let uniform = "uniform:100..103".parse::<SizeDistribution>()?;
+        let sizes = (0..1000)
+            .map(|_| uniform.draw(&mut random))
+            .collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(
+            vec![100, 101, 102, 103],
+            sizes.into_iter().collect::<Vec<_>>()
+        );
+
+        let empirical = "empirical:64=3,1024=1".parse::<SizeDistribution>()?;
+        let small = (0..10_000)
+            .filter(|_| empirical.draw(&mut random) == 64)
+            .count();
+        assert!((7_000..8_000).contains(&small), "{}", small);
+
+        let normal = "normal:1000,100".parse::<SizeDistribution>()?;
+        let mean = (0..10_000)
+            .map(|_| normal.draw(&mut random) as f64)
+            .sum::<f64>()
+            / 10_000.0;
+        assert!((990.0..1010.0).contains(&mean), "{}", mean);
+        Ok(())
+    }
+
+    #[test]
+    fn test_text() -> Result<(), String> {
+        let mut payloads = generator(Payloads::text(b"abc".to_vec(), None));
+        assert_eq!(b"abc", payloads.generate());
+
+        let sizes = "40".parse::<SizeDistribution>()?;
+        let mut payloads = generator(Payloads::text(b"abc".to_vec(), Some(sizes)));
+        let payload = payloads.generate();
+        assert_eq!(40, payload.len());
+        assert_eq!(b"abcabc", &payload[..6]);
+
+        // Drawn sizes leave room for the header
+        let sizes = "8".parse::<SizeDistribution>()?;
+        let mut payloads = generator(Payloads::text(vec![], Some(sizes)));
+        assert_eq!(Header::LEN, payloads.generate().len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_random() -> Result<(), String> {
+        let sizes = "uniform:64..128".parse::<SizeDistribution>()?;
+        let mut payloads = generator(Payloads::random(64, Some(sizes)));
+        let first = payloads.generate().to_vec();
+        let second = payloads.generate().to_vec();
+        assert!((64..=128).contains(&first.len()));
+        assert_ne!(first[..64], second[..64]);
+
+        let mut payloads = generator(Payloads::random(100, None));
+        assert_eq!(100, payloads.generate().len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_samples() {
+        let samples = vec![b"first".to_vec(), b"second".to_vec()];
+        let mut payloads = generator(Payloads {
+            content: Content::Samples(samples.clone()),
+            sizes: None,
+        });
+        for _ in 0..10 {
+            let payload = payloads.generate();
+            assert_eq!(&[0; Header::LEN], &payload[..Header::LEN]);
+            assert!(samples.contains(&payload[Header::LEN..].to_vec()));
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! JSON payload templates.
+//!
+//! A template is a JSON document whose strings can contain field generators, expanded for every
+//! message:
+//!
+//! - `{int:<a>..<b>}`: integer from `a` to `b` inclusive;
+//! - `{float:<a>..<b>}`: number from `a` to `b`, with two decimals;
+//! - `{bool}`: `true` or `false`;
+//! - `{choice:<a>|<b>|...}`: one of the given words;
+//! - `{string:<n>}`: `n` random letters and digits;
+//! - `{uuid}`: random UUID;
+//! - `{client_id}`: ID of the client;
+//! - `{seq}`: number of the message of the client, from 0;
+//! - `{timestamp}`: milliseconds since the UNIX epoch.
+//!
+//! A string made of a single generator takes the type of its values, so that `"{int:0..100}"`
+//! becomes a number, while generators within longer strings are written as text, as in
+//! `"sensor-{int:1..9}"`. `{{` and `}}` stand for literal braces, e.g. `"{{draft}}"` is written
+//! as `"{draft}"`. Object keys are left as is.
+
+use super::Random;
+use crate::topic::parse_range;
+use anyhow::{bail, ensure, Context as _};
+use serde_json::{Map, Value};
+use std::time::SystemTime;
+
+const ALPHANUMERIC: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Values generators expand to for a message.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Context<'a> {
+    pub(super) client_id: &'a str,
+    /// Number of the message of the client
+    pub(super) sequence: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Field {
+    /// Random integer between the bounds, inclusive
+    Int(i64, i64),
+    Float(f64, f64),
+    Bool,
+    Choice(Vec<String>),
+    /// Random letters and digits, as many as given
+    String(usize),
+    Uuid,
+    ClientId,
+    Seq,
+    Timestamp,
+}
+
+impl Field {
+    fn parse(placeholder: &str) -> Result<Self, anyhow::Error> {
+        let (name, argument) = match placeholder.split_once(':') {
+            Some((name, argument)) => (name, Some(argument)),
+            None => (placeholder, None),
+        };
+        let field = match (name, argument) {
+            ("int", Some(range)) => {
+                let (low, high) = parse_range(range).context(format!(
+                    "Expected `{{int:<a>..<b>}}`, got `{{{}}}`",
+                    placeholder
+                ))?;
+                Field::Int(low, high)
+            }
+            ("float", Some(range)) => {
+                let (low, high) = parse_range::<f64>(range)
+                    .and_then(|(low, high)| {
+                        ensure!(low.is_finite() && high.is_finite(), "Infinite bound");
+                        Ok((low, high))
+                    })
+                    .context(format!(
+                        "Expected `{{float:<a>..<b>}}`, got `{{{}}}`",
+                        placeholder
+                    ))?;
+                Field::Float(low, high)
+            }
+            ("bool", None) => Field::Bool,
+            ("choice", Some(choices)) => {
+                Field::Choice(choices.split('|').map(str::to_owned).collect())
+            }
+            ("string", Some(len)) => Field::String(len.trim().parse().context(format!(
+                "Expected `{{string:<n>}}`, got `{{{}}}`",
+                placeholder
+            ))?),
+            ("uuid", None) => Field::Uuid,
+            ("client_id", None) => Field::ClientId,
+            ("seq", None) => Field::Seq,
+            ("timestamp", None) => Field::Timestamp,
+            _ => bail!("Unknown field generator `{{{}}}`", placeholder),
+        };
+        Ok(field)
+    }
+
+    fn generate(&self, context: &Context, random: &mut Random) -> Value {
+        match self {
+            Field::Int(low, high) => {
+                let offset = match high.abs_diff(*low).checked_add(1) {
+                    Some(count) => random.below(count),
+                    None => random.next(),
+                };
+                Value::from(low.wrapping_add_unsigned(offset))
+            }
+            Field::Float(low, high) => {
+                let value = low + (high - low) * random.unit();
+                Value::from((value * 100.0).round() / 100.0)
+            }
+            Field::Bool => Value::Bool(random.next() & 1 == 1),
+            Field::Choice(choices) => {
+                Value::from(choices[random.below(choices.len() as u64) as usize].as_str())
+            }
+            Field::String(len) => {
+                let count = ALPHANUMERIC.len() as u64;
+                Value::from(
+                    (0..*len)
+                        .map(|_| ALPHANUMERIC[random.below(count) as usize] as char)
+                        .collect::<String>(),
+                )
+            }
+            Field::Uuid => Value::from(crate::topic::uuid(random.next())),
+            Field::ClientId => Value::from(context.client_id),
+            Field::Seq => Value::from(context.sequence),
+            Field::Timestamp => Value::from(
+                SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_millis() as u64),
+            ),
+        }
+    }
+}
+
+/// Part of a string with generators.
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Literal(String),
+    Field(Field),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    /// Value without generators
+    Value(Value),
+    /// String made of a single generator
+    Field(Field),
+    Text(Vec<Piece>),
+    Array(Vec<Node>),
+    Object(Vec<(String, Node)>),
+}
+
+impl Node {
+    fn parse(value: Value) -> Result<Self, anyhow::Error> {
+        let node = match value {
+            Value::String(text) => {
+                let mut pieces = parse_pieces(&text)?;
+                match pieces.as_mut_slice() {
+                    [] => Node::Value(Value::String(text)),
+                    [Piece::Literal(literal)] => {
+                        Node::Value(Value::String(std::mem::take(literal)))
+                    }
+                    [Piece::Field(field)] => Node::Field(field.clone()),
+                    _ => Node::Text(pieces),
+                }
+            }
+            Value::Array(values) => Node::Array(
+                values
+                    .into_iter()
+                    .map(Node::parse)
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Object(entries) => Node::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| Ok((key, Node::parse(value)?)))
+                    .collect::<Result<_, anyhow::Error>>()?,
+            ),
+            value => Node::Value(value),
+        };
+        Ok(node)
+    }
+
+    fn render(&self, context: &Context, random: &mut Random) -> Value {
+        match self {
+            Node::Value(value) => value.clone(),
+            Node::Field(field) => field.generate(context, random),
+            Node::Text(pieces) => {
+                let mut text = String::new();
+                for piece in pieces {
+                    match piece {
+                        Piece::Literal(literal) => text.push_str(literal),
+                        Piece::Field(field) => match field.generate(context, random) {
+                            Value::String(value) => text.push_str(&value),
+                            value => text.push_str(&value.to_string()),
+                        },
+                    }
+                }
+                Value::String(text)
+            }
+            Node::Array(nodes) => nodes
+                .iter()
+                .map(|node| node.render(context, random))
+                .collect(),
+            Node::Object(entries) => Value::Object(
+                entries
+                    .iter()
+                    .map(|(key, node)| (key.clone(), node.render(context, random)))
+                    .collect::<Map<_, _>>(),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonTemplate {
+    root: Node,
+}
+
+impl JsonTemplate {
+    pub fn parse(template: &str) -> Result<Self, anyhow::Error> {
+        let value = serde_json::from_str(template).context("Invalid JSON")?;
+        Ok(Self {
+            root: Node::parse(value)?,
+        })
+    }
+
+    /// Expand the generators for a message, appending the document to `buffer`.
+    pub(super) fn render_to(&self, context: &Context, random: &mut Random, buffer: &mut Vec<u8>) {
+        let document = self.root.render(context, random);
+        // Writing to a vector never fails, and JSON values have no NaN to reject
+        serde_json::to_writer(buffer, &document).unwrap();
+    }
+}
+
+fn parse_pieces(text: &str) -> Result<Vec<Piece>, anyhow::Error> {
+    let mut pieces = vec![];
+    let mut literal = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(['{', '}']) {
+        literal.push_str(&rest[..start]);
+        let brace = &rest[start..start + 1];
+        let after = &rest[start + 1..];
+        // `{{` and `}}` stand for literal braces, and so does a lone `}`
+        if brace == "}" || after.starts_with(brace) {
+            literal.push_str(brace);
+            rest = after.strip_prefix(brace).unwrap_or(after);
+            continue;
+        }
+        let end = after
+            .find('}')
+            .context(format!("Unclosed field generator in `{}`", text))?;
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+        }
+        pieces.push(Piece::Field(Field::parse(&after[..end])?));
+        rest = &after[end + 1..];
+    }
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        pieces.push(Piece::Literal(literal));
+    }
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Context, JsonTemplate, Random};
+    use serde_json::Value;
+
+    const CONTEXT: Context<'static> = Context {
+        client_id: "device-7",
+        sequence: 5,
+    };
+
+    fn render(template: &JsonTemplate, random: &mut Random) -> Value {
+        let mut buffer = vec![];
+        template.render_to(&CONTEXT, random, &mut buffer);
+        serde_json::from_slice(&buffer).unwrap()
+    }
+
+    #[test]
+    fn test_fields() -> anyhow::Result<()> {
+        let template = JsonTemplate::parse(
+            r#"{
+                "device": "{client_id}",
+                "seq": "{seq}",
+                "temperature": "{float:15..30}",
+                "humidity": "{int:-5..5}",
+                "ok": "{bool}",
+                "status": "{choice:ok|warn|error}",
+                "id": "{uuid}",
+                "tag": "{string:8}",
+                "at": "{timestamp}",
+                "unit": "celsius",
+                "readings": [1, "{int:1..1}"]
+            }"#,
+        )?;
+        let mut random = Random(0);
+        for _ in 0..100 {
+            let document = render(&template, &mut random);
+            assert_eq!("device-7", document["device"]);
+            assert_eq!(5, document["seq"]);
+            let temperature = document["temperature"].as_f64().unwrap();
+            assert!((15.0..=30.0).contains(&temperature));
+            let humidity = document["humidity"].as_i64().unwrap();
+            assert!((-5..=5).contains(&humidity));
+            assert!(document["ok"].is_boolean());
+            let status = document["status"].as_str().unwrap();
+            assert!(["ok", "warn", "error"].contains(&status));
+            assert_eq!(36, document["id"].as_str().unwrap().len());
+            assert_eq!(8, document["tag"].as_str().unwrap().len());
+            assert!(document["at"].as_u64().unwrap() > 0);
+            assert_eq!("celsius", document["unit"]);
+            assert_eq!(serde_json::json!([1, 1]), document["readings"]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_text() -> anyhow::Result<()> {
+        let template = JsonTemplate::parse(r#"["sensor-{int:3..3}/{client_id}", "{seq}s"]"#)?;
+        let document = render(&template, &mut Random(0));
+        assert_eq!(serde_json::json!(["sensor-3/device-7", "5s"]), document);
+        Ok(())
+    }
+
+    #[test]
+    fn test_escapes() -> anyhow::Result<()> {
+        let template = JsonTemplate::parse(r#"["{{seq}}", "{{{seq}}}", "a}b", "{{}}"]"#)?;
+        let document = render(&template, &mut Random(0));
+        assert_eq!(serde_json::json!(["{seq}", "{5}", "a}b", "{}"]), document);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() {
+        for template in [
+            "{",
+            r#""{int:3..1}""#,
+            r#""{float:a..b}""#,
+            r#""{string:x}""#,
+            r#""{temperature}""#,
+            r#""{seq""#,
+        ] {
+            assert!(JsonTemplate::parse(template).is_err(), "{}", template);
+        }
+    }
+}
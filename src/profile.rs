@@ -0,0 +1,304 @@
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Target publish rate over the course of a run, shared by all publishers.
+///
+/// A profile is a comma separated list of phases which run one after the other:
+/// - `rate:<r>/s [for <d>]`: publish at a constant rate;
+/// - `ramp:<from>-><to>/s over <d>`: change the rate linearly;
+/// - `hold <d>`: keep the rate reached so far;
+/// - `step +<r>/s every <d> [x<n>]`: raise (or lower, with `-`) the rate at the start of every
+///   interval, `n` times;
+/// - `spike:<r>/s for <d>`: publish at a different rate, then resume the previous one.
+///
+/// Durations take an `s`, `m` or `h` suffix. Only the last phase may leave its duration out, in
+/// which case it lasts until the end of the run; otherwise the rate reached at the end of the
+/// profile is kept.
+///
+/// For example `ramp:0->5000/s over 60s, hold 300s, step +1000/s every 60s` warms up for a
+/// minute, holds 5000 messages per second for five minutes, then adds 1000 messages per second
+/// every minute until the run ends.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    spec: String,
+    phases: Vec<Phase>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Phase {
+    /// The phase as written in the profile
+    label: String,
+    duration: Option<Duration>,
+    shape: Shape,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Shape {
+    Constant(f64),
+    Linear {
+        from: f64,
+        to: f64,
+    },
+    Step {
+        from: f64,
+        delta: f64,
+        every: Duration,
+    },
+}
+
+impl Phase {
+    fn rate_at(&self, offset: Duration) -> f64 {
+        let rate = match self.shape {
+            Shape::Constant(rate) => rate,
+            Shape::Linear { from, to } => match self.duration {
+                Some(duration) if !duration.is_zero() => {
+                    let progress = (offset.as_secs_f64() / duration.as_secs_f64()).min(1.0);
+                    from + (to - from) * progress
+                }
+                _ => to,
+            },
+            Shape::Step { from, delta, every } => {
+                let steps = (offset.as_nanos() / every.as_nanos()) as f64 + 1.0;
+                from + delta * steps
+            }
+        };
+        rate.max(0.0)
+    }
+
+    /// Rate at which the phase ends; only meaningful for phases with a duration.
+    fn final_rate(&self) -> f64 {
+        match (self.shape, self.duration) {
+            (Shape::Step { from, delta, every }, Some(duration)) => {
+                let steps = (duration.as_nanos() / every.as_nanos()) as f64;
+                (from + delta * steps).max(0.0)
+            }
+            (_, duration) => self.rate_at(duration.unwrap_or_default()),
+        }
+    }
+}
+
+impl Profile {
+    /// A profile publishing at `rate` messages per second for the whole run.
+    pub fn constant(rate: f64) -> Self {
+        let spec = format!("rate:{}/s", rate);
+        Self {
+            phases: vec![Phase {
+                label: spec.clone(),
+                duration: None,
+                shape: Shape::Constant(rate),
+            }],
+            spec,
+        }
+    }
+
+    /// Target rate in messages per second and label of the phase at `elapsed` into the run.
+    pub fn at(&self, elapsed: Duration) -> (f64, &str) {
+        let mut offset = elapsed;
+        for phase in &self.phases {
+            match phase.duration {
+                Some(duration) if offset >= duration => offset -= duration,
+                _ => return (phase.rate_at(offset), &phase.label),
+            }
+        }
+        // Profiles are never empty
+        let last = self.phases.last().unwrap();
+        (last.final_rate(), &last.label)
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut phases: Vec<Phase> = vec![];
+        // Rate that `hold` and `step` continue from
+        let mut current = 0.0;
+        for label in spec.split(',').map(str::trim) {
+            if phases.last().is_some_and(|phase| phase.duration.is_none()) {
+                return Err(format!("phase before `{}` never ends", label));
+            }
+
+            let (keyword, rest) = label
+                .split_once([':', ' '])
+                .map(|(keyword, rest)| (keyword, rest.trim()))
+                .unwrap_or((label, ""));
+            let (duration, shape) = match keyword {
+                "rate" => {
+                    let (rate, duration) = split_duration(rest, "for")?;
+                    let rate = parse_rate(rate)?;
+                    current = rate;
+                    (duration, Shape::Constant(rate))
+                }
+                "ramp" => {
+                    let (rates, duration) = split_duration(rest, "over")?;
+                    let (from, to) = rates
+                        .split_once("->")
+                        .ok_or_else(|| format!("expected `<from>-><to>/s`, got `{}`", rates))?;
+                    let (from, to) = (parse_rate(from)?, parse_rate(to)?);
+                    if duration.is_none() {
+                        return Err(format!("`{}` needs a duration", label));
+                    }
+                    current = to;
+                    (duration, Shape::Linear { from, to })
+                }
+                "hold" => {
+                    let duration = if rest.is_empty() {
+                        None
+                    } else {
+                        Some(parse_duration(rest)?)
+                    };
+                    (duration, Shape::Constant(current))
+                }
+                "step" => {
+                    let (delta, rest) = rest.split_once(" every ").ok_or_else(|| {
+                        format!("expected `step <delta>/s every <d>`, got `{}`", label)
+                    })?;
+                    let delta = parse_delta(delta)?;
+                    let (every, count) = match rest.trim().split_once(" x") {
+                        Some((every, count)) => {
+                            let count = count
+                                .trim()
+                                .parse::<u32>()
+                                .map_err(|_| format!("invalid step count `{}`", count))?;
+                            (parse_duration(every)?, Some(count))
+                        }
+                        None => (parse_duration(rest)?, None),
+                    };
+                    if every.is_zero() {
+                        return Err(format!("`{}` steps every 0s", label));
+                    }
+                    let phase = Phase {
+                        label: label.to_owned(),
+                        duration: count.map(|count| every * count),
+                        shape: Shape::Step {
+                            from: current,
+                            delta,
+                            every,
+                        },
+                    };
+                    current = phase.final_rate();
+                    (phase.duration, phase.shape)
+                }
+                "spike" => {
+                    let (rate, duration) = split_duration(rest, "for")?;
+                    if duration.is_none() {
+                        return Err(format!("`{}` needs a duration", label));
+                    }
+                    (duration, Shape::Constant(parse_rate(rate)?))
+                }
+                _ => return Err(format!("unknown phase `{}`", label)),
+            };
+            phases.push(Phase {
+                label: label.to_owned(),
+                duration,
+                shape,
+            });
+        }
+
+        Ok(Self {
+            spec: spec.to_owned(),
+            phases,
+        })
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.spec)
+    }
+}
+
+impl Serialize for Profile {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.spec)
+    }
+}
+
+/// Split `<value> <keyword> <duration>` into its value and optional duration.
+fn split_duration<'a>(s: &'a str, keyword: &str) -> Result<(&'a str, Option<Duration>), String> {
+    match s.split_once(&format!(" {} ", keyword)) {
+        Some((value, duration)) => Ok((value.trim(), Some(parse_duration(duration)?))),
+        None => Ok((s.trim(), None)),
+    }
+}
+
+/// Parse a rate such as `5000/s`; the unit may be left out.
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let s = s.trim();
+    match s.trim_end_matches("/s").parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate >= 0.0 => Ok(rate),
+        _ => Err(format!("invalid rate `{}`", s)),
+    }
+}
+
+/// Parse a signed rate change such as `+1000/s`.
+fn parse_delta(s: &str) -> Result<f64, String> {
+    let s = s.trim();
+    match s.trim_end_matches("/s").parse::<f64>() {
+        Ok(delta) if delta.is_finite() => Ok(delta),
+        _ => Err(format!("invalid rate change `{}`", s)),
+    }
+}
+
+/// Parse a duration such as `60s`, `5m` or `1h`.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (value, unit) = s.split_at(s.len() - s.ends_with(['s', 'm', 'h']) as usize);
+    let secs = match (value.parse::<f64>(), unit) {
+        (Ok(value), "s") => value,
+        (Ok(value), "m") => value * 60.0,
+        (Ok(value), "h") => value * 3600.0,
+        _ => return Err(format!("invalid duration `{}`, expected e.g. `60s`", s)),
+    };
+    Duration::try_from_secs_f64(secs).map_err(|_| format!("invalid duration `{}`", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Profile;
+    use std::time::Duration;
+
+    fn at(profile: &Profile, secs: u64) -> (f64, &str) {
+        profile.at(Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn test_at() -> Result<(), String> {
+        let profile: Profile =
+            "ramp:0->5000/s over 60s, hold 5m, step +1000/s every 60s".parse()?;
+        assert_eq!((0.0, "ramp:0->5000/s over 60s"), at(&profile, 0));
+        assert_eq!((2500.0, "ramp:0->5000/s over 60s"), at(&profile, 30));
+        assert_eq!((5000.0, "hold 5m"), at(&profile, 60));
+        assert_eq!((5000.0, "hold 5m"), at(&profile, 359));
+        assert_eq!((6000.0, "step +1000/s every 60s"), at(&profile, 360));
+        assert_eq!((6000.0, "step +1000/s every 60s"), at(&profile, 419));
+        assert_eq!((8000.0, "step +1000/s every 60s"), at(&profile, 480));
+
+        let profile: Profile =
+            "rate:100/s for 10s, spike:1000/s for 5s, step +100/s every 10s x2".parse()?;
+        assert_eq!((100.0, "rate:100/s for 10s"), at(&profile, 5));
+        assert_eq!((1000.0, "spike:1000/s for 5s"), at(&profile, 12));
+        assert_eq!((200.0, "step +100/s every 10s x2"), at(&profile, 15));
+        assert_eq!((300.0, "step +100/s every 10s x2"), at(&profile, 25));
+        // The final rate is kept once the profile is over
+        assert_eq!((300.0, "step +100/s every 10s x2"), at(&profile, 100));
+
+        let profile = Profile::constant(50.0);
+        assert_eq!((50.0, "rate:50/s"), at(&profile, 1000));
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_str_errors() {
+        assert!("hold, ramp:0->10/s over 1s".parse::<Profile>().is_err());
+        assert!("ramp:0->10/s".parse::<Profile>().is_err());
+        assert!("ramp:10/s over 1s".parse::<Profile>().is_err());
+        assert!("step +10/s every 0s".parse::<Profile>().is_err());
+        assert!("spike:10/s".parse::<Profile>().is_err());
+        assert!("rate:-1/s".parse::<Profile>().is_err());
+        assert!("hold 10 parsecs".parse::<Profile>().is_err());
+        assert!("burst:10/s".parse::<Profile>().is_err());
+    }
+}
@@ -0,0 +1,263 @@
+use crate::cli::{BenchOptions, Common, PubOptions, ReportFormat, SubOptions};
+use crate::state::{describe_reason_code, State};
+use crate::statistics::{LatencySummary, Statistics};
+use anyhow::Context;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Final report of a run, written by `--output`.
+#[derive(Debug, Serialize)]
+pub struct Report<'a> {
+    pub command: &'a str,
+    pub common: &'a Common,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pub_options: Option<&'a PubOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_options: Option<&'a SubOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bench_options: Option<&'a BenchOptions>,
+    pub elapsed_secs: f64,
+    pub totals: Totals,
+    pub throughput: Throughput,
+    /// Occurrences of each acknowledgement return code, e.g. `CONNACK 0x00`
+    pub reason_codes: BTreeMap<String, usize>,
+    /// Latency percentiles in microseconds, keyed by connect, ws_connect, publish and subscribe
+    pub latency_us: BTreeMap<&'static str, LatencySummary>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Totals {
+    pub attempted: usize,
+    pub connected: usize,
+    pub disconnected: usize,
+    pub published: usize,
+    pub publish_failures: usize,
+    pub received: usize,
+    /// Deliveries expected for the messages published, counted by `benchmark`
+    pub expected: usize,
+    /// Messages received per delivery expected, if any was
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delivery_ratio: Option<f64>,
+    pub lost: usize,
+    pub duplicated: usize,
+    pub out_of_order: usize,
+    /// Messages received that match none of the filters of their receiver
+    pub unmatched: usize,
+    /// Messages received per distinct message, if any message carried a header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fan_out: Option<f64>,
+}
+
+/// Messages per second over the whole run.
+#[derive(Debug, Serialize)]
+pub struct Throughput {
+    pub published: f64,
+    pub received: f64,
+}
+
+impl<'a> Report<'a> {
+    pub fn new(
+        command: &'a str,
+        common: &'a Common,
+        state: &State,
+        statistics: &Statistics,
+    ) -> Self {
+        let elapsed_secs = state.elapsed().as_secs_f64();
+        let totals = Totals {
+            attempted: state.attempted(),
+            connected: state.connected(),
+            disconnected: state.disconnected(),
+            published: state.published_total(),
+            publish_failures: state.publish_failures_total(),
+            received: state.received_total(),
+            expected: state.expected(),
+            delivery_ratio: state.delivery_ratio(),
+            lost: state.lost(),
+            duplicated: state.duplicated(),
+            out_of_order: state.out_of_order(),
+            unmatched: state.unmatched(),
+            fan_out: state.fan_out(),
+        };
+        let throughput = Throughput {
+            published: totals.published as f64 / elapsed_secs,
+            received: totals.received as f64 / elapsed_secs,
+        };
+
+        let reason_codes = state
+            .reason_codes()
+            .into_iter()
+            .map(|(ack, code, count)| {
+                let reason = describe_reason_code(code, common.mqtt_version);
+                (format!("{} {}", ack, reason), count)
+            })
+            .collect();
+
+        let latency_us = statistics
+            .latency
+            .hdr
+            .histograms()
+            .into_iter()
+            .filter_map(|(name, _, histogram)| {
+                LatencySummary::of(&histogram.snapshot()).map(|summary| (name, summary))
+            })
+            .collect();
+
+        Self {
+            command,
+            common,
+            pub_options: None,
+            sub_options: None,
+            bench_options: None,
+            elapsed_secs,
+            totals,
+            throughput,
+            reason_codes,
+            latency_us,
+        }
+    }
+
+    pub fn pub_options(mut self, pub_options: &'a PubOptions) -> Self {
+        self.pub_options = Some(pub_options);
+        self
+    }
+
+    pub fn sub_options(mut self, sub_options: &'a SubOptions) -> Self {
+        self.sub_options = Some(sub_options);
+        self
+    }
+
+    pub fn bench_options(mut self, bench_options: &'a BenchOptions) -> Self {
+        self.bench_options = Some(bench_options);
+        self
+    }
+
+    pub fn render(&self, format: ReportFormat) -> Result<String, anyhow::Error> {
+        match format {
+            ReportFormat::Json => {
+                serde_json::to_string_pretty(self).context("Failed to serialize report")
+            }
+            ReportFormat::Csv => {
+                let value = serde_json::to_value(self).context("Failed to serialize report")?;
+                let mut columns = vec![];
+                flatten("", &value, &mut columns);
+                let header = columns
+                    .iter()
+                    .map(|(key, _)| csv_field(key))
+                    .collect::<Vec<_>>();
+                let row = columns
+                    .iter()
+                    .map(|(_, value)| csv_field(value))
+                    .collect::<Vec<_>>();
+                Ok(format!("{}\n{}\n", header.join(","), row.join(",")))
+            }
+        }
+    }
+
+    pub fn write(&self, path: &Path, format: ReportFormat) -> Result<(), anyhow::Error> {
+        let content = self.render(format)?;
+        fs::write(path, content).context(format!("Failed to write report to {}", path.display()))
+    }
+}
+
+/// Flatten nested objects and arrays into columns whose names join the keys with `.`.
+fn flatten(prefix: &str, value: &Value, columns: &mut Vec<(String, String)>) {
+    let key_of = |key: &str| {
+        if prefix.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}.{}", prefix, key)
+        }
+    };
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                flatten(&key_of(key), value, columns);
+            }
+        }
+        Value::Array(values) => {
+            for (index, value) in values.iter().enumerate() {
+                flatten(&key_of(&index.to_string()), value, columns);
+            }
+        }
+        Value::Null => columns.push((prefix.to_owned(), String::new())),
+        Value::String(s) => columns.push((prefix.to_owned(), s.clone())),
+        _ => columns.push((prefix.to_owned(), value.to_string())),
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Report;
+    use crate::cli::{Cli, Commands, ReportFormat};
+    use crate::state::{Ack, State};
+    use crate::statistics::Statistics;
+    use clap::Parser;
+    use std::time::Duration;
+
+    #[test]
+    fn test_render() -> anyhow::Result<()> {
+        let cli = Cli::try_parse_from([
+            "mqtt-bench",
+            "pub",
+            "--host",
+            "localhost",
+            "-u",
+            "user",
+            "-P",
+            "secret",
+            "--user-property",
+            "a=b,c",
+            "--ws-header",
+            "Authorization=Bearer token",
+        ])?;
+        let Some(Commands::Pub {
+            common,
+            pub_options,
+        }) = cli.command
+        else {
+            panic!("Expected pub subcommand");
+        };
+
+        let state = State::new(common.total);
+        state.on_connected();
+        state.on_publish();
+        state.on_reason_code(Ack::ConnAck, 0);
+        let statistics = Statistics::new();
+        statistics
+            .latency
+            .recorder()
+            .publish(Duration::from_micros(1500));
+
+        let report = Report::new("pub", &common, &state, &statistics).pub_options(&pub_options);
+
+        let json: serde_json::Value = serde_json::from_str(&report.render(ReportFormat::Json)?)?;
+        assert_eq!("pub", json["command"]);
+        assert_eq!("localhost", json["common"]["host"]);
+        assert!(json["common"].get("password").is_none());
+        assert!(json["common"].get("ws_headers").is_none());
+        assert_eq!(1, json["totals"]["published"]);
+        assert_eq!(1, json["reason_codes"]["CONNACK 0x00"]);
+        assert_eq!(1, json["latency_us"]["publish"]["count"]);
+        assert!(json.get("sub_options").is_none());
+
+        let csv = report.render(ReportFormat::Csv)?;
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(2, lines.len());
+        assert!(lines[0].starts_with("command,common.host,"));
+        assert!(lines[0].contains(",pub_options.user_properties.0.0,"));
+        assert!(lines[1].starts_with("pub,localhost,"));
+        assert!(lines[1].contains(",\"b,c\","));
+        Ok(())
+    }
+}
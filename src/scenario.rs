@@ -0,0 +1,358 @@
+use crate::cli::{Cli, Commands, Common};
+use anyhow::{bail, Context};
+use clap::{CommandFactory, Parser};
+use std::fs;
+use std::path::Path;
+use toml::{Table, Value};
+
+/// Settings that apply to the run as a whole, which can only be set in `[defaults]`.
+const RUN_SETTINGS: [&str; 7] = [
+    "time",
+    "show_statistics",
+    "output",
+    "format",
+    "series",
+    "series_format",
+    "metrics_listen",
+];
+
+/// Subcommands that groups can run.
+const CLIENT_COMMANDS: [&str; 4] = ["connect", "pub", "sub", "benchmark"];
+
+/// A mixed workload of client groups running concurrently, described in TOML. YAML is not
+/// supported.
+///
+/// ```toml
+/// [defaults]
+/// host = "localhost"
+/// username = "user"
+/// password = "secret"
+/// time = 300
+///
+/// [[groups]]
+/// name = "idle"
+/// command = "connect"
+/// total = 10000
+///
+/// [[groups]]
+/// name = "sensors"
+/// command = "pub"
+/// total = 500
+/// interval = 1000
+/// topic = "sensors/%d"
+/// ```
+///
+/// Each group names the subcommand whose clients it runs and sets its options, using the long
+/// option names with `_` or `-`. Options in `[defaults]` apply to every group that has them
+/// unless the group overrides them, and must be options of at least one subcommand. Flags take
+/// `true`, repeatable options take an array. Unless a group sets `start_number`, its IDs follow
+/// those of the previous group so that client IDs do not clash.
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    /// Run-wide settings from `[defaults]`, along with the other options of the first group
+    pub common: Common,
+    pub groups: Vec<Group>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Group {
+    pub name: String,
+    /// Subcommand with the options of the group; never [`Commands::Run`]
+    pub command: Commands,
+}
+
+impl Group {
+    pub fn common(&self) -> &Common {
+        match &self.command {
+            Commands::Connect { common }
+            | Commands::Pub { common, .. }
+            | Commands::Sub { common, .. }
+            | Commands::Benchmark { common, .. } => common,
+            Commands::Run { .. } | Commands::Broker { .. } | Commands::Cert { .. } => {
+                unreachable!("Scenario groups only run clients")
+            }
+        }
+    }
+
+    fn common_mut(&mut self) -> &mut Common {
+        match &mut self.command {
+            Commands::Connect { common }
+            | Commands::Pub { common, .. }
+            | Commands::Sub { common, .. }
+            | Commands::Benchmark { common, .. } => common,
+            Commands::Run { .. } | Commands::Broker { .. } | Commands::Cert { .. } => {
+                unreachable!("Scenario groups only run clients")
+            }
+        }
+    }
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let content = fs::read_to_string(path)
+            .context(format!("Failed to read scenario {}", path.display()))?;
+        Self::parse(&content).context(format!("Invalid scenario {}", path.display()))
+    }
+
+    pub fn parse(content: &str) -> Result<Self, anyhow::Error> {
+        let mut table: Table = content.parse().context("Failed to parse TOML")?;
+        let defaults = match table.remove("defaults") {
+            Some(Value::Table(defaults)) => defaults,
+            Some(_) => bail!("`defaults` must be a table"),
+            None => Table::new(),
+        };
+        let groups = match table.remove("groups") {
+            Some(Value::Array(groups)) if !groups.is_empty() => groups,
+            _ => bail!("Expected at least one `[[groups]]`"),
+        };
+        if let Some(key) = table.keys().next() {
+            bail!("Unknown setting `{}`, expected `defaults` or `groups`", key);
+        }
+        // Defaults only reach the groups that have them, so no group would report a typo
+        let options = CLIENT_COMMANDS
+            .into_iter()
+            .map(options_of)
+            .collect::<Result<Vec<_>, _>>()?
+            .concat();
+        if let Some(key) = defaults
+            .keys()
+            .find(|key| !options.contains(&key.replace('_', "-")))
+        {
+            bail!("Unknown option `{}` in [defaults]", key);
+        }
+
+        // The first group starts at the `start_number` it has, possibly from [defaults]
+        let mut next_id = None;
+        let groups = groups
+            .into_iter()
+            .enumerate()
+            .map(|(index, group)| {
+                let Value::Table(mut settings) = group else {
+                    bail!("Group {} must be a table", index + 1);
+                };
+                let name = match settings.remove("name") {
+                    Some(Value::String(name)) => name,
+                    Some(_) => bail!("Name of group {} must be a string", index + 1),
+                    None => format!("group-{}", index + 1),
+                };
+                let command = match settings.remove("command") {
+                    Some(Value::String(command)) => command,
+                    _ => bail!("Group `{}` must set `command`", name),
+                };
+                if let Some(key) = settings
+                    .keys()
+                    .find(|key| RUN_SETTINGS.contains(&key.replace('-', "_").as_str()))
+                {
+                    bail!(
+                        "`{}` of group `{}` can only be set in [defaults]",
+                        key,
+                        name
+                    );
+                }
+
+                let mut group = Group {
+                    command: parse_command(&command, &defaults, &settings)
+                        .context(format!("Invalid group `{}`", name))?,
+                    name,
+                };
+                if let Some(next_id) = next_id {
+                    if !settings.contains_key("start_number")
+                        && !settings.contains_key("start-number")
+                    {
+                        group.common_mut().start_number = next_id;
+                    }
+                }
+                next_id = Some(group.common().start_number + group.common().total);
+                Ok(group)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Groups cannot override run-wide settings, so they all have those of [defaults]
+        let common = groups[0].common().clone();
+        Ok(Self { common, groups })
+    }
+
+    /// Number of clients across all groups.
+    pub fn total(&self) -> usize {
+        self.groups.iter().map(|group| group.common().total).sum()
+    }
+}
+
+/// Parse the settings of `defaults`, overridden by `settings`, as arguments of `command`.
+fn parse_command(
+    command: &str,
+    defaults: &Table,
+    settings: &Table,
+) -> Result<Commands, anyhow::Error> {
+    if ["run", "broker", "cert"].contains(&command) {
+        bail!("Scenario groups only run clients, not `{}`", command);
+    }
+
+    // Defaults may hold options of other subcommands, e.g. `topic` shared by publishers
+    let options = options_of(command)?;
+    let mut merged = defaults.clone();
+    merged.retain(|key, _| options.contains(&key.replace('_', "-")));
+
+    let mut args = vec!["mqtt-bench".to_owned(), command.to_owned()];
+    for (key, value) in settings {
+        // `topic_total` and `topic-total` are the same option
+        merged.retain(|existing, _| existing.replace('-', "_") != key.replace('-', "_"));
+        merged.insert(key.clone(), value.clone());
+    }
+    for (key, value) in &merged {
+        let flag = format!("--{}", key.replace('_', "-"));
+        let values = match value {
+            Value::Array(values) => values.clone(),
+            value => vec![value.clone()],
+        };
+        for value in values {
+            match value {
+                Value::Boolean(true) => args.push(flag.clone()),
+                Value::Boolean(false) => {}
+                Value::String(value) => args.extend([flag.clone(), value]),
+                Value::Integer(value) => args.extend([flag.clone(), value.to_string()]),
+                Value::Float(value) => args.extend([flag.clone(), value.to_string()]),
+                _ => bail!("Unsupported value of `{}`", key),
+            }
+        }
+    }
+
+    let cli = Cli::try_parse_from(args)?;
+    let mut command = cli.command.context("Missing command")?;
+    command.normalize()?;
+    Ok(command)
+}
+
+/// Long names of the options of `command`.
+fn options_of(command: &str) -> Result<Vec<String>, anyhow::Error> {
+    let options = Cli::command()
+        .find_subcommand(command)
+        .context(format!("Unknown command `{}`", command))?
+        .get_arguments()
+        .filter_map(|arg| arg.get_long())
+        .map(str::to_owned)
+        .collect();
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scenario;
+    use crate::cli::Commands;
+
+    const SCENARIO: &str = r#"
+[defaults]
+host = "localhost"
+username = "user"
+password = "secret"
+time = 300
+message_size = 128
+
+[[groups]]
+name = "idle"
+command = "connect"
+total = 10000
+
+[[groups]]
+name = "sensors"
+command = "pub"
+total = 500
+interval = 1000
+topic = "sensors/%d"
+user-property = ["site=a", "zone=%d"]
+
+[[groups]]
+command = "sub"
+total = 20
+topic = "sensors/+"
+start_number = 50000
+"#;
+
+    #[test]
+    fn test_parse() -> anyhow::Result<()> {
+        let scenario = Scenario::parse(SCENARIO)?;
+        assert_eq!(300, scenario.common.time);
+        assert_eq!(10_520, scenario.total());
+
+        let names = scenario
+            .groups
+            .iter()
+            .map(|group| group.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["idle", "sensors", "group-3"], names);
+
+        let starts = scenario
+            .groups
+            .iter()
+            .map(|group| group.common().start_number)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![0, 10_000, 50_000], starts);
+
+        let Commands::Pub {
+            common,
+            pub_options,
+        } = &scenario.groups[1].command
+        else {
+            panic!("Expected pub group");
+        };
+        assert_eq!("localhost", common.host);
+        assert_eq!(1000, common.interval);
+        assert_eq!(500, pub_options.topic_total);
+        assert_eq!(128, pub_options.message_size);
+        assert_eq!(2, pub_options.user_properties.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_empty_defaults() -> anyhow::Result<()> {
+        // Required options can be left to the groups
+        let scenario = Scenario::parse(
+            r#"
+[defaults]
+
+[[groups]]
+command = "connect"
+host = "broker-a"
+username = "user"
+password = "secret"
+total = 2
+start_number = 100
+
+[[groups]]
+command = "connect"
+host = "broker-b"
+username = "user"
+password = "secret"
+total = 3
+"#,
+        )?;
+        assert_eq!("broker-a", scenario.common.host);
+        assert_eq!(60, scenario.common.time);
+        let starts = scenario
+            .groups
+            .iter()
+            .map(|group| group.common().start_number)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![100, 102], starts);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let invalid = [
+            // Run-wide setting in a group
+            SCENARIO.replace("total = 20", "total = 20\ntime = 10"),
+            // Unknown command
+            SCENARIO.replace("command = \"sub\"", "command = \"run\""),
+            // Unknown option
+            SCENARIO.replace("interval = 1000", "intervals = 1000"),
+            // Unknown option in defaults, which no group would report
+            SCENARIO.replace("message_size = 128", "message_size = 128\nqoss = 1"),
+            // No groups
+            "[defaults]\nhost = \"localhost\"\n".to_owned(),
+        ];
+        for scenario in invalid {
+            assert!(Scenario::parse(&scenario).is_err(), "{}", scenario);
+        }
+    }
+}
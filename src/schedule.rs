@@ -0,0 +1,118 @@
+use crate::profile::Profile;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Interval at which the target rate is read from the profile while waiting for the next send.
+const RATE_RESOLUTION: Duration = Duration::from_millis(100);
+
+/// Open-loop send schedule of one publisher sharing the rate of a [`Profile`] with other
+/// publishers.
+///
+/// With `total` publishers and a target rate of `rate` messages per second, each publisher sends
+/// every `total / rate` seconds, and publishers are offset from one another so that sends are
+/// spread evenly. Send times are fixed up front and do not move when a publish is slow; a
+/// publisher falling behind sends immediately, and its latency is measured from the time the
+/// message was meant to go out, so stalls of the server are not hidden by fewer sends.
+#[derive(Debug, Clone)]
+pub(crate) struct Schedule {
+    profile: Arc<Profile>,
+    /// Start of the run, which the profile is relative to
+    start: Instant,
+    total: usize,
+    /// Time of the previous send
+    last: Instant,
+    /// Fraction of the next send accumulated at `last`
+    progress: f64,
+}
+
+impl Schedule {
+    /// Schedule of publisher `index` among `total` publishers, for a run started at `start`.
+    ///
+    /// Sends begin now, so publishers joining late do not catch up on sends they were never
+    /// around for.
+    pub(crate) fn new(profile: Arc<Profile>, start: Instant, index: usize, total: usize) -> Self {
+        let total = total.max(1);
+        Self {
+            profile,
+            start,
+            total,
+            last: Instant::now(),
+            progress: 1.0 - (index % total) as f64 / total as f64,
+        }
+    }
+
+    /// Sends per second of this publisher at `instant`.
+    fn rate_at(&self, instant: Instant) -> f64 {
+        let (rate, _) = self
+            .profile
+            .at(instant.saturating_duration_since(self.start));
+        rate / self.total as f64
+    }
+
+    /// Wait for the next send time and return it.
+    ///
+    /// The rate is integrated over time, so that a rate changing between two sends, or starting
+    /// from 0, is followed closely.
+    pub(crate) async fn tick(&mut self) -> Instant {
+        let mut at = self.last;
+        loop {
+            let rate = self.rate_at(at);
+            let needed = 1.0 - self.progress;
+            if rate > 0.0 && needed / rate <= RATE_RESOLUTION.as_secs_f64() {
+                let intended = at + Duration::from_secs_f64(needed / rate);
+                self.last = intended;
+                self.progress = 0.0;
+                tokio::time::sleep_until(intended.into()).await;
+                return intended;
+            }
+
+            self.progress += rate * RATE_RESOLUTION.as_secs_f64();
+            at += RATE_RESOLUTION;
+            if at > Instant::now() {
+                tokio::time::sleep_until(at.into()).await;
+            }
+        }
+    }
+}
+
+/// Wall clock time of `instant`, used to tag payloads with intended send times.
+pub(crate) fn system_time_of(instant: Instant) -> SystemTime {
+    let now = Instant::now();
+    if instant <= now {
+        SystemTime::now() - (now - instant)
+    } else {
+        SystemTime::now() + (instant - now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Schedule;
+    use crate::profile::Profile;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_tick() -> Result<(), String> {
+        // 4 publishers sharing 1000 messages per second send every 4ms, 1ms apart
+        let profile = Arc::new(Profile::constant(1000.0));
+        let start = Instant::now();
+        let mut first = Schedule::new(Arc::clone(&profile), start, 0, 4);
+        let mut second = Schedule::new(profile, start, 1, 4);
+        let a = first.tick().await;
+        let b = second.tick().await;
+        let close = |actual: Duration, expected: Duration| {
+            actual.abs_diff(expected) < Duration::from_micros(500)
+        };
+        assert!(close(b - a, Duration::from_millis(1)));
+        assert!(close(first.tick().await - a, Duration::from_millis(4)));
+        assert!(close(second.tick().await - b, Duration::from_millis(4)));
+
+        // Nothing is sent while the target rate is 0
+        let profile = Arc::new("rate:0/s for 1s, rate:1000/s".parse::<Profile>()?);
+        let start = Instant::now() - Duration::from_millis(950);
+        let mut schedule = Schedule::new(profile, start, 0, 1);
+        assert!(schedule.tick().await - start >= Duration::from_secs(1));
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::{BTreeSet, HashMap};
+
+/// Upper bound of missing sequence numbers remembered per stream.
+///
+/// Older gaps beyond this are still counted as lost, but a late arrival filling one of them is
+/// reported as a duplicate.
+const MAX_MISSING: usize = 65_536;
+
+/// Classification of a received message against the stream it belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Delivery {
+    /// The message is the next one expected, or the first one seen for the stream.
+    InOrder,
+    /// The message skipped ahead; the given number of preceding messages are now missing.
+    Gap(u64),
+    /// The message filled a previously detected gap.
+    OutOfOrder,
+    /// The message was received before.
+    Duplicate,
+}
+
+/// Sequence bookkeeping of a single publisher/topic stream.
+#[derive(Debug, Default)]
+struct Stream {
+    lowest: u64,
+    highest: Option<u64>,
+    missing: BTreeSet<u64>,
+}
+
+impl Stream {
+    fn on_sequence(&mut self, sequence: u64) -> Delivery {
+        let Some(highest) = self.highest else {
+            // Subscribers may join a stream late, so the first message seen is the baseline.
+            self.lowest = sequence;
+            self.highest = Some(sequence);
+            return Delivery::InOrder;
+        };
+        self.lowest = self.lowest.min(sequence);
+
+        if sequence == highest + 1 {
+            self.highest = Some(sequence);
+            return Delivery::InOrder;
+        }
+
+        if sequence > highest {
+            let skipped = sequence - highest - 1;
+            let remembered = skipped.min(MAX_MISSING as u64);
+            self.missing.extend(sequence - remembered..sequence);
+            while self.missing.len() > MAX_MISSING {
+                self.missing.pop_first();
+            }
+            self.highest = Some(sequence);
+            return Delivery::Gap(skipped);
+        }
+
+        if self.missing.remove(&sequence) {
+            Delivery::OutOfOrder
+        } else {
+            Delivery::Duplicate
+        }
+    }
+}
+
+/// Tracks the sequence numbers received by one client, per publisher and topic.
+#[derive(Debug, Default)]
+pub(crate) struct SequenceTracker {
+    streams: HashMap<String, HashMap<u64, Stream>>,
+    /// Number of messages tracked
+    received: usize,
+}
+
+impl SequenceTracker {
+    pub(crate) fn on_message(&mut self, topic: &str, publisher: u64, sequence: u64) -> Delivery {
+        self.received += 1;
+        let publishers = match self.streams.get_mut(topic) {
+            Some(publishers) => publishers,
+            None => self.streams.entry(topic.to_owned()).or_default(),
+        };
+        publishers
+            .entry(publisher)
+            .or_default()
+            .on_sequence(sequence)
+    }
+
+    pub(crate) fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Lowest and highest sequence numbers seen of each publisher, per topic.
+    pub(crate) fn ranges(&self) -> impl Iterator<Item = (&str, u64, (u64, u64))> {
+        self.streams.iter().flat_map(|(topic, publishers)| {
+            publishers.iter().filter_map(|(publisher, stream)| {
+                let highest = stream.highest?;
+                Some((topic.as_str(), *publisher, (stream.lowest, highest)))
+            })
+        })
+    }
+}
+
+/// Number of distinct messages seen by `trackers`, counting every sequence number between the
+/// lowest and the highest seen of a publisher on a topic, whether received or lost.
+pub(crate) fn distinct_messages<'a>(trackers: impl Iterator<Item = &'a SequenceTracker>) -> u64 {
+    let mut ranges: HashMap<(&str, u64), (u64, u64)> = HashMap::new();
+    for tracker in trackers {
+        for (topic, publisher, (lowest, highest)) in tracker.ranges() {
+            ranges
+                .entry((topic, publisher))
+                .and_modify(|(low, high)| {
+                    *low = (*low).min(lowest);
+                    *high = (*high).max(highest);
+                })
+                .or_insert((lowest, highest));
+        }
+    }
+    ranges.values().map(|(low, high)| high - low + 1).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{distinct_messages, Delivery, SequenceTracker};
+
+    #[test]
+    fn test_on_message() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(Delivery::InOrder, tracker.on_message("a", 0, 5));
+        assert_eq!(Delivery::InOrder, tracker.on_message("a", 0, 6));
+        assert_eq!(Delivery::Gap(2), tracker.on_message("a", 0, 9));
+        assert_eq!(Delivery::OutOfOrder, tracker.on_message("a", 0, 7));
+        assert_eq!(Delivery::Duplicate, tracker.on_message("a", 0, 7));
+        assert_eq!(Delivery::Duplicate, tracker.on_message("a", 0, 9));
+        assert_eq!(Delivery::Duplicate, tracker.on_message("a", 0, 2));
+
+        // Streams are independent per topic and publisher
+        assert_eq!(Delivery::InOrder, tracker.on_message("b", 0, 0));
+        assert_eq!(Delivery::InOrder, tracker.on_message("a", 1, 0));
+        assert_eq!(Delivery::InOrder, tracker.on_message("a", 0, 10));
+    }
+
+    #[test]
+    fn test_distinct_messages() {
+        let mut first = SequenceTracker::default();
+        for sequence in [3, 4, 6] {
+            first.on_message("a", 0, sequence);
+        }
+        first.on_message("b", 0, 0);
+        let mut second = SequenceTracker::default();
+        for sequence in [5, 6, 7, 2] {
+            second.on_message("a", 0, sequence);
+        }
+        assert_eq!(4, first.received());
+        // 2 to 7 on `a`, and 0 on `b`
+        assert_eq!(7, distinct_messages([&first, &second].into_iter()));
+    }
+}
@@ -0,0 +1,184 @@
+use crate::cli::SeriesFormat;
+use crate::statistics::LatencySummary;
+use anyhow::Context;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Latency types included in each sample, in column order.
+const LATENCY_TYPES: [&str; 4] = ["connect", "ws_connect", "publish", "subscribe"];
+
+/// Fields of [`LatencySummary`], in column order.
+const LATENCY_FIELDS: [&str; 10] = [
+    "count", "min", "mean", "p50", "p90", "p95", "p99", "p999", "p9999", "max",
+];
+
+/// Counters and latencies of one second of a run.
+#[derive(Debug, Clone, Serialize)]
+pub struct Sample {
+    /// Seconds since the run started
+    pub elapsed_secs: f64,
+    /// Target publish rate of the rate profile, if any
+    pub target_rate: Option<f64>,
+    /// Phase of the rate profile, if any
+    pub phase: Option<String>,
+    pub attempted: usize,
+    pub connected: usize,
+    pub disconnected: usize,
+    /// Messages published in the interval
+    pub published: usize,
+    /// Failed publishes in the interval
+    pub publish_failures: usize,
+    /// Messages received in the interval
+    pub received: usize,
+    /// Latency percentiles in microseconds over the interval, keyed by connect, ws_connect, publish
+    /// and subscribe
+    pub latency_us: BTreeMap<&'static str, LatencySummary>,
+}
+
+/// Render the samples as CSV, one row per sample, or as JSON Lines, one object per sample.
+pub fn render(samples: &[Sample], format: SeriesFormat) -> Result<String, anyhow::Error> {
+    let mut content = String::new();
+    match format {
+        SeriesFormat::Csv => {
+            let mut header = vec![
+                "elapsed_secs".to_owned(),
+                "target_rate".to_owned(),
+                "phase".to_owned(),
+                "attempted".to_owned(),
+                "connected".to_owned(),
+                "disconnected".to_owned(),
+                "published".to_owned(),
+                "publish_failures".to_owned(),
+                "received".to_owned(),
+            ];
+            for kind in LATENCY_TYPES {
+                for field in LATENCY_FIELDS {
+                    header.push(format!("latency_us.{}.{}", kind, field));
+                }
+            }
+            content.push_str(&header.join(","));
+            content.push('\n');
+
+            for sample in samples {
+                let mut row = vec![
+                    format!("{:.3}", sample.elapsed_secs),
+                    sample
+                        .target_rate
+                        .map_or(String::new(), |rate| format!("{:.3}", rate)),
+                    sample.phase.as_deref().map_or(String::new(), csv_field),
+                    sample.attempted.to_string(),
+                    sample.connected.to_string(),
+                    sample.disconnected.to_string(),
+                    sample.published.to_string(),
+                    sample.publish_failures.to_string(),
+                    sample.received.to_string(),
+                ];
+                for kind in LATENCY_TYPES {
+                    // Types without latencies in the interval leave their columns empty
+                    let summary = match sample.latency_us.get(kind) {
+                        Some(summary) => serde_json::to_value(summary)
+                            .context("Failed to serialize latency summary")?,
+                        None => Value::Null,
+                    };
+                    for field in LATENCY_FIELDS {
+                        row.push(summary.get(field).map_or(String::new(), Value::to_string));
+                    }
+                }
+                content.push_str(&row.join(","));
+                content.push('\n');
+            }
+        }
+        SeriesFormat::Jsonl => {
+            for sample in samples {
+                let line = serde_json::to_string(sample).context("Failed to serialize sample")?;
+                content.push_str(&line);
+                content.push('\n');
+            }
+        }
+    }
+    Ok(content)
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+pub fn write(samples: &[Sample], path: &Path, format: SeriesFormat) -> Result<(), anyhow::Error> {
+    let content = render(samples, format)?;
+    fs::write(path, content).context(format!("Failed to write time series to {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, Sample};
+    use crate::cli::SeriesFormat;
+    use crate::statistics::LatencySummary;
+    use std::collections::BTreeMap;
+
+    fn sample(elapsed_secs: f64, latency_us: BTreeMap<&'static str, LatencySummary>) -> Sample {
+        Sample {
+            elapsed_secs,
+            target_rate: Some(10.0),
+            phase: Some("hold 60s".to_owned()),
+            attempted: 2,
+            connected: 2,
+            disconnected: 0,
+            published: 10,
+            publish_failures: 1,
+            received: 9,
+            latency_us,
+        }
+    }
+
+    #[test]
+    fn test_render() -> anyhow::Result<()> {
+        let summary = LatencySummary {
+            count: 10,
+            min: 100,
+            mean: 150.0,
+            p50: 140,
+            p90: 180,
+            p95: 190,
+            p99: 200,
+            p999: 200,
+            p9999: 200,
+            max: 200,
+        };
+        let samples = vec![
+            sample(1.0, BTreeMap::from([("publish", summary)])),
+            sample(2.0, BTreeMap::new()),
+        ];
+
+        let csv = render(&samples, SeriesFormat::Csv)?;
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(3, lines.len());
+        let header = lines[0].split(',').collect::<Vec<_>>();
+        let first = lines[1].split(',').collect::<Vec<_>>();
+        let second = lines[2].split(',').collect::<Vec<_>>();
+        assert_eq!(header.len(), first.len());
+        assert_eq!(header.len(), second.len());
+        let p99 = header
+            .iter()
+            .position(|&column| column == "latency_us.publish.p99")
+            .unwrap();
+        assert_eq!("200", first[p99]);
+        assert_eq!("", second[p99]);
+        assert_eq!("2.000", second[0]);
+        assert_eq!("hold 60s", second[2]);
+
+        let jsonl = render(&samples, SeriesFormat::Jsonl)?;
+        let lines = jsonl.lines().collect::<Vec<_>>();
+        assert_eq!(2, lines.len());
+        let first: serde_json::Value = serde_json::from_str(lines[0])?;
+        assert_eq!(10, first["published"]);
+        assert_eq!(200, first["latency_us"]["publish"]["p99"]);
+        Ok(())
+    }
+}
@@ -1,10 +1,60 @@
+use crate::cli::MqttVersion;
+use crate::profile::Profile;
+use crate::sequence::{distinct_messages, Delivery, SequenceTracker};
+use crate::series::Sample;
+use crate::statistics::{HdrLatency, IntervalLatency};
 use log::{debug, info};
+use paho_mqtt as mqtt;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Receiver;
 use tokio::time::sleep;
 
+/// Acknowledgement packets whose reason codes are tracked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Ack {
+    ConnAck,
+    PubAck,
+    SubAck,
+}
+
+impl fmt::Display for Ack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ack::ConnAck => write!(f, "CONNACK"),
+            Ack::PubAck => write!(f, "PUBACK"),
+            Ack::SubAck => write!(f, "SUBACK"),
+        }
+    }
+}
+
+/// Integer type the Paho bindings use for reason codes.
+#[cfg(target_env = "msvc")]
+type RawReasonCode = std::os::raw::c_int;
+#[cfg(not(target_env = "msvc"))]
+type RawReasonCode = std::os::raw::c_uint;
+
+/// Describe a return code recorded through [`State::on_reason_code`].
+///
+/// Non-negative values are return codes sent by the server, negative values are errors raised by
+/// the Paho client itself, e.g. a timeout or a dropped connection. Only MQTT 5 assigns names to
+/// the server side codes.
+pub fn describe_reason_code(code: i32, mqtt_version: MqttVersion) -> String {
+    if code < 0 {
+        return mqtt::error_message(code).to_owned();
+    }
+    if mqtt_version.is_v5() {
+        let reason = mqtt::ReasonCode::from(code as RawReasonCode);
+        if reason != mqtt::ReasonCode::MqttppV3Code {
+            return format!("{} ({:#04x})", reason, code);
+        }
+    }
+    format!("{:#04x}", code)
+}
+
 pub struct State {
     /// Number of CONNECT attempts
     attempted: AtomicUsize,
@@ -15,9 +65,31 @@ pub struct State {
     stopped: AtomicBool,
     published: AtomicUsize,
     pub_failures: AtomicUsize,
+    pub_failures_total: AtomicUsize,
     published_total: AtomicUsize,
     received: AtomicUsize,
     received_total: AtomicUsize,
+    /// Number of deliveries expected for the messages published, one per matching subscriber
+    expected: AtomicUsize,
+    /// Number of messages missing from the sequence of their publisher
+    lost: AtomicUsize,
+    /// Number of messages received more than once
+    duplicated: AtomicUsize,
+    /// Number of messages received after a later message of the same publisher
+    out_of_order: AtomicUsize,
+    /// Number of messages received that match none of the filters of their receiver
+    unmatched: AtomicUsize,
+    /// Sequence numbers received by each client, for counting distinct messages
+    trackers: Mutex<Vec<Arc<Mutex<SequenceTracker>>>>,
+    /// Occurrences of each return code, keyed by the acknowledgement that carried it
+    reason_codes: Mutex<BTreeMap<(Ack, i32), usize>>,
+    /// Number of messages received by each client, keyed by client ID
+    received_by_client: Mutex<HashMap<String, Arc<AtomicUsize>>>,
+    /// Per-second samples recorded by [`print_stats`]
+    samples: Mutex<Vec<Sample>>,
+    /// Target rates publishers follow, one per group of publishers with a profile
+    profiles: Mutex<Vec<Arc<Profile>>>,
+    started: Instant,
 }
 
 impl State {
@@ -29,9 +101,21 @@ impl State {
             stopped: AtomicBool::new(false),
             published: AtomicUsize::new(0),
             pub_failures: AtomicUsize::new(0),
+            pub_failures_total: AtomicUsize::new(0),
             published_total: AtomicUsize::new(0),
             received: AtomicUsize::new(0),
             received_total: AtomicUsize::new(0),
+            expected: AtomicUsize::new(0),
+            lost: AtomicUsize::new(0),
+            duplicated: AtomicUsize::new(0),
+            out_of_order: AtomicUsize::new(0),
+            unmatched: AtomicUsize::new(0),
+            trackers: Mutex::new(Vec::new()),
+            reason_codes: Mutex::new(BTreeMap::new()),
+            received_by_client: Mutex::new(HashMap::new()),
+            samples: Mutex::new(Vec::new()),
+            profiles: Mutex::new(Vec::new()),
+            started: Instant::now(),
         };
         Arc::new(state)
     }
@@ -69,6 +153,10 @@ impl State {
         self.published_total.fetch_add(1, Ordering::Relaxed);
     }
 
+    pub fn published_total(&self) -> usize {
+        self.published_total.load(Ordering::Relaxed)
+    }
+
     pub fn publish_success_count(&self) -> usize {
         let count = self.published.load(Ordering::Relaxed);
         if count > 0 {
@@ -79,6 +167,11 @@ impl State {
 
     pub fn on_publish_failure(&self) {
         self.pub_failures.fetch_add(1, Ordering::Relaxed);
+        self.pub_failures_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn publish_failures_total(&self) -> usize {
+        self.pub_failures_total.load(Ordering::Relaxed)
     }
 
     pub fn publish_failure_count(&self) -> usize {
@@ -102,6 +195,193 @@ impl State {
         rcv
     }
 
+    pub fn received_total(&self) -> usize {
+        self.received_total.load(Ordering::Relaxed)
+    }
+
+    /// Count the deliveries a message published is expected to make.
+    pub fn on_expected(&self, deliveries: usize) {
+        self.expected.fetch_add(deliveries, Ordering::Relaxed);
+    }
+
+    pub fn expected(&self) -> usize {
+        self.expected.load(Ordering::Relaxed)
+    }
+
+    /// Messages received per delivery expected, if any was.
+    pub fn delivery_ratio(&self) -> Option<f64> {
+        let expected = self.expected();
+        (expected > 0).then(|| self.received_total() as f64 / expected as f64)
+    }
+
+    pub(crate) fn on_delivery(&self, delivery: Delivery) {
+        match delivery {
+            Delivery::InOrder => {}
+            Delivery::Gap(skipped) => {
+                self.lost.fetch_add(skipped as usize, Ordering::Relaxed);
+            }
+            Delivery::OutOfOrder => {
+                // The message was counted as lost when the gap was detected
+                self.lost.fetch_sub(1, Ordering::Relaxed);
+                self.out_of_order.fetch_add(1, Ordering::Relaxed);
+            }
+            Delivery::Duplicate => {
+                self.duplicated.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn lost(&self) -> usize {
+        self.lost.load(Ordering::Relaxed)
+    }
+
+    pub fn duplicated(&self) -> usize {
+        self.duplicated.load(Ordering::Relaxed)
+    }
+
+    pub fn out_of_order(&self) -> usize {
+        self.out_of_order.load(Ordering::Relaxed)
+    }
+
+    pub fn on_unmatched(&self) {
+        self.unmatched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn unmatched(&self) -> usize {
+        self.unmatched.load(Ordering::Relaxed)
+    }
+
+    /// Register a client whose received sequence numbers are tracked.
+    pub(crate) fn register_tracker(&self) -> Arc<Mutex<SequenceTracker>> {
+        let tracker = Arc::new(Mutex::new(SequenceTracker::default()));
+        self.trackers.lock().unwrap().push(Arc::clone(&tracker));
+        tracker
+    }
+
+    /// Number of messages received per distinct message, e.g. 3 if every message matched the
+    /// filters of 3 clients, over the messages carrying a header.
+    pub fn fan_out(&self) -> Option<f64> {
+        let trackers = self.trackers.lock().unwrap();
+        let trackers = trackers
+            .iter()
+            .map(|tracker| tracker.lock().unwrap())
+            .collect::<Vec<_>>();
+        let received = trackers
+            .iter()
+            .map(|tracker| tracker.received())
+            .sum::<usize>();
+        let distinct = distinct_messages(trackers.iter().map(|tracker| &**tracker));
+        (distinct > 0).then(|| received as f64 / distinct as f64)
+    }
+
+    pub fn show_delivery(&self) {
+        if 0 == self.received_total() {
+            return;
+        }
+        info!(
+            "Delivery Summary[Received: {}, Lost: {}, Duplicated: {}, Out of order: {}]",
+            self.received_total(),
+            self.lost(),
+            self.duplicated(),
+            self.out_of_order()
+        );
+        if let Some(ratio) = self.delivery_ratio() {
+            info!(
+                "Delivery Ratio[Expected: {}, Received: {}, Ratio: {:.2}%]",
+                self.expected(),
+                self.received_total(),
+                ratio * 100.0
+            );
+        }
+        if let Some(fan_out) = self.fan_out() {
+            info!(
+                "Matching Summary[Unmatched: {}, Fan-out: {:.2}x]",
+                self.unmatched(),
+                fan_out
+            );
+        }
+    }
+
+    /// Register a client whose received messages are counted separately.
+    ///
+    /// The returned counter is shared with the state and should be incremented by the client on
+    /// each received message, in addition to [`State::on_receive`].
+    pub fn register_receiver(&self, client_id: &str) -> Arc<AtomicUsize> {
+        let mut receivers = self.received_by_client.lock().unwrap();
+        Arc::clone(receivers.entry(client_id.to_owned()).or_default())
+    }
+
+    /// Number of messages received by the given client so far.
+    pub fn received_by(&self, client_id: &str) -> usize {
+        self.received_by_client
+            .lock()
+            .unwrap()
+            .get(client_id)
+            .map_or(0, |received| received.load(Ordering::Relaxed))
+    }
+
+    pub fn on_reason_code(&self, ack: Ack, code: i32) {
+        let mut reason_codes = self.reason_codes.lock().unwrap();
+        *reason_codes.entry((ack, code)).or_insert(0) += 1;
+    }
+
+    pub fn reason_codes(&self) -> Vec<(Ack, i32, usize)> {
+        self.reason_codes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&(ack, code), &count)| (ack, code, count))
+            .collect()
+    }
+
+    pub fn show_reason_codes(&self, mqtt_version: MqttVersion) {
+        for (ack, code, count) in self.reason_codes() {
+            info!(
+                "{} {}: {}",
+                ack,
+                describe_reason_code(code, mqtt_version),
+                count
+            );
+        }
+    }
+
+    pub fn on_sample(&self, sample: Sample) {
+        self.samples.lock().unwrap().push(sample);
+    }
+
+    /// Per-second samples recorded so far.
+    pub fn samples(&self) -> Vec<Sample> {
+        self.samples.lock().unwrap().clone()
+    }
+
+    /// Follow the rate profile of a group of publishers, e.g. one of several in a scenario.
+    pub fn add_profile(&self, profile: Arc<Profile>) {
+        self.profiles.lock().unwrap().push(profile);
+    }
+
+    /// Target rate and phase of the rate profiles at this point of the run, if there are any:
+    /// the sum of their rates, and their phases joined by ` + `.
+    pub fn target(&self) -> Option<(f64, String)> {
+        let profiles = self.profiles.lock().unwrap();
+        if profiles.is_empty() {
+            return None;
+        }
+        let elapsed = self.elapsed();
+        let (rates, phases): (Vec<_>, Vec<_>) =
+            profiles.iter().map(|profile| profile.at(elapsed)).unzip();
+        Some((rates.iter().sum(), phases.join(" + ")))
+    }
+
+    /// Time the run started.
+    pub fn started(&self) -> Instant {
+        self.started
+    }
+
+    /// Time since the run started.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
     pub fn stop_flag(&self) -> &AtomicBool {
         &self.stopped
     }
@@ -118,9 +398,10 @@ pub fn ctrl_c(state: Arc<State>) {
         });
 }
 
-pub fn print_stats(state: Arc<State>, mut rx: Receiver<()>) {
+pub fn print_stats(state: Arc<State>, hdr: Arc<HdrLatency>, mut rx: Receiver<()>) {
     let _ = tokio::task::Builder::new().name("stats_printer").spawn({
         async move {
+            let mut interval_latency = IntervalLatency::new(hdr);
             loop {
                 tokio::select! {
                     _ = rx.recv() => {
@@ -128,9 +409,26 @@ pub fn print_stats(state: Arc<State>, mut rx: Receiver<()>) {
                         break;
                     }
                     _ = sleep(Duration::from_secs(1)) => {
+                        let target = state.target();
+                        let sample = Sample {
+                            elapsed_secs: state.elapsed().as_secs_f64(),
+                            target_rate: target.as_ref().map(|(rate, _)| *rate),
+                            phase: target.map(|(_, phase)| phase),
+                            attempted: state.attempted(),
+                            connected: state.connected(),
+                            disconnected: state.disconnected(),
+                            published: state.publish_success_count(),
+                            publish_failures: state.publish_failure_count(),
+                            received: state.received(),
+                            latency_us: interval_latency.take(),
+                        };
                         info!("Client Summary[Attempted:{}, Connected: {}, Disconnected: {}] Publish: [Success: {}, Failure: {}], Subscribed: {}", 
-                            state.attempted(), state.connected(), state.disconnected(),
-                            state.publish_success_count(), state.publish_failure_count(), state.received());
+                            sample.attempted, sample.connected, sample.disconnected,
+                            sample.published, sample.publish_failures, sample.received);
+                        if let (Some(rate), Some(phase)) = (sample.target_rate, &sample.phase) {
+                            info!("Target Rate[{:.0}/s, Phase: {}]", rate, phase);
+                        }
+                        state.on_sample(sample);
                         if state.stopped() {
                             break;
                         }
@@ -140,3 +438,63 @@ pub fn print_stats(state: Arc<State>, mut rx: Receiver<()>) {
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{describe_reason_code, State};
+    use crate::cli::MqttVersion;
+    use crate::profile::Profile;
+    use crate::sequence::Delivery;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_on_delivery() {
+        let state = State::new(1);
+        state.on_delivery(Delivery::InOrder);
+        state.on_delivery(Delivery::Gap(3));
+        state.on_delivery(Delivery::OutOfOrder);
+        state.on_delivery(Delivery::Duplicate);
+        assert_eq!(2, state.lost());
+        assert_eq!(1, state.out_of_order());
+        assert_eq!(1, state.duplicated());
+    }
+
+    #[test]
+    fn test_fan_out() {
+        let state = State::new(3);
+        assert_eq!(None, state.fan_out());
+        let trackers = (0..3).map(|_| state.register_tracker()).collect::<Vec<_>>();
+        // All three clients receive the 4 messages of publisher 1 on `a`
+        for tracker in &trackers {
+            let mut tracker = tracker.lock().unwrap();
+            for sequence in 0..4 {
+                tracker.on_message("a", 1, sequence);
+            }
+        }
+        // Only the first one receives the 2 messages of publisher 2 on `b`
+        for sequence in 0..2 {
+            trackers[0].lock().unwrap().on_message("b", 2, sequence);
+        }
+        // 14 messages received out of 6 distinct ones
+        assert_eq!(Some(14.0 / 6.0), state.fan_out());
+    }
+
+    #[test]
+    fn test_target() {
+        let state = State::new(1);
+        assert_eq!(None, state.target());
+        state.add_profile(Arc::new(Profile::constant(100.0)));
+        state.add_profile(Arc::new(Profile::constant(50.0)));
+        let (rate, phase) = state.target().unwrap();
+        assert_eq!(150.0, rate);
+        assert_eq!(2, phase.split(" + ").count());
+    }
+
+    #[test]
+    fn test_describe_reason_code() {
+        assert_eq!("0x87", describe_reason_code(0x87, MqttVersion::V3_1_1));
+        assert!(describe_reason_code(0x87, MqttVersion::V5).ends_with("(0x87)"));
+        assert_eq!("0xff", describe_reason_code(0xff, MqttVersion::V5));
+        assert_eq!("General failure", describe_reason_code(-1, MqttVersion::V5));
+    }
+}
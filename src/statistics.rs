@@ -3,12 +3,270 @@ use prometheus::{
     labels, linear_buckets, proto::MetricType, Encoder, Histogram, HistogramOpts, Registry,
     TextEncoder,
 };
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Number of shards of each HDR histogram.
+///
+/// Clients are spread across the shards so that they rarely contend for the same lock, and the
+/// shards are merged when read. This keeps memory bounded no matter how many clients there are.
+const HDR_SHARDS: usize = 64;
+
+/// Number of significant decimal digits kept by HDR histograms.
+const HDR_SIGNIFICANT_FIGURES: u8 = 3;
 
 #[derive(Debug, Clone)]
 pub struct LatencyHistogram {
     pub connect: Histogram,
+    /// Connect latency of clients connecting over WebSocket
+    pub ws_connect: Histogram,
     pub publish: Histogram,
     pub subscribe: Histogram,
+    pub hdr: Arc<HdrLatency>,
+    next_shard: Arc<AtomicUsize>,
+}
+
+impl LatencyHistogram {
+    /// Create a recorder for a new client.
+    pub fn recorder(&self) -> LatencyRecorder {
+        LatencyRecorder {
+            histogram: self.clone(),
+            shard: self.next_shard.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+/// Records the latencies of a single client into both the Prometheus and the HDR histograms.
+#[derive(Debug, Clone)]
+pub struct LatencyRecorder {
+    histogram: LatencyHistogram,
+    shard: usize,
+}
+
+impl LatencyRecorder {
+    pub fn connect(&self, latency: Duration) {
+        self.histogram.connect.observe(as_millis_f64(latency));
+        self.histogram.hdr.connect.record(self.shard, latency);
+    }
+
+    pub fn ws_connect(&self, latency: Duration) {
+        self.histogram.ws_connect.observe(as_millis_f64(latency));
+        self.histogram.hdr.ws_connect.record(self.shard, latency);
+    }
+
+    pub fn publish(&self, latency: Duration) {
+        self.histogram.publish.observe(as_millis_f64(latency));
+        self.histogram.hdr.publish.record(self.shard, latency);
+    }
+
+    pub fn subscribe(&self, latency: Duration) {
+        self.histogram.subscribe.observe(as_millis_f64(latency));
+        self.histogram.hdr.subscribe.record(self.shard, latency);
+    }
+}
+
+fn as_millis_f64(latency: Duration) -> f64 {
+    latency.as_secs_f64() * 1000.0
+}
+
+/// High dynamic range histogram of latencies in microseconds.
+#[derive(Debug)]
+pub struct HdrHistogram {
+    shards: Vec<Mutex<hdrhistogram::Histogram<u64>>>,
+}
+
+impl HdrHistogram {
+    fn new() -> Self {
+        let shards = (0..HDR_SHARDS)
+            .map(|_| Mutex::new(new_hdr_histogram()))
+            .collect();
+        Self { shards }
+    }
+
+    pub fn record(&self, shard: usize, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        let mut histogram = self.shards[shard % self.shards.len()].lock().unwrap();
+        // Only fails for values beyond what a histogram can ever resize to
+        if histogram.record(micros).is_err() {
+            histogram.saturating_record(micros);
+        }
+    }
+
+    /// Merge all shards into a single histogram.
+    pub fn snapshot(&self) -> hdrhistogram::Histogram<u64> {
+        let mut merged = new_hdr_histogram();
+        for shard in &self.shards {
+            merge(&mut merged, &shard.lock().unwrap());
+        }
+        merged
+    }
+}
+
+/// Create an empty, auto-resizing histogram.
+fn new_hdr_histogram() -> hdrhistogram::Histogram<u64> {
+    hdrhistogram::Histogram::new(HDR_SIGNIFICANT_FIGURES).unwrap()
+}
+
+/// Add the counts of `source` to `target`.
+pub fn merge(target: &mut hdrhistogram::Histogram<u64>, source: &hdrhistogram::Histogram<u64>) {
+    // Auto-resizing histograms grow to fit whatever is added
+    target.add(source).unwrap();
+}
+
+/// HDR counterparts of the Prometheus histograms in [`LatencyHistogram`].
+#[derive(Debug)]
+pub struct HdrLatency {
+    pub connect: HdrHistogram,
+    pub ws_connect: HdrHistogram,
+    pub publish: HdrHistogram,
+    pub subscribe: HdrHistogram,
+}
+
+impl HdrLatency {
+    fn new() -> Self {
+        Self {
+            connect: HdrHistogram::new(),
+            ws_connect: HdrHistogram::new(),
+            publish: HdrHistogram::new(),
+            subscribe: HdrHistogram::new(),
+        }
+    }
+
+    /// The histograms along with their type label and description.
+    pub fn histograms(&self) -> [(&'static str, &'static str, &HdrHistogram); 4] {
+        [
+            ("connect", "Connect Latency", &self.connect),
+            ("ws_connect", "WebSocket Connect Latency", &self.ws_connect),
+            ("publish", "Publish MQTT Message Latency", &self.publish),
+            (
+                "subscribe",
+                "E2E MQTT Message Delivery Latency",
+                &self.subscribe,
+            ),
+        ]
+    }
+}
+
+/// Latencies recorded since the previous call to [`IntervalLatency::take`].
+///
+/// Works off the cumulative histograms by subtracting the snapshot taken at the previous call, so
+/// clients record each latency once.
+#[derive(Debug)]
+pub struct IntervalLatency {
+    hdr: Arc<HdrLatency>,
+    previous: [hdrhistogram::Histogram<u64>; 4],
+}
+
+impl IntervalLatency {
+    pub fn new(hdr: Arc<HdrLatency>) -> Self {
+        Self {
+            hdr,
+            previous: [
+                new_hdr_histogram(),
+                new_hdr_histogram(),
+                new_hdr_histogram(),
+                new_hdr_histogram(),
+            ],
+        }
+    }
+
+    /// Summarize each histogram over the interval, keyed by type label. Histograms without any
+    /// latency recorded in the interval are left out.
+    pub fn take(&mut self) -> BTreeMap<&'static str, LatencySummary> {
+        let mut summaries = BTreeMap::new();
+        for ((name, _, histogram), previous) in
+            self.hdr.histograms().into_iter().zip(&mut self.previous)
+        {
+            let current = histogram.snapshot();
+            let mut interval = current.clone();
+            // Counts only grow, so the previous snapshot always fits in the current one
+            if interval.subtract(&*previous).is_ok() {
+                if let Some(summary) = LatencySummary::of(&interval) {
+                    summaries.insert(name, summary);
+                }
+            }
+            *previous = current;
+        }
+        summaries
+    }
+}
+
+/// Percentiles of a latency histogram, in microseconds.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub min: u64,
+    pub mean: f64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub p999: u64,
+    pub p9999: u64,
+    pub max: u64,
+}
+
+impl LatencySummary {
+    /// Summarize the given histogram, or `None` if it is empty.
+    pub fn of(histogram: &hdrhistogram::Histogram<u64>) -> Option<Self> {
+        if histogram.is_empty() {
+            return None;
+        }
+        Some(Self {
+            count: histogram.len(),
+            min: histogram.min(),
+            mean: histogram.mean(),
+            p50: histogram.value_at_quantile(0.5),
+            p90: histogram.value_at_quantile(0.9),
+            p95: histogram.value_at_quantile(0.95),
+            p99: histogram.value_at_quantile(0.99),
+            p999: histogram.value_at_quantile(0.999),
+            p9999: histogram.value_at_quantile(0.9999),
+            max: histogram.max(),
+        })
+    }
+}
+
+/// Format a latency in microseconds as milliseconds.
+pub fn format_micros(micros: u64) -> String {
+    format!("{:.3}ms", micros as f64 / 1000.0)
+}
+
+/// How a count is spread across a population, e.g. messages received per subscriber.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Distribution {
+    pub population: usize,
+    pub total: usize,
+    pub min: usize,
+    pub max: usize,
+    pub mean: f64,
+    pub stddev: f64,
+}
+
+impl Distribution {
+    /// Summarize the given samples, or `None` if there are none.
+    pub fn of(samples: &[usize]) -> Option<Self> {
+        let min = *samples.iter().min()?;
+        let max = *samples.iter().max()?;
+        let total = samples.iter().sum::<usize>();
+        let mean = total as f64 / samples.len() as f64;
+        let variance = samples
+            .iter()
+            .map(|&sample| (sample as f64 - mean).powi(2))
+            .sum::<f64>()
+            / samples.len() as f64;
+        Some(Self {
+            population: samples.len(),
+            total,
+            min,
+            max,
+            mean,
+            stddev: variance.sqrt(),
+        })
+    }
 }
 
 pub struct Statistics {
@@ -25,6 +283,13 @@ impl Statistics {
         let connect = Histogram::with_opts(conn_histogram_opts).unwrap();
         r.register(Box::new(connect.clone())).unwrap();
 
+        let ws_conn_histogram_opts =
+            HistogramOpts::new("ws_conn_histogram", "WebSocket Connect Latency Histogram")
+                .buckets(linear_buckets(0.0, 100.0, 10).unwrap())
+                .const_labels(labels! {"type".to_string() => "ws_connect".to_string(), "unit".to_string() => "ms".to_string()});
+        let ws_connect = Histogram::with_opts(ws_conn_histogram_opts).unwrap();
+        r.register(Box::new(ws_connect.clone())).unwrap();
+
         let pub_histogram_opts =
             HistogramOpts::new("pub_histogram", "Publish MQTT Message Latency")
                 .buckets(linear_buckets(0.0, 10.0, 20).unwrap())
@@ -41,8 +306,11 @@ impl Statistics {
 
         let latency_histogram = LatencyHistogram {
             connect,
+            ws_connect,
             publish,
             subscribe,
+            hdr: Arc::new(HdrLatency::new()),
+            next_shard: Arc::new(AtomicUsize::new(0)),
         };
 
         Self {
@@ -52,61 +320,24 @@ impl Statistics {
     }
 
     pub fn show_statistics(&self) {
-        let metric_families = self.registry.gather();
-        for family in metric_families.iter() {
-            let metric_type = family.get_field_type();
-            for metric in family.get_metric() {
-                if MetricType::HISTOGRAM != metric_type {
-                    continue;
-                }
-                let histogram = metric.get_histogram();
-
-                let mut p90 = 0.0;
-                let mut p95 = 0.0;
-                let mut p99 = 0.0;
-
-                let mut percentiles = [false; 3];
-                let mut result = [0.0; 3];
-
-                let buckets = histogram.get_bucket();
-                if let Some(bucket) = buckets.last() {
-                    if 0 == bucket.get_cumulative_count() {
-                        continue;
-                    }
-                    p90 = bucket.get_cumulative_count() as f64 * 0.9;
-                    p95 = bucket.get_cumulative_count() as f64 * 0.95;
-                    p99 = bucket.get_cumulative_count() as f64 * 0.99;
-                }
-
-                for bucket in buckets {
-                    let upper_bound = bucket.get_upper_bound();
-                    let cumulative_count = bucket.get_cumulative_count();
-                    if !percentiles[0] && cumulative_count >= p90 as u64 {
-                        result[0] = upper_bound;
-                        percentiles[0] = true;
-                    }
-
-                    if !percentiles[1] && cumulative_count >= p95 as u64 {
-                        result[1] = upper_bound;
-                        percentiles[1] = true;
-                    }
-
-                    if !percentiles[2] && cumulative_count >= p99 as u64 {
-                        result[2] = upper_bound;
-                        break;
-                    }
-                }
-
-                info!(
-                    "{} P90: {}ms, P95: {}ms, P99: {}ms",
-                    family.get_help(),
-                    result[0],
-                    result[1],
-                    result[2]
-                );
-            }
+        for (_, title, histogram) in self.latency.hdr.histograms() {
+            let Some(summary) = LatencySummary::of(&histogram.snapshot()) else {
+                continue;
+            };
+            info!(
+                "{} P50: {}, P90: {}, P95: {}, P99: {}, P99.9: {}, P99.99: {}, Max: {}",
+                title,
+                format_micros(summary.p50),
+                format_micros(summary.p90),
+                format_micros(summary.p95),
+                format_micros(summary.p99),
+                format_micros(summary.p999),
+                format_micros(summary.p9999),
+                format_micros(summary.max)
+            );
         }
 
+        let metric_families = self.registry.gather();
         for family in metric_families.iter() {
             if family.get_field_type() == MetricType::HISTOGRAM {
                 continue;
@@ -127,3 +358,81 @@ impl Default for Statistics {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Distribution, IntervalLatency, LatencySummary, Statistics};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_hdr_latency() {
+        let statistics = Statistics::new();
+        let recorders = (0..4)
+            .map(|_| statistics.latency.recorder())
+            .collect::<Vec<_>>();
+        for i in 1..=1000 {
+            recorders[i % recorders.len()].publish(Duration::from_micros(i as u64));
+        }
+        recorders[0].publish(Duration::from_secs(3));
+
+        let summary = LatencySummary::of(&statistics.latency.hdr.publish.snapshot()).unwrap();
+        assert_eq!(1001, summary.count);
+        assert_eq!(1, summary.min);
+        assert!(summary.p50.abs_diff(501) <= 1);
+        assert!(summary.p99.abs_diff(991) <= 1);
+        // 3 significant figures
+        assert!(summary.max.abs_diff(3_000_000) <= 3_000);
+        assert_eq!(
+            None,
+            LatencySummary::of(&statistics.latency.hdr.connect.snapshot())
+        );
+    }
+
+    #[test]
+    fn test_ws_connect_latency() {
+        let statistics = Statistics::new();
+        let recorder = statistics.latency.recorder();
+        recorder.connect(Duration::from_millis(2));
+        recorder.ws_connect(Duration::from_millis(5));
+        recorder.ws_connect(Duration::from_millis(7));
+
+        let hdr = &statistics.latency.hdr;
+        assert_eq!(1, hdr.connect.snapshot().len());
+        assert_eq!(2, hdr.ws_connect.snapshot().len());
+        assert_eq!(2, statistics.latency.ws_connect.get_sample_count());
+    }
+
+    #[test]
+    fn test_interval_latency() {
+        let statistics = Statistics::new();
+        let recorder = statistics.latency.recorder();
+        let mut interval = IntervalLatency::new(Arc::clone(&statistics.latency.hdr));
+
+        recorder.publish(Duration::from_micros(100));
+        recorder.publish(Duration::from_micros(200));
+        let summaries = interval.take();
+        assert_eq!(2, summaries["publish"].count);
+        assert!(!summaries.contains_key("connect"));
+
+        recorder.publish(Duration::from_micros(5000));
+        let summaries = interval.take();
+        assert_eq!(1, summaries["publish"].count);
+        assert!(summaries["publish"].min.abs_diff(5000) <= 5);
+
+        assert!(interval.take().is_empty());
+    }
+
+    #[test]
+    fn test_distribution() {
+        assert_eq!(None, Distribution::of(&[]));
+
+        let distribution = Distribution::of(&[2, 4, 4, 4, 5, 5, 7, 9]).unwrap();
+        assert_eq!(8, distribution.population);
+        assert_eq!(40, distribution.total);
+        assert_eq!(2, distribution.min);
+        assert_eq!(9, distribution.max);
+        assert_eq!(5.0, distribution.mean);
+        assert_eq!(2.0, distribution.stddev);
+    }
+}
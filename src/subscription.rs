@@ -1,11 +1,79 @@
+use crate::topic;
+
 #[derive(Debug, Clone)]
 pub(crate) struct Subscription {
-    pub(crate) topic_filter: String,
+    pub(crate) topic_filters: Vec<String>,
     pub(crate) qos: i32,
+    /// Shared subscription group, if the filters are subscribed as `$share/<group>/<topic_filter>`
+    pub(crate) share_group: Option<String>,
 }
 
 impl Subscription {
-    pub(crate) fn new(topic_filter: String, qos: i32) -> Subscription {
-        Self { topic_filter, qos }
+    pub(crate) fn new(topic_filters: Vec<String>, qos: i32) -> Subscription {
+        Self {
+            topic_filters,
+            qos,
+            share_group: None,
+        }
+    }
+
+    pub(crate) fn shared(
+        share_group: String,
+        topic_filters: Vec<String>,
+        qos: i32,
+    ) -> Subscription {
+        Self {
+            topic_filters,
+            qos,
+            share_group: Some(share_group),
+        }
+    }
+
+    /// Filters as sent in the SUBSCRIBE packet.
+    pub(crate) fn filters(&self) -> Vec<String> {
+        self.topic_filters
+            .iter()
+            .map(|topic_filter| match &self.share_group {
+                Some(group) => format!("$share/{}/{}", group, topic_filter),
+                None => topic_filter.clone(),
+            })
+            .collect()
+    }
+
+    /// Whether a message published to `topic` matches any of the filters.
+    pub(crate) fn matches(&self, topic: &str) -> bool {
+        self.topic_filters
+            .iter()
+            .any(|topic_filter| topic::matches(topic_filter, topic))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Subscription;
+
+    #[test]
+    fn test_filters() {
+        let subscription = Subscription::new(vec!["home/1".to_owned()], 1);
+        assert_eq!(vec!["home/1"], subscription.filters());
+
+        let subscription = Subscription::shared(
+            "g0".to_owned(),
+            vec!["home/+".to_owned(), "office/#".to_owned()],
+            1,
+        );
+        assert_eq!(
+            vec!["$share/g0/home/+", "$share/g0/office/#"],
+            subscription.filters()
+        );
+    }
+
+    #[test]
+    fn test_matches() {
+        let subscription =
+            Subscription::new(vec!["home/+/temp".to_owned(), "office/#".to_owned()], 1);
+        assert!(subscription.matches("home/1/temp"));
+        assert!(subscription.matches("office/2/humidity"));
+        assert!(!subscription.matches("home/1/humidity"));
     }
 }
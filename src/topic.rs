@@ -0,0 +1,490 @@
+//! Mapping of clients onto a bounded set of topics, the topics generated from it, and matching
+//! of topic filters.
+
+mod template;
+
+use crate::header::Header;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+pub use template::Template;
+pub(crate) use template::{parse_range, uuid};
+
+/// How clients are spread across `topic_total` topics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TopicMapping {
+    /// Client `n` uses topic `n % topic_total`, so that neighbours use different topics
+    #[default]
+    Modulo,
+    /// Consecutive clients share a topic, in blocks of `total / topic_total` clients
+    Block,
+    /// Every client uses a topic drawn uniformly, reproducible by `--topic-seed`
+    Random,
+    /// Topics are drawn from a Zipf distribution, so that a few hot topics get most clients
+    Zipf,
+}
+
+/// Maps the clients of a run onto topic numbers.
+///
+/// Topic numbers start from the start number of the clients, like client IDs do, so that with
+/// as many topics as clients [`TopicMapping::Modulo`] gives every client the topic of its own ID.
+#[derive(Debug, Clone)]
+pub struct TopicMapper {
+    mapping: TopicMapping,
+    start_number: usize,
+    /// Number of the first topic
+    first_topic: usize,
+    total: usize,
+    topic_total: usize,
+    seed: u64,
+    /// Cumulative probabilities of the topics under [`TopicMapping::Zipf`], hottest first
+    cdf: Vec<f64>,
+}
+
+impl TopicMapper {
+    /// Map `total` clients numbered from `start_number` onto `topic_total` topics, or onto one
+    /// topic each if `topic_total` is 0.
+    pub fn new(
+        mapping: TopicMapping,
+        start_number: usize,
+        total: usize,
+        topic_total: usize,
+        seed: u64,
+        zipf_exponent: f64,
+    ) -> Self {
+        let topic_total = if topic_total == 0 { total } else { topic_total }.max(1);
+        let cdf = match mapping {
+            TopicMapping::Zipf => zipf_cdf(topic_total, zipf_exponent),
+            _ => vec![],
+        };
+        Self {
+            mapping,
+            start_number,
+            first_topic: start_number,
+            total: total.max(1),
+            topic_total,
+            seed,
+            cdf,
+        }
+    }
+
+    /// Number topics from `first_topic` rather than from the start number of the clients, so that
+    /// clients numbered after others can be mapped onto the same topics.
+    pub fn numbered_from(mut self, first_topic: usize) -> Self {
+        self.first_topic = first_topic;
+        self
+    }
+
+    /// Number of the topic of client `id`.
+    pub fn topic_number(&self, id: usize) -> usize {
+        let index = id.saturating_sub(self.start_number);
+        let topic = match self.mapping {
+            TopicMapping::Modulo => index % self.topic_total,
+            TopicMapping::Block => {
+                let block = self.total.div_ceil(self.topic_total);
+                (index / block).min(self.topic_total - 1)
+            }
+            TopicMapping::Random => (self.draw(id) % self.topic_total as u64) as usize,
+            TopicMapping::Zipf => {
+                // Uniform in [0, 1) from the 53 high bits
+                let uniform = (self.draw(id) >> 11) as f64 / (1u64 << 53) as f64;
+                let rank = self.cdf.partition_point(|p| *p <= uniform);
+                rank.min(self.topic_total - 1)
+            }
+        };
+        self.first_topic + topic
+    }
+
+    /// Random number of client `id`, the same for the same seed.
+    fn draw(&self, id: usize) -> u64 {
+        splitmix64(self.seed ^ splitmix64(id as u64))
+    }
+}
+
+/// Topics of the clients of a run, expanded from a [`Template`] with the topic number of each
+/// client.
+#[derive(Debug, Clone)]
+pub struct Topics {
+    template: Template,
+    mapper: TopicMapper,
+    /// Number of consecutive topics in a `{group}`
+    group_size: usize,
+}
+
+impl Topics {
+    pub fn new(template: Template, mapper: TopicMapper, group_size: usize) -> Self {
+        Self {
+            template,
+            mapper,
+            group_size: group_size.max(1),
+        }
+    }
+
+    /// Whether the topic of a client changes from message to message.
+    pub fn is_per_message(&self) -> bool {
+        self.template.is_per_message()
+    }
+
+    /// Topic of message `sequence` of client `id`.
+    pub fn topic_of(&self, id: usize, client_id: &str, sequence: u64) -> String {
+        self.template.render(&self.context(id, client_id, sequence))
+    }
+
+    /// Topic filter matching the messages of client `id`, with `+` for the levels that change from
+    /// message to message.
+    pub fn filter_of(&self, id: usize, client_id: &str) -> String {
+        self.template.filter(&self.context(id, client_id, 0))
+    }
+
+    fn context<'a>(&self, id: usize, client_id: &'a str, sequence: u64) -> template::Context<'a> {
+        let index = self.mapper.topic_number(id);
+        template::Context {
+            client_id,
+            index,
+            group: index / self.group_size,
+            sequence,
+            random: splitmix64(self.mapper.draw(id)),
+        }
+    }
+}
+
+/// Number of topics a publisher numbers its messages on; messages to further topics are sent with
+/// [`Header::UNTRACKED`], so that wide `{rand:<a>..<b>}` ranges do not grow without bound.
+const MAX_TRACKED_TOPICS: usize = 1024;
+
+/// Topics a publisher sends its messages to, with the sequence number of each message within its
+/// topic, so that subscribers of any of the topics see no gaps.
+#[derive(Debug)]
+pub struct TopicCursor {
+    topics: Arc<Topics>,
+    id: usize,
+    client_id: String,
+    /// Number of messages sent so far
+    count: u64,
+    topic: String,
+    /// Number of messages sent to each of the first [`MAX_TRACKED_TOPICS`] topics so far, unless
+    /// every message has a topic of its own
+    sequences: HashMap<String, u64>,
+}
+
+impl TopicCursor {
+    pub fn new(topics: Arc<Topics>, id: usize, client_id: String) -> Self {
+        let topic = topics.topic_of(id, &client_id, 0);
+        Self {
+            topics,
+            id,
+            client_id,
+            count: 0,
+            topic,
+            sequences: HashMap::new(),
+        }
+    }
+
+    /// Topic of the next message.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Sequence number of the next message within its topic, or [`Header::UNTRACKED`] beyond
+    /// [`MAX_TRACKED_TOPICS`] topics.
+    pub fn sequence(&self) -> u64 {
+        if !self.is_tracked() {
+            return Header::UNTRACKED;
+        }
+        self.sequences.get(&self.topic).copied().unwrap_or_default()
+    }
+
+    /// Move on to the message after the next one.
+    pub fn advance(&mut self) {
+        self.count += 1;
+        // Each topic is used once when every message has a topic of its own
+        if !self.topics.template.is_unique_per_message() && self.is_tracked() {
+            *self.sequences.entry(self.topic.clone()).or_default() += 1;
+        }
+        if self.topics.is_per_message() {
+            self.topic = self.topics.topic_of(self.id, &self.client_id, self.count);
+        }
+    }
+
+    /// Whether the next message is numbered within its topic.
+    fn is_tracked(&self) -> bool {
+        self.sequences.len() < MAX_TRACKED_TOPICS || self.sequences.contains_key(&self.topic)
+    }
+}
+
+/// Topic filters of the subscribers of a run, to count how many of them a message reaches.
+#[derive(Debug, Clone, Default)]
+pub struct Audience {
+    /// Number of subscribers with each set of filters, as subscribers often share theirs
+    subscribers: HashMap<Vec<String>, usize>,
+}
+
+impl Audience {
+    /// Add a subscriber with its topic filters.
+    pub fn add(&mut self, filters: Vec<String>) {
+        *self.subscribers.entry(filters).or_default() += 1;
+    }
+
+    /// Number of subscribers with a filter matching `topic`.
+    pub fn count(&self, topic: &str) -> usize {
+        self.subscribers
+            .iter()
+            .filter(|(filters, _)| filters.iter().any(|filter| matches(filter, topic)))
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+/// Subscribers the messages of a publisher reach, remembered for its first [`MAX_TRACKED_TOPICS`]
+/// topics so that publishing does not match the topic against every filter each time.
+#[derive(Debug)]
+pub struct Reach {
+    audience: Arc<Audience>,
+    counts: HashMap<String, usize>,
+}
+
+impl Reach {
+    pub fn new(audience: Arc<Audience>) -> Self {
+        Self {
+            audience,
+            counts: HashMap::new(),
+        }
+    }
+
+    /// Number of subscribers a message to `topic` reaches.
+    pub fn count(&mut self, topic: &str) -> usize {
+        if let Some(count) = self.counts.get(topic) {
+            return *count;
+        }
+        let count = self.audience.count(topic);
+        if self.counts.len() < MAX_TRACKED_TOPICS {
+            self.counts.insert(topic.to_owned(), count);
+        }
+        count
+    }
+}
+
+/// Whether `filter` is a valid topic filter, with wildcards taking whole levels and `#` only as
+/// the last level.
+pub fn is_valid_filter(filter: &str) -> bool {
+    let levels = filter.split('/').collect::<Vec<_>>();
+    !filter.is_empty()
+        && levels
+            .iter()
+            .enumerate()
+            .all(|(index, level)| match *level {
+                "#" => index == levels.len() - 1,
+                "+" => true,
+                level => !level.contains(['#', '+']),
+            })
+}
+
+/// Whether `topic` matches the topic `filter`.
+///
+/// Wildcards at the first level do not match topics starting with `$`.
+pub fn matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(topic_level)) if level == topic_level => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+/// Cumulative probabilities of ranks `1..=n` under Zipf's law with exponent `s`.
+fn zipf_cdf(n: usize, s: f64) -> Vec<f64> {
+    let weights = (1..=n)
+        .map(|rank| 1.0 / (rank as f64).powf(s))
+        .collect::<Vec<_>>();
+    let sum = weights.iter().sum::<f64>();
+    let mut cumulative = 0.0;
+    weights
+        .into_iter()
+        .map(|weight| {
+            cumulative += weight / sum;
+            cumulative
+        })
+        .collect()
+}
+
+/// SplitMix64, to hash client IDs and counters into well spread random numbers.
+pub(crate) fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        is_valid_filter, matches, Audience, Reach, Template, TopicCursor, TopicMapper,
+        TopicMapping, Topics, MAX_TRACKED_TOPICS,
+    };
+    use crate::header::Header;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn topics(mapper: &TopicMapper, ids: std::ops::Range<usize>) -> Vec<usize> {
+        ids.map(|id| mapper.topic_number(id)).collect()
+    }
+
+    #[test]
+    fn test_modulo() {
+        let mapper = TopicMapper::new(TopicMapping::Modulo, 100, 6, 3, 0, 1.0);
+        assert_eq!(
+            vec![100, 101, 102, 100, 101, 102],
+            topics(&mapper, 100..106)
+        );
+
+        // One topic per client by default
+        let mapper = TopicMapper::new(TopicMapping::Modulo, 0, 4, 0, 0, 1.0);
+        assert_eq!(vec![0, 1, 2, 3], topics(&mapper, 0..4));
+    }
+
+    #[test]
+    fn test_numbered_from() {
+        // Subscribers numbered after 4 publishers get the topics of the publishers
+        let mapper = TopicMapper::new(TopicMapping::Modulo, 4, 2, 4, 0, 1.0).numbered_from(0);
+        assert_eq!(vec![0, 1], topics(&mapper, 4..6));
+    }
+
+    #[test]
+    fn test_block() {
+        let mapper = TopicMapper::new(TopicMapping::Block, 0, 7, 3, 0, 1.0);
+        assert_eq!(vec![0, 0, 0, 1, 1, 1, 2], topics(&mapper, 0..7));
+    }
+
+    #[test]
+    fn test_random() {
+        let mapper = TopicMapper::new(TopicMapping::Random, 0, 1000, 10, 42, 1.0);
+        let numbers = topics(&mapper, 0..1000);
+        assert!(numbers.iter().all(|topic| *topic < 10));
+        for topic in 0..10 {
+            let count = numbers.iter().filter(|n| **n == topic).count();
+            assert!((50..150).contains(&count), "topic {}: {}", topic, count);
+        }
+        assert_eq!(numbers, topics(&mapper, 0..1000));
+
+        let reseeded = TopicMapper::new(TopicMapping::Random, 0, 1000, 10, 43, 1.0);
+        assert_ne!(numbers, topics(&reseeded, 0..1000));
+    }
+
+    #[test]
+    fn test_zipf() {
+        let mapper = TopicMapper::new(TopicMapping::Zipf, 0, 10_000, 100, 7, 1.2);
+        let numbers = topics(&mapper, 0..10_000);
+        assert!(numbers.iter().all(|topic| *topic < 100));
+        let hottest = numbers.iter().filter(|n| **n == 0).count();
+        let coldest = numbers.iter().filter(|n| **n == 99).count();
+        // The hottest topic takes about a quarter of the clients with exponent 1.2
+        assert!(hottest > 1_500, "{}", hottest);
+        assert!(hottest > 20 * coldest.max(1), "{} vs {}", hottest, coldest);
+    }
+
+    #[test]
+    fn test_topics() -> anyhow::Result<()> {
+        let mapper = TopicMapper::new(TopicMapping::Block, 0, 4, 2, 0, 1.0);
+        let template = Template::parse("home/%d/temp", &[], 10)?;
+        let topics = Topics::new(template, mapper.clone(), 10);
+        assert_eq!("home/1/temp", topics.topic_of(3, "client-3", 0));
+        let topics = Topics::new(Template::parse("home", &[], 10)?, mapper, 10);
+        assert_eq!("home", topics.topic_of(3, "client-3", 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cursor() -> anyhow::Result<()> {
+        let mapper = TopicMapper::new(TopicMapping::Modulo, 0, 1, 0, 0, 1.0);
+        let template = Template::parse("r/{rand:1..2}", &[], 10)?;
+        let topics = Arc::new(Topics::new(template, mapper.clone(), 10));
+        let mut cursor = TopicCursor::new(topics, 0, "client-0".to_owned());
+        let mut sent = HashMap::<String, Vec<u64>>::new();
+        for _ in 0..100 {
+            sent.entry(cursor.topic().to_owned())
+                .or_default()
+                .push(cursor.sequence());
+            cursor.advance();
+        }
+        // Every topic sees consecutive sequence numbers
+        assert_eq!(2, sent.len());
+        for sequences in sent.values() {
+            assert!(sequences.iter().copied().eq(0..sequences.len() as u64));
+        }
+
+        let template = Template::parse("s/{seq}", &[], 10)?;
+        let topics = Arc::new(Topics::new(template, mapper.clone(), 10));
+        let mut cursor = TopicCursor::new(topics, 0, "client-0".to_owned());
+        cursor.advance();
+        assert_eq!(("s/1", 0), (cursor.topic(), cursor.sequence()));
+        assert!(cursor.sequences.is_empty());
+
+        // Numbered topics are capped, messages to further ones going untracked
+        let template = Template::parse("w/{rand:0..1000000}", &[], 10)?;
+        let topics = Arc::new(Topics::new(template, mapper, 10));
+        let mut cursor = TopicCursor::new(topics, 0, "client-0".to_owned());
+        let mut untracked = 0;
+        for _ in 0..2 * MAX_TRACKED_TOPICS {
+            if cursor.sequence() == Header::UNTRACKED {
+                untracked += 1;
+            }
+            cursor.advance();
+        }
+        assert_eq!(MAX_TRACKED_TOPICS, cursor.sequences.len());
+        assert!(untracked > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("home/0", "home/0"));
+        assert!(!matches("home/0", "home/1"));
+        assert!(matches("home/+", "home/0"));
+        assert!(!matches("home/+", "home/0/temperature"));
+        assert!(matches("home/#", "home"));
+        assert!(matches("home/#", "home/0/temperature"));
+        assert!(matches("+/+/temperature", "home/0/temperature"));
+        assert!(matches("#", "home/0"));
+        assert!(!matches("#", "$SYS/uptime"));
+        assert!(matches("$SYS/#", "$SYS/uptime"));
+        assert!(!matches("home/0/temperature", "home/0"));
+    }
+
+    #[test]
+    fn test_is_valid_filter() {
+        assert!(is_valid_filter("home/+/temperature"));
+        assert!(is_valid_filter("#"));
+        assert!(!is_valid_filter("home/#/temperature"));
+        assert!(!is_valid_filter("home/a+"));
+        assert!(!is_valid_filter(""));
+    }
+
+    #[test]
+    fn test_audience() {
+        let mut audience = Audience::default();
+        audience.add(vec!["home/+".to_owned()]);
+        audience.add(vec!["home/0".to_owned(), "home/#".to_owned()]);
+        audience.add(vec!["office/#".to_owned()]);
+        audience.add(vec!["office/#".to_owned()]);
+        assert_eq!(2, audience.count("home/0"));
+        assert_eq!(2, audience.count("office/1"));
+        assert_eq!(0, audience.count("garage"));
+
+        let mut reach = Reach::new(Arc::new(audience));
+        for topic in 0..MAX_TRACKED_TOPICS + 10 {
+            assert_eq!(2, reach.count(&format!("home/{}", topic)));
+        }
+        assert_eq!(MAX_TRACKED_TOPICS, reach.counts.len());
+        assert_eq!(2, reach.count("office/1"));
+    }
+}
@@ -0,0 +1,375 @@
+//! Topic templates.
+//!
+//! A template is a topic with placeholders, expanded for every client and, for some of them, for
+//! every message:
+//!
+//! - `%d` or `{index}`: number of the topic the client is mapped onto;
+//! - `{client_id}`: ID of the client;
+//! - `{group}`: number of the group of the topic, i.e. `{index}` divided by the group size;
+//! - `{uuid}`: random UUID of the client;
+//! - `{rand:<a>..<b>}`: random number from `a` to `b` inclusive, drawn for every message;
+//! - `{seq}`: number of the message of the client, from 0;
+//! - `{level:<n>}`: `n` levels of the topic hierarchy, joined by `/`;
+//! - `{<name>}`: one named level of the topic hierarchy, for names given a fan-out; other names
+//!   are rejected, so that typos like `{clientid}` do not silently become levels.
+//!
+//! The hierarchy is made of all `{level:<n>}` and named levels in order. The topic number is
+//! written with one digit per level, the last level being the least significant one and each level
+//! having as many values as its fan-out, so that `site/{site}/floor/{floor}/sensor/{sensor}` with
+//! fan-outs of 2, 3 and 4 spreads 24 topics over a tree.
+
+use anyhow::{bail, ensure, Context as _};
+use std::str::FromStr;
+
+/// Part of a template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Index,
+    ClientId,
+    Group,
+    Uuid,
+    /// Random number between the bounds, inclusive
+    Rand(u64, u64),
+    Seq,
+    /// Consecutive levels of the hierarchy
+    Levels {
+        first: usize,
+        count: usize,
+    },
+}
+
+impl Part {
+    /// Whether the part changes from message to message.
+    fn per_message(&self) -> bool {
+        matches!(self, Part::Rand(..) | Part::Seq)
+    }
+
+    fn render(&self, context: &Context, rendered: &mut String, fanouts: &[usize]) {
+        match self {
+            Part::Literal(literal) => rendered.push_str(literal),
+            Part::Index => rendered.push_str(&context.index.to_string()),
+            Part::ClientId => rendered.push_str(context.client_id),
+            Part::Group => rendered.push_str(&context.group.to_string()),
+            Part::Uuid => rendered.push_str(&uuid(context.random)),
+            Part::Rand(low, high) => {
+                // Salted by the position of the part, so that random numbers of a topic differ
+                let salt = rendered.len() as u64;
+                let random =
+                    super::splitmix64(context.random ^ super::splitmix64(context.sequence) ^ salt);
+                let span = high - low;
+                let value = match span.checked_add(1) {
+                    Some(count) => low + random % count,
+                    None => random,
+                };
+                rendered.push_str(&value.to_string());
+            }
+            Part::Seq => rendered.push_str(&context.sequence.to_string()),
+            Part::Levels { first, count } => {
+                let digits = digits(context.index, fanouts);
+                let levels = digits[*first..first + count]
+                    .iter()
+                    .map(usize::to_string)
+                    .collect::<Vec<_>>();
+                rendered.push_str(&levels.join("/"));
+            }
+        }
+    }
+}
+
+/// Values placeholders expand to for a message.
+#[derive(Debug, Clone, Copy)]
+pub struct Context<'a> {
+    pub client_id: &'a str,
+    /// Number of the topic of the client
+    pub index: usize,
+    /// Number of the group of the topic
+    pub group: usize,
+    /// Number of the message of the client
+    pub sequence: u64,
+    /// Random number of the client, from which the UUID and random numbers are derived
+    pub random: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+    /// Fan-out of every level of the hierarchy, outermost first
+    fanouts: Vec<usize>,
+}
+
+impl Template {
+    /// Parse a template, giving named levels their fan-out of `fanouts` and the levels of
+    /// `{level:<n>}` `default_fanout`.
+    pub fn parse(
+        template: &str,
+        fanouts: &[(String, usize)],
+        default_fanout: usize,
+    ) -> Result<Self, anyhow::Error> {
+        let fanout_of = |name: &str| {
+            fanouts
+                .iter()
+                .find(|(level, _)| level == name)
+                .map(|(_, fanout)| (*fanout).max(1))
+        };
+        let mut parts = vec![];
+        let mut levels = vec![];
+        let mut literal = String::new();
+        let mut rest = template;
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix("%d") {
+                push_literal(&mut parts, &mut literal);
+                parts.push(Part::Index);
+                rest = after;
+                continue;
+            }
+            let Some(after) = rest.strip_prefix('{') else {
+                // Not empty, so there is a first character
+                let c = rest.chars().next().unwrap();
+                literal.push(c);
+                rest = &rest[c.len_utf8()..];
+                continue;
+            };
+            let end = after
+                .find('}')
+                .context(format!("Unclosed placeholder in topic `{}`", template))?;
+            let placeholder = &after[..end];
+            rest = &after[end + 1..];
+            push_literal(&mut parts, &mut literal);
+            let (name, argument) = match placeholder.split_once(':') {
+                Some((name, argument)) => (name, Some(argument)),
+                None => (placeholder, None),
+            };
+            let part = match (name, argument) {
+                ("index", None) => Part::Index,
+                ("client_id", None) => Part::ClientId,
+                ("group", None) => Part::Group,
+                ("uuid", None) => Part::Uuid,
+                ("seq", None) => Part::Seq,
+                ("rand", Some(range)) => {
+                    let (low, high) = parse_range(range).context(format!(
+                        "Expected `{{rand:<a>..<b>}}`, got `{{{}}}`",
+                        placeholder
+                    ))?;
+                    Part::Rand(low, high)
+                }
+                ("level", Some(count)) => {
+                    let count = match count.parse::<usize>() {
+                        Ok(count) if count > 0 => count,
+                        _ => bail!("Expected `{{level:<n>}}`, got `{{{}}}`", placeholder),
+                    };
+                    let first = levels.len();
+                    levels.resize(first + count, default_fanout.max(1));
+                    Part::Levels { first, count }
+                }
+                (name, None) => {
+                    let fanout = fanout_of(name).context(format!(
+                        "Unknown placeholder `{{{}}}` in topic; named levels need a \
+                         `--level-fanout {}=<n>`",
+                        placeholder, name
+                    ))?;
+                    levels.push(fanout);
+                    Part::Levels {
+                        first: levels.len() - 1,
+                        count: 1,
+                    }
+                }
+                _ => bail!("Unknown placeholder `{{{}}}` in topic", placeholder),
+            };
+            parts.push(part);
+        }
+        push_literal(&mut parts, &mut literal);
+        Ok(Self {
+            parts,
+            fanouts: levels,
+        })
+    }
+
+    /// Whether the topic changes from message to message of a client.
+    pub fn is_per_message(&self) -> bool {
+        self.parts.iter().any(Part::per_message)
+    }
+
+    /// Whether every message of a client has a topic of its own.
+    pub fn is_unique_per_message(&self) -> bool {
+        self.parts.contains(&Part::Seq)
+    }
+
+    /// Expand the placeholders for a message.
+    pub fn render(&self, context: &Context) -> String {
+        self.expand(|part, rendered| part.render(context, rendered, &self.fanouts))
+    }
+
+    /// Expand the placeholders for a subscription to the messages of a client, with levels that
+    /// change from message to message replaced by `+`.
+    pub fn filter(&self, context: &Context) -> String {
+        // Marks the levels to replace, as a topic cannot contain NUL
+        const PER_MESSAGE: char = '\0';
+        let topic = self.expand(|part, rendered| {
+            if part.per_message() {
+                rendered.push(PER_MESSAGE);
+            } else {
+                part.render(context, rendered, &self.fanouts);
+            }
+        });
+        topic
+            .split('/')
+            .map(|level| {
+                if level.contains(PER_MESSAGE) {
+                    "+"
+                } else {
+                    level
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    fn expand(&self, mut render: impl FnMut(&Part, &mut String)) -> String {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            render(part, &mut rendered);
+        }
+        rendered
+    }
+}
+
+fn push_literal(parts: &mut Vec<Part>, literal: &mut String) {
+    if !literal.is_empty() {
+        parts.push(Part::Literal(std::mem::take(literal)));
+    }
+}
+
+/// Parse `<a>..<b>` with `a <= b`, as used by random placeholders of topics and payloads.
+pub(crate) fn parse_range<T>(range: &str) -> Result<(T, T), anyhow::Error>
+where
+    T: FromStr + PartialOrd,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let (low, high) = range.split_once("..").context("Missing `..`")?;
+    let (low, high) = (low.trim().parse::<T>()?, high.trim().parse::<T>()?);
+    ensure!(low <= high, "Empty range");
+    Ok((low, high))
+}
+
+/// Digits of `index` with one digit per level of `fanouts`, outermost first, wrapping around once
+/// all topics of the hierarchy are used.
+fn digits(mut index: usize, fanouts: &[usize]) -> Vec<usize> {
+    let mut digits = vec![0; fanouts.len()];
+    for (digit, fanout) in digits.iter_mut().zip(fanouts).rev() {
+        *digit = index % fanout;
+        index /= fanout;
+    }
+    digits
+}
+
+/// Random UUID, as of version 4, derived from `random`.
+pub(crate) fn uuid(random: u64) -> String {
+    let high = super::splitmix64(random);
+    let low = super::splitmix64(high);
+    // Version 4 and variant 1
+    let high = (high & !0xF000) | 0x4000;
+    let low = (low & !(0b11 << 62)) | (0b10 << 62);
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xFFFF,
+        high & 0xFFFF,
+        low >> 48,
+        low & 0xFFFF_FFFF_FFFF
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{digits, Context, Template};
+
+    fn context(index: usize, sequence: u64) -> Context<'static> {
+        Context {
+            client_id: "device-7",
+            index,
+            group: index / 10,
+            sequence,
+            random: 42,
+        }
+    }
+
+    #[test]
+    fn test_placeholders() -> anyhow::Result<()> {
+        let template = Template::parse("a/%d/{index}/{client_id}/{group}/{seq}", &[], 10)?;
+        assert_eq!("a/23/23/device-7/2/5", template.render(&context(23, 5)));
+        assert!(template.is_per_message());
+        assert!(template.is_unique_per_message());
+
+        let template = Template::parse("home/{uuid}", &[], 10)?;
+        let topic = template.render(&context(0, 0));
+        let uuid = topic.strip_prefix("home/").unwrap();
+        assert_eq!(36, uuid.len());
+        assert_eq!(Some('4'), uuid.chars().nth(14));
+        assert_eq!(topic, template.render(&context(0, 1)));
+        assert!(!template.is_per_message());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rand() -> anyhow::Result<()> {
+        let template = Template::parse("r/{rand:1..3}", &[], 10)?;
+        let values = (0..100)
+            .map(|sequence| template.render(&context(0, sequence)))
+            .collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(
+            vec!["r/1", "r/2", "r/3"],
+            values.iter().map(String::as_str).collect::<Vec<_>>()
+        );
+        assert_eq!("r/+", template.filter(&context(0, 0)));
+        assert!(template.is_per_message());
+        assert!(!template.is_unique_per_message());
+
+        let template = Template::parse("r/x{seq}/{client_id}", &[], 10)?;
+        assert_eq!("r/+/device-7", template.filter(&context(0, 0)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hierarchy() -> anyhow::Result<()> {
+        let fanouts = [
+            ("site".to_owned(), 2),
+            ("floor".to_owned(), 3),
+            ("sensor".to_owned(), 4),
+        ];
+        let template = Template::parse(
+            "site/{site}/floor/{floor}/sensor/{sensor}/temp",
+            &fanouts,
+            3,
+        )?;
+        // 23 = 1 * 12 + 2 * 4 + 3
+        assert_eq!(
+            "site/1/floor/2/sensor/3/temp",
+            template.render(&context(23, 0))
+        );
+        assert_eq!(
+            "site/0/floor/0/sensor/1/temp",
+            template.render(&context(25, 0))
+        );
+
+        let template = Template::parse("tree/{level:3}", &[], 10)?;
+        assert_eq!("tree/1/2/3", template.render(&context(123, 0)));
+        assert_eq!(vec![0, 1, 2], digits(5, &[2, 2, 3]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() {
+        for template in [
+            "a/{index",
+            "a/{rand:3..1}",
+            "a/{rand:1}",
+            "a/{level:0}",
+            "a/{b c}",
+            // Named levels need a fan-out
+            "a/{clientid}",
+        ] {
+            assert!(Template::parse(template, &[], 10).is_err(), "{}", template);
+        }
+    }
+}
@@ -0,0 +1,739 @@
+//! End-to-end runs of the commands against the embedded broker, without network access.
+
+use clap::Parser;
+use mqtt_bench::broker::{tls_acceptor, tls_acceptor_builder, Broker};
+use mqtt_bench::cert::{load_ca_cert, load_ca_pkey, mk_ca_cert, Issuer, KeyAlgorithm};
+use mqtt_bench::cli::{Cli, Commands};
+use mqtt_bench::command;
+use mqtt_bench::state::State;
+use mqtt_bench::statistics::Statistics;
+use openssl::ssl::SslVersion;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, OnceLock};
+
+/// Listeners of a broker shared by all tests.
+#[derive(Clone, Copy)]
+struct Listeners {
+    tcp: SocketAddr,
+    tls: SocketAddr,
+    /// TLS, requiring clients to present a certificate signed by the CA
+    mtls: SocketAddr,
+    /// TLS 1.3 only, with a certificate signed by the CA in [`private_ca_bundle`]
+    tls13: SocketAddr,
+    ws: SocketAddr,
+    wss: SocketAddr,
+}
+
+/// Listeners of the broker, started on first use.
+///
+/// The broker runs on a runtime of its own, as it would in a separate process, so that clients
+/// blocking the runtime of a test, e.g. while disconnecting, cannot stall it.
+fn broker() -> Listeners {
+    static LISTENERS: OnceLock<Listeners> = OnceLock::new();
+    *LISTENERS.get_or_init(|| {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                tx.send(start_broker().await.unwrap()).unwrap();
+                std::future::pending::<()>().await
+            })
+        });
+        rx.recv().unwrap()
+    })
+}
+
+fn assets() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets")
+}
+
+/// CA certificate, generated for the run, of the TLS 1.3 listener.
+fn private_ca_bundle() -> PathBuf {
+    std::env::temp_dir().join(format!("mqtt-bench-private-ca-{}.crt", std::process::id()))
+}
+
+async fn start_broker() -> anyhow::Result<Listeners> {
+    let ca_cert = load_ca_cert(&assets().join("CA.crt"))?;
+    let ca_key = load_ca_pkey(&assets().join("CA.key"))?;
+    let broker = Broker::bind("127.0.0.1:0".parse()?).await?;
+    let acceptor = tls_acceptor(&ca_cert, &ca_key, KeyAlgorithm::Rsa2048, false)?;
+    let tls = Broker::bind("127.0.0.1:0".parse()?)
+        .await?
+        .tls(acceptor.clone())
+        .share_with(&broker)
+        .spawn()?;
+    let ws = Broker::bind("127.0.0.1:0".parse()?)
+        .await?
+        .websocket()
+        .share_with(&broker)
+        .spawn()?;
+    let wss = Broker::bind("127.0.0.1:0".parse()?)
+        .await?
+        .tls(acceptor)
+        .websocket()
+        .share_with(&broker)
+        .spawn()?;
+    let mtls = Broker::bind("127.0.0.1:0".parse()?)
+        .await?
+        .tls(tls_acceptor(
+            &ca_cert,
+            &ca_key,
+            KeyAlgorithm::Rsa2048,
+            true,
+        )?)
+        .share_with(&broker)
+        .spawn()?;
+    let (private_ca_cert, private_ca_key) = mk_ca_cert(KeyAlgorithm::EcdsaP256)?;
+    std::fs::write(private_ca_bundle(), private_ca_cert.to_pem()?)?;
+    let mut acceptor = tls_acceptor_builder(
+        &private_ca_cert,
+        &private_ca_key,
+        KeyAlgorithm::EcdsaP256,
+        false,
+    )?;
+    acceptor.set_min_proto_version(Some(SslVersion::TLS1_3))?;
+    let tls13 = Broker::bind("127.0.0.1:0".parse()?)
+        .await?
+        .tls(acceptor.build())
+        .share_with(&broker)
+        .spawn()?;
+    Ok(Listeners {
+        tcp: broker.spawn()?,
+        tls,
+        mtls,
+        tls13,
+        ws,
+        wss,
+    })
+}
+
+/// Parse a command line against the broker at `addr`, normalized like `main` does.
+///
+/// Commands run for a second unless `args` set `--time`.
+fn parse(addr: SocketAddr, args: &[&str]) -> Commands {
+    let port = addr.port().to_string();
+    let mut command_line = vec!["mqtt-bench"];
+    command_line.extend_from_slice(&args[..1]);
+    command_line.extend([
+        "--host",
+        "127.0.0.1",
+        "--port",
+        &port,
+        "-u",
+        "user",
+        "-P",
+        "secret",
+    ]);
+    if !args.contains(&"--time") {
+        command_line.extend(["--time", "1"]);
+    }
+    command_line.extend_from_slice(&args[1..]);
+    let mut command = Cli::try_parse_from(command_line).unwrap().command.unwrap();
+    command.normalize().unwrap();
+    command
+}
+
+/// Run `command` to completion, then stop its clients.
+async fn execute(command: Commands) -> anyhow::Result<Arc<State>> {
+    let statistics = Statistics::new();
+    let state = match &command {
+        Commands::Connect { common } => {
+            let state = State::new(common.total);
+            command::connect(common, &state, &statistics).await?;
+            state
+        }
+        Commands::Pub {
+            common,
+            pub_options,
+        } => {
+            let state = State::new(common.total);
+            command::publish(common, &state, &statistics, pub_options).await?;
+            state
+        }
+        Commands::Sub {
+            common,
+            sub_options,
+        } => {
+            let state = State::new(common.total);
+            command::subscribe(common, &state, &statistics, sub_options).await?;
+            state
+        }
+        Commands::Benchmark {
+            common,
+            pub_options,
+            bench_options,
+        } => {
+            let state = State::new(common.total);
+            command::benchmark(common, &state, &statistics, pub_options, bench_options).await?;
+            state
+        }
+        _ => unreachable!(),
+    };
+    state.stop_flag().store(true, Ordering::Relaxed);
+    Ok(state)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect() -> anyhow::Result<()> {
+    let addr = broker().tcp;
+    for version in ["3.1", "3.1.1", "5"] {
+        let command = parse(
+            addr,
+            &["connect", "--total", "4", "--mqtt-version", version],
+        );
+        let state = execute(command).await?;
+        assert_eq!(4, state.connected(), "MQTT {}", version);
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_tls() -> anyhow::Result<()> {
+    let addr = broker().tls;
+    let state = execute(parse(addr, &["connect", "--total", "2", "--ssl"])).await?;
+    assert_eq!(2, state.connected());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_websocket() -> anyhow::Result<()> {
+    let listeners = broker();
+    for (addr, ssl) in [(listeners.ws, false), (listeners.wss, true)] {
+        let mut args = vec![
+            "connect",
+            "--total",
+            "2",
+            "--transport",
+            "ws",
+            "--ws-path",
+            "/mqtt",
+            "--ws-header",
+            "Authorization=Bearer token",
+        ];
+        if ssl {
+            args.push("--ssl");
+        }
+        let state = execute(parse(addr, &args)).await?;
+        assert_eq!(2, state.connected(), "TLS: {}", ssl);
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_benchmark_websocket() -> anyhow::Result<()> {
+    let state = execute(parse(
+        broker().ws,
+        &[
+            "benchmark",
+            "--total",
+            "2",
+            "--transport",
+            "ws",
+            "--qos",
+            "1",
+            "--interval",
+            "100",
+            "--topic",
+            "ws/%d",
+        ],
+    ))
+    .await?;
+    assert_eq!(2, state.connected());
+    assert!(state.received_total() > 0);
+    assert_eq!(0, state.lost());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_tls13_private_ca() -> anyhow::Result<()> {
+    let addr = broker().tls13;
+    let ca_bundle = private_ca_bundle();
+    let state = execute(parse(
+        addr,
+        &[
+            "connect",
+            "--total",
+            "2",
+            "--ssl",
+            "--auth-server-certificate",
+            "--ca-bundle",
+            ca_bundle.to_str().unwrap(),
+            "--alpn",
+            "mqtt",
+        ],
+    ))
+    .await?;
+    assert_eq!(2, state.connected());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_mtls() -> anyhow::Result<()> {
+    let addr = broker().mtls;
+    let ca_cert = assets().join("CA.crt");
+    let ca_key = assets().join("CA.key");
+    for algorithm in ["rsa2048", "ecdsa-p256", "ed25519"] {
+        let state = execute(parse(
+            addr,
+            &[
+                "connect",
+                "--total",
+                "2",
+                "--ssl",
+                "--ca-cert",
+                ca_cert.to_str().unwrap(),
+                "--ca-key",
+                ca_key.to_str().unwrap(),
+                "--key-algorithm",
+                algorithm,
+            ],
+        ))
+        .await?;
+        assert_eq!(2, state.connected(), "{}", algorithm);
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_publish_subscribe() -> anyhow::Result<()> {
+    let addr = broker().tcp;
+    let subscriber = tokio::spawn(execute(parse(
+        addr,
+        &[
+            "sub",
+            "--total",
+            "2",
+            "--topic",
+            "pubsub/#",
+            "--client-id",
+            "sub_%d",
+            "--time",
+            "3",
+        ],
+    )));
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    for qos in ["0", "1", "2"] {
+        let publisher = execute(parse(
+            addr,
+            &[
+                "pub",
+                "--total",
+                "2",
+                "--qos",
+                qos,
+                "--interval",
+                "100",
+                "--topic",
+                "pubsub/%d",
+                "--client-id",
+                "pub_%d",
+            ],
+        ))
+        .await?;
+        assert_eq!(2, publisher.connected());
+        assert!(publisher.published_total() > 0, "QoS {}", qos);
+        assert_eq!(0, publisher.publish_failures_total(), "QoS {}", qos);
+    }
+
+    let subscriber = subscriber.await??;
+    assert_eq!(2, subscriber.connected());
+    assert!(subscriber.received_total() > 0);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_benchmark() -> anyhow::Result<()> {
+    let addr = broker().tcp;
+    for (version, qos) in [("3.1.1", "1"), ("5", "2")] {
+        let state = execute(parse(
+            addr,
+            &[
+                "benchmark",
+                "--total",
+                "4",
+                "--mqtt-version",
+                version,
+                "--qos",
+                qos,
+                "--interval",
+                "100",
+                "--topic",
+                "bench/%d",
+            ],
+        ))
+        .await?;
+        assert_eq!(4, state.connected(), "MQTT {}", version);
+        assert!(state.published_total() > 0, "MQTT {}", version);
+        assert!(state.received_total() > 0, "MQTT {}", version);
+        assert_eq!(0, state.lost(), "MQTT {}", version);
+        assert_eq!(0, state.duplicated(), "MQTT {}", version);
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_cert_dir() -> anyhow::Result<()> {
+    let addr = broker().mtls;
+    let cert_dir = std::env::temp_dir().join(format!("mqtt-bench-certs-{}", std::process::id()));
+    let clients = [(0, "Device0".to_owned()), (1, "Device1".to_owned())];
+    Issuer::cached(
+        &cert_dir,
+        Some((&assets().join("CA.crt"), &assets().join("CA.key"))),
+    )?
+    .generate(&clients)?;
+
+    let state = execute(parse(
+        addr,
+        &[
+            "connect",
+            "--total",
+            "2",
+            "--ssl",
+            "--client-id",
+            "Device%d",
+            "--cert-dir",
+            cert_dir.to_str().unwrap(),
+        ],
+    ))
+    .await?;
+    assert_eq!(2, state.connected());
+    std::fs::remove_dir_all(&cert_dir)?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_credentials_file() -> anyhow::Result<()> {
+    let addr = broker().mtls;
+    let dir = std::env::temp_dir().join(format!("mqtt-bench-credentials-{}", std::process::id()));
+    let clients = [(0, "Device0".to_owned()), (1, "Device1".to_owned())];
+    Issuer::cached(
+        &dir,
+        Some((&assets().join("CA.crt"), &assets().join("CA.key"))),
+    )?
+    .generate(&clients)?;
+    let credentials_file = dir.join("credentials.csv");
+    std::fs::write(
+        &credentials_file,
+        "client_id,username,password,cert,key\n\
+         Device0,user0,secret0,Device0.crt,Device0.key\n\
+         Device1,user1,secret1,Device1.crt,Device1.key\n",
+    )?;
+
+    let state = execute(parse(
+        addr,
+        &[
+            "connect",
+            "--total",
+            "2",
+            "--ssl",
+            "--credentials-file",
+            credentials_file.to_str().unwrap(),
+        ],
+    ))
+    .await?;
+    assert_eq!(2, state.connected());
+
+    // Every client needs an entry
+    let result = execute(parse(
+        addr,
+        &[
+            "connect",
+            "--total",
+            "3",
+            "--ssl",
+            "--credentials-file",
+            credentials_file.to_str().unwrap(),
+        ],
+    ))
+    .await;
+    assert!(result.is_err());
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connect_signed_passwords() -> anyhow::Result<()> {
+    let addr = broker().tcp;
+    let jwt_key = assets().join("CA.key");
+    for args in [
+        &["--auth", "jwt-hs256", "--jwt-audience", "project"][..],
+        &[
+            "--auth",
+            "jwt-rs256",
+            "--jwt-key",
+            jwt_key.to_str().unwrap(),
+        ][..],
+        &["--auth", "hmac-sha256", "--signature-encoding", "base64"][..],
+    ] {
+        let mut command_line = vec!["connect", "--total", "2"];
+        command_line.extend_from_slice(args);
+        let state = execute(parse(addr, &command_line)).await?;
+        assert_eq!(2, state.connected(), "{}", args[1]);
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_benchmark_topic_total() -> anyhow::Result<()> {
+    // All clients publish to and subscribe to the same topic
+    let state = execute(parse(
+        broker().tcp,
+        &[
+            "benchmark",
+            "--total",
+            "4",
+            "--qos",
+            "1",
+            "--interval",
+            "100",
+            "--topic",
+            "mapped/%d",
+            "--topic-total",
+            "1",
+            "--topic-mapping",
+            "block",
+        ],
+    ))
+    .await?;
+    assert_eq!(4, state.connected());
+    assert!(state.received_total() > state.published_total());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_benchmark_topic_template() -> anyhow::Result<()> {
+    // Every client publishes to the 3 topics under its own sensor, and subscribes to them with `+`
+    let state = execute(parse(
+        broker().tcp,
+        &[
+            "benchmark",
+            "--total",
+            "4",
+            "--qos",
+            "1",
+            "--interval",
+            "50",
+            "--topic",
+            "site/{site}/sensor/{sensor}/{rand:1..3}",
+            "--level-fanout",
+            "site=2",
+            "--level-fanout",
+            "sensor=2",
+        ],
+    ))
+    .await?;
+    assert_eq!(4, state.connected());
+    assert!(state.received_total() > 0);
+    assert_eq!(0, state.lost());
+    assert_eq!(0, state.duplicated());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_subscribe_wildcards() -> anyhow::Result<()> {
+    let addr = broker().tcp;
+    // Every subscriber gets the messages of both publishers through two disjoint filters
+    let subscriber = tokio::spawn(execute(parse(
+        addr,
+        &[
+            "sub",
+            "--total",
+            "3",
+            "--topic",
+            "fanout/0/+",
+            "--topic",
+            "fanout/1/#",
+            "--client-id",
+            "wildcard_sub_%d",
+            "--time",
+            "3",
+        ],
+    )));
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    let publisher = execute(parse(
+        addr,
+        &[
+            "pub",
+            "--total",
+            "2",
+            "--qos",
+            "1",
+            "--interval",
+            "50",
+            "--topic",
+            "fanout/%d/temp",
+            "--client-id",
+            "wildcard_pub_%d",
+        ],
+    ))
+    .await?;
+    assert!(publisher.published_total() > 0);
+
+    let subscriber = subscriber.await??;
+    assert_eq!(3, subscriber.connected());
+    assert!(subscriber.received_total() > 0);
+    assert_eq!(0, subscriber.unmatched());
+    let fan_out = subscriber.fan_out().unwrap();
+    assert!(fan_out > 2.5 && fan_out <= 3.0, "{}", fan_out);
+    Ok(())
+}
+
+/// Rate profile of the benchmarks that count deliveries: publishers wait for the subscribers to
+/// subscribe, and stop early enough for every message to arrive before the run ends.
+const DELIVERY_PROFILE: &str = "rate:0/s for 1s, rate:20/s for 1s, rate:0/s";
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_benchmark_fan_out() -> anyhow::Result<()> {
+    // One publisher, four subscribers of its topic
+    let state = execute(parse(
+        broker().tcp,
+        &[
+            "benchmark",
+            "--publishers",
+            "1",
+            "--subscribers",
+            "4",
+            "--qos",
+            "1",
+            "--interval",
+            "10",
+            "--time",
+            "3",
+            "--profile",
+            DELIVERY_PROFILE,
+            "--topic",
+            "bench/fan-out",
+        ],
+    ))
+    .await?;
+    assert_eq!(5, state.connected());
+    assert!(state.published_total() > 0);
+    // Every message is expected to reach the four subscribers, and does
+    assert_eq!(4 * state.published_total(), state.expected());
+    assert_eq!(Some(1.0), state.delivery_ratio());
+    assert_eq!(0, state.unmatched());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_benchmark_fan_in() -> anyhow::Result<()> {
+    // Four publishers of their own topics, one subscriber of all of them
+    let state = execute(parse(
+        broker().tcp,
+        &[
+            "benchmark",
+            "--total",
+            "5",
+            "--subscribers",
+            "1",
+            "--qos",
+            "1",
+            "--interval",
+            "10",
+            "--time",
+            "3",
+            "--profile",
+            DELIVERY_PROFILE,
+            "--topic",
+            "bench/fan-in/%d",
+            "--sub-topic",
+            "bench/fan-in/+",
+        ],
+    ))
+    .await?;
+    assert_eq!(5, state.connected());
+    assert!(state.received_total() > 0);
+    // Every message is expected to reach the subscriber once, and does
+    assert_eq!(state.published_total(), state.expected());
+    assert_eq!(Some(1.0), state.delivery_ratio());
+    assert_eq!(0, state.lost());
+    assert_eq!(1.0, state.fan_out().unwrap());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_benchmark_random_payload() -> anyhow::Result<()> {
+    // Sizes below the header are drawn too, and raised to it so that no message goes untracked
+    let state = execute(parse(
+        broker().tcp,
+        &[
+            "benchmark",
+            "--total",
+            "2",
+            "--qos",
+            "1",
+            "--interval",
+            "50",
+            "--topic",
+            "bench/random/%d",
+            "--payload-source",
+            "random",
+            "--message-size-distribution",
+            "normal:64,48",
+        ],
+    ))
+    .await?;
+    assert_eq!(2, state.connected());
+    assert!(state.received_total() > 0);
+    assert_eq!(0, state.lost());
+    assert_eq!(0, state.duplicated());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_benchmark_json_payload() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join(format!("mqtt-bench-payload-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let template = dir.join("template.json");
+    std::fs::write(
+        &template,
+        r#"{"device": "{client_id}", "seq": "{seq}", "temperature": "{float:15..30}"}"#,
+    )?;
+
+    let state = execute(parse(
+        broker().tcp,
+        &[
+            "benchmark",
+            "--total",
+            "2",
+            "--qos",
+            "1",
+            "--interval",
+            "50",
+            "--topic",
+            "bench/json/%d",
+            "--payload-source",
+            "json",
+            "--payload-template",
+            template.to_str().unwrap(),
+        ],
+    ))
+    .await?;
+    assert_eq!(2, state.connected());
+    assert!(state.received_total() > 0);
+    assert_eq!(0, state.lost());
+
+    // Sizes are those of the documents
+    let result = execute(parse(
+        broker().tcp,
+        &[
+            "benchmark",
+            "--total",
+            "1",
+            "--payload-source",
+            "json",
+            "--payload-template",
+            template.to_str().unwrap(),
+            "--message-size-distribution",
+            "128",
+        ],
+    ))
+    .await;
+    assert!(result.is_err());
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}